@@ -0,0 +1,66 @@
+// Headless libretro frontend: loads a core, runs a ROM for a number of
+// frames and saves the last picture
+//
+//   retro-frontend <core.so> <rom.gb> [frames] [screenshot.png]
+
+// The frontend the tests use. Not all of it is needed here.
+#[allow(dead_code)]
+#[path = "../tests/support/frontend.rs"]
+mod frontend;
+
+use frontend::Frontend;
+use gb_emulator::image::write_image;
+use gb_emulator_libretro::api::MEMORY_SYSTEM_RAM;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: retro-frontend <core.so> <rom.gb> [frames] [screenshot.png]";
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [core, rom, rest @ ..] = args.as_slice() else {
+        fail(USAGE.to_string());
+    };
+    let frames: u64 = match rest.first() {
+        Some(frames) => frames.parse().unwrap_or_else(|_| fail(format!("Invalid frame count: {}", frames))),
+        None => 60,
+    };
+
+    let mut frontend = unsafe { Frontend::load(Path::new(core)) }
+        .unwrap_or_else(|e| fail(format!("Failed to load core {}: {}", core, e)));
+    let (name, version) = frontend.system_info();
+    println!("Core: {} {} (libretro API {})", name, version, frontend.api_version());
+
+    let data = fs::read(rom).unwrap_or_else(|e| fail(format!("Failed to read {}: {}", rom, e)));
+    if !frontend.load_game(&data) {
+        fail(format!("The core didn't accept {}", rom));
+    }
+    let av = frontend.av_info();
+    println!(
+        "{}x{} at {:.4} FPS, audio {} Hz",
+        av.geometry.base_width, av.geometry.base_height, av.timing.fps, av.timing.sample_rate
+    );
+
+    for _ in 0..frames {
+        frontend.run();
+    }
+    let frame = frontend.frame();
+    println!("Ran {} frames, received {} audio frames", frames, frontend.audio_frames());
+    if let Some(ram) = frontend.memory(MEMORY_SYSTEM_RAM) {
+        println!("Work RAM: {} bytes", ram.len());
+    }
+
+    if let Some(path) = rest.get(1) {
+        match write_image(Path::new(path), frame.width, frame.height, &frame.pixels) {
+            Ok(()) => println!("Screenshot saved to {}", path),
+            Err(e) => fail(format!("Failed to save screenshot: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,362 @@
+// libretro core
+//
+// Builds the emulator as a shared library implementing the libretro API,
+// so it runs in RetroArch and other libretro frontends. The frontend calls
+// the retro_* functions below from a single thread; the core keeps its
+// state in statics between calls.
+//
+// - video: XRGB8888, the emulator's own pixel format, 160x144 or 256x224
+//   with a Super Game Boy border
+// - audio: there's no APU yet, so every frame sends the matching amount of
+//   silence at 32768 Hz, which frontends that sync to audio need
+// - input: joypad on port 0
+// - save states: our native format, padded to a size fixed at load time
+// - memory: work RAM as RETRO_MEMORY_SYSTEM_RAM and in the memory map.
+//   Cartridge RAM isn't emulated yet, so RETRO_MEMORY_SAVE_RAM is empty.
+//   Once it is, it belongs in RETRO_MEMORY_SAVE_RAM, where frontends load
+//   and save .srm files, and in the memory map at 0xA000 with
+//   MEMDESC_SAVE_RAM.
+//
+// tests/support/frontend.rs is a minimal frontend that loads the built core,
+// for tests. It isn't part of the core.
+
+// Every export is called by a libretro frontend, under the contract of
+// libretro.h; the pointers they take are only valid as that describes
+#![allow(clippy::missing_safety_doc)]
+
+pub mod api;
+
+use api::*;
+use gb_emulator::pacing::{CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+use gb_emulator::sgb::{SGB_HEIGHT, SGB_WIDTH};
+use gb_emulator::{GameBoy, Input};
+use std::ffi::{c_char, c_uint, c_void};
+use std::sync::{Mutex, MutexGuard};
+
+// One stereo sample every 128 cycles
+pub const SAMPLE_RATE: u32 = 32768;
+const CYCLES_PER_SAMPLE: u128 = CPU_CLOCK_HZ as u128 / SAMPLE_RATE as u128;
+
+// Room for save states to grow after load, for SGB packets in flight
+const STATE_SLACK: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+struct Core {
+    // Boxed so the memory map pointers stay put
+    gameboy: Box<GameBoy>,
+    // Kept for retro_reset
+    rom: Vec<u8>,
+    // Stereo samples sent so far, to keep audio in step with the cycles run
+    samples_sent: u128,
+    state_size: usize,
+}
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+fn core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap()
+}
+
+fn start(rom: &[u8]) -> GameBoy {
+    let mut gameboy = GameBoy::new();
+    gameboy.load_cartridge(rom.to_vec());
+    gameboy
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    let info = unsafe { &mut *info };
+    info.library_name = c"gb-emulator".as_ptr();
+    info.library_version = concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast();
+    info.valid_extensions = c"gb|gbc".as_ptr();
+    info.need_fullpath = false;
+    info.block_extract = false;
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let (width, height) = core().as_ref().map_or((160, 144), |core| core.gameboy.screen_size());
+    unsafe {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: width as c_uint,
+                base_height: height as c_uint,
+                max_width: SGB_WIDTH as c_uint,
+                max_height: SGB_HEIGHT as c_uint,
+                aspect_ratio: width as f32 / height as f32,
+            },
+            timing: SystemTiming {
+                fps: CPU_CLOCK_HZ as f64 / CYCLES_PER_FRAME as f64,
+                sample_rate: SAMPLE_RATE as f64,
+            },
+        };
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+// Single samples aren't used, everything goes through the batch callback
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_init() {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_deinit() {
+    *core() = None;
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let Some(game) = (unsafe { game.as_ref() }) else {
+        return false;
+    };
+    if game.data.is_null() || game.size == 0 {
+        return false;
+    }
+    let rom = unsafe { std::slice::from_raw_parts(game.data.cast::<u8>(), game.size) }.to_vec();
+
+    let Some(environment) = callbacks().environment else {
+        return false;
+    };
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    if !unsafe { environment(ENVIRONMENT_SET_PIXEL_FORMAT, (&raw mut format).cast()) } {
+        return false;
+    }
+
+    let mut gameboy = Box::new(start(&rom));
+    let state_size = 4 + gameboy.save_state().len() + STATE_SLACK;
+
+    // Bank 0 and the switchable bank, as the CPU sees them after power-on
+    let wram = gameboy.memory_mut().wram_mut().as_mut_ptr();
+    let hram = gameboy.memory_mut().hram_mut();
+    let descriptor = |ptr: *mut u8, start: usize, len: usize| MemoryDescriptor {
+        flags: MEMDESC_SYSTEM_RAM,
+        ptr: ptr.cast(),
+        offset: 0,
+        start,
+        select: 0,
+        disconnect: 0,
+        len,
+        addrspace: std::ptr::null(),
+    };
+    let descriptors = [
+        descriptor(wram, 0xC000, 0x1000),
+        descriptor(wram.wrapping_add(0x1000), 0xD000, 0x1000),
+        descriptor(hram.as_mut_ptr(), 0xFF80, hram.len()),
+    ];
+    let mut map = MemoryMap { descriptors: descriptors.as_ptr(), num_descriptors: descriptors.len() as c_uint };
+    // Optional; frontends that don't know it just don't get the map
+    unsafe { environment(ENVIRONMENT_SET_MEMORY_MAPS, (&raw mut map).cast()) };
+
+    *core() = Some(Core { gameboy, rom, samples_sent: 0, state_size });
+    true
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_load_game_special(_type: c_uint, _info: *const GameInfo, _num: usize) -> bool {
+    false
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_unload_game() {
+    *core() = None;
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_get_region() -> c_uint {
+    REGION_NTSC
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = core().as_mut() {
+        // Into the same box, the memory map still points at it
+        *core.gameboy = start(&core.rom);
+        core.samples_sent = 0;
+    }
+}
+
+fn read_input(input_state: InputStateFn) -> Input {
+    let pressed = |id| unsafe { input_state(0, DEVICE_JOYPAD, 0, id) } != 0;
+    Input {
+        right: pressed(DEVICE_ID_JOYPAD_RIGHT),
+        left: pressed(DEVICE_ID_JOYPAD_LEFT),
+        up: pressed(DEVICE_ID_JOYPAD_UP),
+        down: pressed(DEVICE_ID_JOYPAD_DOWN),
+        a: pressed(DEVICE_ID_JOYPAD_A),
+        b: pressed(DEVICE_ID_JOYPAD_B),
+        select: pressed(DEVICE_ID_JOYPAD_SELECT),
+        start: pressed(DEVICE_ID_JOYPAD_START),
+    }
+}
+
+// The core isn't locked while the frontend's callbacks run, so a callback
+// that calls back into the core doesn't deadlock
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    if core().is_none() {
+        return;
+    }
+
+    if let Some(poll) = callbacks.input_poll {
+        unsafe { poll() };
+    }
+    let input = callbacks.input_state.map(read_input);
+
+    let (frame, width, height, count) = {
+        let mut core = core();
+        let Some(core) = core.as_mut() else {
+            return;
+        };
+        if let Some(input) = input {
+            core.gameboy.set_buttons(input);
+        }
+        core.gameboy.run_frame();
+
+        let samples = core.gameboy.cycles() / CYCLES_PER_SAMPLE;
+        let count = samples.saturating_sub(core.samples_sent) as usize;
+        core.samples_sent = samples;
+        let (width, height) = core.gameboy.screen_size();
+        (core.gameboy.framebuffer().to_vec(), width, height, count)
+    };
+
+    if let Some(video_refresh) = callbacks.video_refresh {
+        unsafe { video_refresh(frame.as_ptr().cast(), width as c_uint, height as c_uint, width * 4) };
+    }
+
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        let silence = vec![0i16; count * 2];
+        let mut sent = 0;
+        // Frontends may take fewer frames than offered
+        while sent < count {
+            let taken = unsafe { audio_sample_batch(silence[sent * 2..].as_ptr(), count - sent) };
+            if taken == 0 {
+                break;
+            }
+            sent += taken;
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_serialize_size() -> usize {
+    core().as_ref().map_or(0, |core| core.state_size)
+}
+
+// Length-prefixed native save state, zero padded to retro_serialize_size
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = core();
+    let Some(core) = core.as_ref() else {
+        return false;
+    };
+    let state = core.gameboy.save_state();
+    if data.is_null() || 4 + state.len() > size {
+        return false;
+    }
+    let out = unsafe { std::slice::from_raw_parts_mut(data.cast::<u8>(), size) };
+    out[..4].copy_from_slice(&(state.len() as u32).to_le_bytes());
+    out[4..4 + state.len()].copy_from_slice(&state);
+    out[4 + state.len()..].fill(0);
+    true
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = core();
+    let Some(core) = core.as_mut() else {
+        return false;
+    };
+    if data.is_null() || size < 4 {
+        return false;
+    }
+    let data = unsafe { std::slice::from_raw_parts(data.cast::<u8>(), size) };
+    let length = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    let Some(state) = data.get(4..4 + length) else {
+        return false;
+    };
+    if core.gameboy.load_state(state).is_err() {
+        return false;
+    }
+    core.samples_sent = core.gameboy.cycles() / CYCLES_PER_SAMPLE;
+    true
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    let mut core = core();
+    match (id, core.as_mut()) {
+        (MEMORY_SYSTEM_RAM, Some(core)) => core.gameboy.memory_mut().wram_mut().as_mut_ptr().cast(),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match (id, core().as_ref()) {
+        // Eight banks in CGB mode, two otherwise
+        (MEMORY_SYSTEM_RAM, Some(core)) if core.gameboy.memory().is_cgb_mode() => 0x8000,
+        (MEMORY_SYSTEM_RAM, Some(_)) => 0x2000,
+        _ => 0,
+    }
+}
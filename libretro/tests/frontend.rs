@@ -0,0 +1,84 @@
+// Loads the built core through the test frontend and drives it like a
+// libretro frontend would. Everything is in one test: the core's state is
+// global, and dlopen hands every test the same copy of it.
+
+mod support;
+
+use gb_emulator_libretro::api::*;
+use support::frontend::Frontend;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::path::PathBuf;
+
+// Cargo builds the cdylib into the deps directory this test runs from
+fn core_path() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    exe.with_file_name(format!("{}gb_emulator_libretro{}", DLL_PREFIX, DLL_SUFFIX))
+}
+
+// The emulator's own test ROMs. Not every one of them is used here.
+#[allow(dead_code)]
+#[path = "../../src/test_roms.rs"]
+mod test_roms;
+
+use test_roms::joypad_rom;
+
+#[test]
+fn test_core_through_frontend() {
+    let path = core_path();
+    assert!(path.exists(), "core not built at {}", path.display());
+    let mut frontend = unsafe { Frontend::load(&path) }.unwrap();
+
+    assert_eq!(frontend.api_version(), API_VERSION);
+    let (name, _) = frontend.system_info();
+    assert_eq!(name, "gb-emulator");
+
+    assert!(!frontend.load_game(&[]));
+    assert!(frontend.load_game(&joypad_rom()));
+    assert_eq!(frontend.pixel_format(), Some(PIXEL_FORMAT_XRGB8888));
+    let regions: Vec<_> = frontend.memory_map().iter().map(|r| (r.start, r.len)).collect();
+    assert_eq!(regions, [(0xC000, 0x1000), (0xD000, 0x1000), (0xFF80, 0x7F)]);
+
+    let av = frontend.av_info();
+    assert_eq!((av.geometry.base_width, av.geometry.base_height), (160, 144));
+    assert!((av.timing.fps - 59.7275).abs() < 0.001);
+    assert_eq!(av.timing.sample_rate, 32768.0);
+
+    // Video, audio and input every frame
+    for _ in 0..10 {
+        frontend.run();
+    }
+    let frame = frontend.frame();
+    assert_eq!((frame.width, frame.height), (160, 144));
+    assert_eq!(frame.pixels.len(), 160 * 144);
+    assert_eq!(frontend.input_polls(), 10);
+    // 70224 cycles per frame at one sample per 128 cycles
+    assert_eq!(frontend.audio_frames(), 10 * 70224 / 128);
+
+    // Work RAM, and the joypad as the game sees it
+    let ram = frontend.memory(MEMORY_SYSTEM_RAM).unwrap();
+    assert_eq!(ram.len(), 0x2000);
+    assert_eq!(ram[0] & 0x0F, 0x0F);
+    frontend.set_buttons(1 << DEVICE_ID_JOYPAD_RIGHT);
+    frontend.run();
+    assert_eq!(frontend.memory(MEMORY_SYSTEM_RAM).unwrap()[0] & 0x0F, 0x0E);
+    frontend.set_buttons(0);
+    assert_eq!(frontend.memory(MEMORY_SAVE_RAM), None);
+
+    // Save states
+    let state = frontend.serialize().unwrap();
+    for _ in 0..5 {
+        frontend.run();
+    }
+    let later = frontend.frame();
+    let later_ram = frontend.memory(MEMORY_SYSTEM_RAM).unwrap();
+    assert!(frontend.unserialize(&state));
+    for _ in 0..5 {
+        frontend.run();
+    }
+    assert_eq!(frontend.frame(), later);
+    assert_eq!(frontend.memory(MEMORY_SYSTEM_RAM).unwrap(), later_ram);
+    assert!(!frontend.unserialize(&state[..8]));
+
+    frontend.reset();
+    assert_eq!(frontend.memory(MEMORY_SYSTEM_RAM).unwrap()[0], 0);
+}
@@ -0,0 +1,281 @@
+// Minimal libretro frontend
+//
+// Loads a core with dlopen (through libloading) and drives it without a
+// window: buttons are set directly, and the last video frame, the audio
+// sample count and the memory map the core reported are kept for looking
+// at. Enough to test a core end to end; it is not meant for playing.
+//
+// libretro callbacks carry no context pointer, so what they receive is kept
+// in thread-locals. Use one Frontend per thread.
+
+use gb_emulator_libretro::api::*;
+use libloading::Library;
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_uint, c_void, CStr};
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    // 0xRRGGBB
+    pub pixels: Vec<u32>,
+}
+
+// A region from the core's memory map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MappedRegion {
+    pub start: usize,
+    pub len: usize,
+    pub flags: u64,
+}
+
+thread_local! {
+    static FRAME: RefCell<Frame> = RefCell::new(Frame::default());
+    static AUDIO_FRAMES: Cell<usize> = const { Cell::new(0) };
+    // Bit n set: joypad id n is pressed
+    static BUTTONS: Cell<u16> = const { Cell::new(0) };
+    static POLLS: Cell<usize> = const { Cell::new(0) };
+    static PIXEL_FORMAT: Cell<Option<c_uint>> = const { Cell::new(None) };
+    static MEMORY_MAP: RefCell<Vec<MappedRegion>> = const { RefCell::new(Vec::new()) };
+}
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match cmd {
+        ENVIRONMENT_SET_PIXEL_FORMAT => {
+            let format = unsafe { *data.cast::<c_uint>() };
+            // The frames are kept as u32 pixels, so that's all we take
+            if format != PIXEL_FORMAT_XRGB8888 {
+                return false;
+            }
+            PIXEL_FORMAT.set(Some(format));
+            true
+        }
+        ENVIRONMENT_SET_MEMORY_MAPS => {
+            let map = unsafe { &*data.cast::<MemoryMap>() };
+            let descriptors = unsafe { std::slice::from_raw_parts(map.descriptors, map.num_descriptors as usize) };
+            let regions = descriptors
+                .iter()
+                .map(|d| MappedRegion { start: d.start, len: d.len, flags: d.flags })
+                .collect();
+            MEMORY_MAP.set(regions);
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    // Null means the frame is a duplicate of the last one
+    if data.is_null() {
+        return;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = unsafe { std::slice::from_raw_parts(data.cast::<u8>().add(y * pitch).cast::<u32>(), width) };
+        pixels.extend(row.iter().map(|pixel| pixel & 0xFF_FFFF));
+    }
+    FRAME.set(Frame { width, height, pixels });
+}
+
+unsafe extern "C" fn audio_sample(_left: i16, _right: i16) {
+    AUDIO_FRAMES.set(AUDIO_FRAMES.get() + 1);
+}
+
+unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    AUDIO_FRAMES.set(AUDIO_FRAMES.get() + frames);
+    frames
+}
+
+unsafe extern "C" fn input_poll() {
+    POLLS.set(POLLS.get() + 1);
+}
+
+unsafe extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    let pressed = port == 0 && device == DEVICE_JOYPAD && id < 16 && BUTTONS.get() & (1 << id) != 0;
+    pressed as i16
+}
+
+// The core's entry points, looked up once at load
+struct Api {
+    api_version: unsafe extern "C" fn() -> c_uint,
+    get_system_info: unsafe extern "C" fn(*mut SystemInfo),
+    get_system_av_info: unsafe extern "C" fn(*mut SystemAvInfo),
+    set_environment: unsafe extern "C" fn(EnvironmentFn),
+    set_video_refresh: unsafe extern "C" fn(VideoRefreshFn),
+    set_audio_sample: unsafe extern "C" fn(AudioSampleFn),
+    set_audio_sample_batch: unsafe extern "C" fn(AudioSampleBatchFn),
+    set_input_poll: unsafe extern "C" fn(InputPollFn),
+    set_input_state: unsafe extern "C" fn(InputStateFn),
+    init: unsafe extern "C" fn(),
+    deinit: unsafe extern "C" fn(),
+    load_game: unsafe extern "C" fn(*const GameInfo) -> bool,
+    unload_game: unsafe extern "C" fn(),
+    reset: unsafe extern "C" fn(),
+    run: unsafe extern "C" fn(),
+    serialize_size: unsafe extern "C" fn() -> usize,
+    serialize: unsafe extern "C" fn(*mut c_void, usize) -> bool,
+    unserialize: unsafe extern "C" fn(*const c_void, usize) -> bool,
+    get_memory_data: unsafe extern "C" fn(c_uint) -> *mut c_void,
+    get_memory_size: unsafe extern "C" fn(c_uint) -> usize,
+}
+
+pub struct Frontend {
+    api: Api,
+    game_loaded: bool,
+    // Last, so the core is unloaded after the calls in drop
+    _library: Library,
+}
+
+impl Frontend {
+    // Load the core at `path` and initialize it
+    //
+    // Safety: the library must be a libretro core. Loading runs its
+    // initializers, and its functions are called as libretro.h declares.
+    pub unsafe fn load(path: &Path) -> Result<Self, libloading::Error> {
+        let library = unsafe { Library::new(path) }?;
+        macro_rules! symbol {
+            ($name:literal) => {
+                *unsafe { library.get(concat!($name, "\0").as_bytes()) }?
+            };
+        }
+        let api = Api {
+            api_version: symbol!("retro_api_version"),
+            get_system_info: symbol!("retro_get_system_info"),
+            get_system_av_info: symbol!("retro_get_system_av_info"),
+            set_environment: symbol!("retro_set_environment"),
+            set_video_refresh: symbol!("retro_set_video_refresh"),
+            set_audio_sample: symbol!("retro_set_audio_sample"),
+            set_audio_sample_batch: symbol!("retro_set_audio_sample_batch"),
+            set_input_poll: symbol!("retro_set_input_poll"),
+            set_input_state: symbol!("retro_set_input_state"),
+            init: symbol!("retro_init"),
+            deinit: symbol!("retro_deinit"),
+            load_game: symbol!("retro_load_game"),
+            unload_game: symbol!("retro_unload_game"),
+            reset: symbol!("retro_reset"),
+            run: symbol!("retro_run"),
+            serialize_size: symbol!("retro_serialize_size"),
+            serialize: symbol!("retro_serialize"),
+            unserialize: symbol!("retro_unserialize"),
+            get_memory_data: symbol!("retro_get_memory_data"),
+            get_memory_size: symbol!("retro_get_memory_size"),
+        };
+
+        // Callbacks go in before retro_init, as libretro.h asks
+        unsafe {
+            (api.set_environment)(environment);
+            (api.set_video_refresh)(video_refresh);
+            (api.set_audio_sample)(audio_sample);
+            (api.set_audio_sample_batch)(audio_sample_batch);
+            (api.set_input_poll)(input_poll);
+            (api.set_input_state)(input_state);
+            (api.init)();
+        }
+        Ok(Self { api, game_loaded: false, _library: library })
+    }
+
+    pub fn api_version(&self) -> c_uint {
+        unsafe { (self.api.api_version)() }
+    }
+
+    // Library name and version
+    pub fn system_info(&self) -> (String, String) {
+        let mut info = SystemInfo {
+            library_name: std::ptr::null(),
+            library_version: std::ptr::null(),
+            valid_extensions: std::ptr::null(),
+            need_fullpath: false,
+            block_extract: false,
+        };
+        unsafe { (self.api.get_system_info)(&mut info) };
+        let string = |ptr: *const std::ffi::c_char| {
+            if ptr.is_null() { String::new() } else { unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned() }
+        };
+        (string(info.library_name), string(info.library_version))
+    }
+
+    pub fn av_info(&self) -> SystemAvInfo {
+        let mut info = SystemAvInfo::default();
+        unsafe { (self.api.get_system_av_info)(&mut info) };
+        info
+    }
+
+    pub fn load_game(&mut self, rom: &[u8]) -> bool {
+        let game = GameInfo {
+            path: std::ptr::null(),
+            data: rom.as_ptr().cast(),
+            size: rom.len(),
+            meta: std::ptr::null(),
+        };
+        self.game_loaded = unsafe { (self.api.load_game)(&game) };
+        self.game_loaded
+    }
+
+    // Buttons held from the next frame on, one bit per DEVICE_ID_JOYPAD id
+    pub fn set_buttons(&self, mask: u16) {
+        BUTTONS.set(mask);
+    }
+
+    pub fn run(&self) {
+        unsafe { (self.api.run)() };
+    }
+
+    pub fn reset(&self) {
+        unsafe { (self.api.reset)() };
+    }
+
+    pub fn serialize(&self) -> Option<Vec<u8>> {
+        let mut data = vec![0; unsafe { (self.api.serialize_size)() }];
+        unsafe { (self.api.serialize)(data.as_mut_ptr().cast(), data.len()) }.then_some(data)
+    }
+
+    pub fn unserialize(&self, data: &[u8]) -> bool {
+        unsafe { (self.api.unserialize)(data.as_ptr().cast(), data.len()) }
+    }
+
+    // One of the RETRO_MEMORY_* areas, copied out
+    pub fn memory(&self, id: c_uint) -> Option<Vec<u8>> {
+        let data = unsafe { (self.api.get_memory_data)(id) };
+        let size = unsafe { (self.api.get_memory_size)(id) };
+        if data.is_null() || size == 0 {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(data.cast::<u8>(), size) }.to_vec())
+    }
+
+    // Last frame the core sent
+    pub fn frame(&self) -> Frame {
+        FRAME.with_borrow(|frame| frame.clone())
+    }
+
+    // Stereo audio frames received so far
+    pub fn audio_frames(&self) -> usize {
+        AUDIO_FRAMES.get()
+    }
+
+    // Times the core polled input
+    pub fn input_polls(&self) -> usize {
+        POLLS.get()
+    }
+
+    pub fn pixel_format(&self) -> Option<c_uint> {
+        PIXEL_FORMAT.get()
+    }
+
+    pub fn memory_map(&self) -> Vec<MappedRegion> {
+        MEMORY_MAP.with_borrow(|map| map.clone())
+    }
+}
+
+impl Drop for Frontend {
+    fn drop(&mut self) {
+        unsafe {
+            if self.game_loaded {
+                (self.api.unload_game)();
+            }
+            (self.api.deinit)();
+        }
+    }
+}
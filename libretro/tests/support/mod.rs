@@ -0,0 +1,3 @@
+// Helpers shared by the integration tests and the retro-frontend example
+
+pub mod frontend;
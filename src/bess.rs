@@ -0,0 +1,317 @@
+// BESS (Best Effort Save State) import and export
+//
+// BESS is the save state format shared by SameBoy, BGB, Gambatte-based and
+// other emulators, used here to cross-check bugs against another
+// implementation. A BESS file is a blob of raw buffers followed by blocks and
+// a footer; all numbers are little-endian:
+//   buffers   WRAM, VRAM, OAM, HRAM, palettes, ... referred to by offset
+//   blocks    4-byte ID, u32 length, data; the last one is END
+//   footer    u32 offset of the first block, "BESS"
+// Blocks written:
+//   NAME      emulator name and version
+//   INFO      ROM title and global checksum, to catch states of other games
+//   CORE      model, CPU registers, I/O registers and buffer descriptors
+//   SGB       Super Game Boy palettes, border and attribute map
+//   END
+// On import, blocks for hardware this emulator doesn't have (MBC, RTC, HUC3,
+// TPP1, MBC7, XOAM) are skipped, as the format allows. BESS only records LY
+// and STAT for the LCD, so imported states start at the beginning of the
+// current LCD mode, and the picture is redrawn at the next VBlank.
+
+use crate::gameboy::GameBoy;
+use crate::memory::Model;
+use std::io;
+
+const MAGIC: &[u8; 4] = b"BESS";
+const VERSION_MAJOR: u16 = 1;
+const VERSION_MINOR: u16 = 1;
+
+const CORE_SIZE: usize = 0xD0;
+const INFO_SIZE: usize = 0x12;
+const SGB_SIZE: usize = 0x39;
+
+// Where the ROM header keeps the title and the global checksum
+const TITLE: std::ops::Range<usize> = 0x0134..0x0144;
+const GLOBAL_CHECKSUM: std::ops::Range<usize> = 0x014E..0x0150;
+
+// Execution states in CORE
+const RUNNING: u8 = 0;
+const HALTED: u8 = 1;
+const STOPPED: u8 = 2;
+
+// Memory as CORE describes it. Sizes depend on the model: the CGB has 32 KB
+// of WRAM, 16 KB of VRAM and palette RAM, the others 8 KB, 8 KB and none.
+pub(crate) struct MemoryImage {
+    pub io: [u8; 128],
+    pub ie: u8,
+    pub wram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub oam: [u8; 160],
+    pub hram: [u8; 127],
+    pub bg_palettes: Vec<u8>,
+    pub obj_palettes: Vec<u8>,
+}
+
+// Buffers of the SGB block. Empty buffers weren't in the state.
+#[derive(Default)]
+pub(crate) struct SgbImage {
+    // 256 SNES 4bpp tiles
+    pub border_tiles: Vec<u8>,
+    // 32x32 u16 entries, of which the SGB shows 32x28
+    pub border_map: Vec<u8>,
+    // Palettes 4-7, 16 RGB555 colors each
+    pub border_palettes: Vec<u8>,
+    // Screen palettes 0-3, 4 RGB555 colors each
+    pub palettes: Vec<u8>,
+    // 512 system palettes from PAL_TRN
+    pub system_palettes: Vec<u8>,
+    // Palette number of each 8x8 cell, 20x18
+    pub attributes: Vec<u8>,
+    // Player count in the high nibble, current player in the low nibble
+    pub multiplayer: u8,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Has a BESS footer
+pub fn is_bess(data: &[u8]) -> bool {
+    data.ends_with(MAGIC)
+}
+
+pub fn export(gameboy: &GameBoy) -> Vec<u8> {
+    let memory = gameboy.memory();
+    let image = memory.bess_image();
+    let mut out = Vec::new();
+
+    // Raw buffers first; the blocks point at them. Empty buffers are (0, 0).
+    let mut buffer = |data: &[u8]| -> [u32; 2] {
+        if data.is_empty() {
+            return [0, 0];
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(data);
+        [data.len() as u32, offset]
+    };
+    let wram = buffer(&image.wram);
+    let vram = buffer(&image.vram);
+    let oam = buffer(&image.oam);
+    let hram = buffer(&image.hram);
+    let bg_palettes = buffer(&image.bg_palettes);
+    let obj_palettes = buffer(&image.obj_palettes);
+    let sgb = memory.sgb().map(|sgb| {
+        let sgb = sgb.bess_image();
+        let buffers = [
+            buffer(&sgb.border_tiles),
+            buffer(&sgb.border_map),
+            buffer(&sgb.border_palettes),
+            buffer(&sgb.palettes),
+            buffer(&sgb.system_palettes),
+            buffer(&sgb.attributes),
+            // ATTR_TRN attribute files aren't kept
+            [0, 0],
+        ];
+        (buffers, sgb.multiplayer)
+    });
+
+    let first_block = out.len() as u32;
+    let name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    write_block(&mut out, b"NAME", name.as_bytes());
+
+    let rom = memory.rom();
+    if rom.len() >= GLOBAL_CHECKSUM.end {
+        let mut info = rom[TITLE].to_vec();
+        info.extend_from_slice(&rom[GLOBAL_CHECKSUM]);
+        write_block(&mut out, b"INFO", &info);
+    }
+
+    let mut core = Vec::with_capacity(CORE_SIZE);
+    core.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+    core.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+    core.extend_from_slice(match memory.model() {
+        Model::Dmg => b"GD  ",
+        Model::Cgb => b"CC  ",
+        Model::Sgb => b"SN  ",
+    });
+    let r = gameboy.cpu().registers();
+    let pairs = [r.pc, u16::from_be_bytes([r.a, r.f]), u16::from_be_bytes([r.b, r.c]),
+        u16::from_be_bytes([r.d, r.e]), u16::from_be_bytes([r.h, r.l]), r.sp];
+    for value in pairs {
+        core.extend_from_slice(&value.to_le_bytes());
+    }
+    let (ime, halted) = gameboy.cpu().interrupt_state();
+    core.extend_from_slice(&[ime as u8, image.ie, if halted { HALTED } else { RUNNING }, 0]);
+    core.extend_from_slice(&image.io);
+    // There's no cartridge RAM
+    let mbc_ram = [0, 0];
+    for [size, offset] in [wram, vram, mbc_ram, oam, hram, bg_palettes, obj_palettes] {
+        core.extend_from_slice(&size.to_le_bytes());
+        core.extend_from_slice(&offset.to_le_bytes());
+    }
+    debug_assert_eq!(core.len(), CORE_SIZE);
+    write_block(&mut out, b"CORE", &core);
+
+    if let Some((buffers, multiplayer)) = sgb {
+        let mut block = Vec::with_capacity(SGB_SIZE);
+        for [size, offset] in buffers {
+            block.extend_from_slice(&size.to_le_bytes());
+            block.extend_from_slice(&offset.to_le_bytes());
+        }
+        block.push(multiplayer);
+        write_block(&mut out, b"SGB ", &block);
+    }
+
+    write_block(&mut out, b"END ", &[]);
+    out.extend_from_slice(&first_block.to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    out
+}
+
+fn write_block(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+// Load a BESS state. On error the machine is left as it was.
+pub fn import(gameboy: &mut GameBoy, data: &[u8]) -> io::Result<()> {
+    let backup = gameboy.save_state();
+    let result = apply(gameboy, data);
+    if result.is_err() {
+        gameboy.load_state(&backup).expect("restoring the state from before a failed import");
+    }
+    result
+}
+
+fn apply(gameboy: &mut GameBoy, data: &[u8]) -> io::Result<()> {
+    if data.len() < 8 || !is_bess(data) {
+        return Err(invalid("not a BESS save state"));
+    }
+    let footer = data.len() - 8;
+    let mut pos = read_u32(data, footer)? as usize;
+
+    let mut found_core = false;
+    loop {
+        let id = data.get(pos..pos + 4).ok_or_else(|| invalid("BESS block list is truncated"))?;
+        let length = read_u32(data, pos + 4)? as usize;
+        let block = data.get(pos + 8..pos + 8 + length).ok_or_else(|| invalid("BESS block is truncated"))?;
+        pos += 8 + length;
+        match id {
+            b"END " => break,
+            b"INFO" => check_info(gameboy, block)?,
+            b"CORE" => {
+                apply_core(gameboy, data, block)?;
+                found_core = true;
+            }
+            b"SGB " => apply_sgb(gameboy, data, block)?,
+            // NAME, and hardware this emulator doesn't have
+            _ => {}
+        }
+    }
+    if !found_core {
+        return Err(invalid("BESS state has no CORE block"));
+    }
+    Ok(())
+}
+
+fn read_u16(data: &[u8], at: usize) -> io::Result<u16> {
+    let bytes = data.get(at..at + 2).ok_or_else(|| invalid("BESS block is too short"))?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], at: usize) -> io::Result<u32> {
+    let bytes = data.get(at..at + 4).ok_or_else(|| invalid("BESS block is too short"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The buffer a (size, offset) descriptor at `at` in `block` points to
+fn buffer<'a>(data: &'a [u8], block: &[u8], at: usize) -> io::Result<&'a [u8]> {
+    let size = read_u32(block, at)? as usize;
+    let offset = read_u32(block, at + 4)? as usize;
+    data.get(offset..offset + size).ok_or_else(|| invalid("BESS buffer is outside the file"))
+}
+
+fn check_info(gameboy: &GameBoy, block: &[u8]) -> io::Result<()> {
+    let rom = gameboy.memory().rom();
+    if block.len() < INFO_SIZE || rom.len() < GLOBAL_CHECKSUM.end {
+        return Ok(());
+    }
+    if block[..16] != rom[TITLE] || block[16..18] != rom[GLOBAL_CHECKSUM] {
+        return Err(invalid("save state is from a different game"));
+    }
+    Ok(())
+}
+
+fn apply_core(gameboy: &mut GameBoy, data: &[u8], core: &[u8]) -> io::Result<()> {
+    if core.len() < CORE_SIZE {
+        return Err(invalid("BESS CORE block is too short"));
+    }
+    if read_u16(core, 0)? != VERSION_MAJOR {
+        return Err(invalid("unsupported BESS version"));
+    }
+    let model = match core[4] {
+        b'G' => Model::Dmg,
+        b'C' => Model::Cgb,
+        b'S' => Model::Sgb,
+        _ => return Err(invalid("unknown model in BESS state")),
+    };
+    if model != gameboy.memory().model() {
+        return Err(invalid("save state is from a different hardware model"));
+    }
+
+    let [pc, af, bc, de, hl, sp] = [8, 10, 12, 14, 16, 18].map(|at| u16::from_le_bytes([core[at], core[at + 1]]));
+    let [a, f] = af.to_be_bytes();
+    let [b, c] = bc.to_be_bytes();
+    let [d, e] = de.to_be_bytes();
+    let [h, l] = hl.to_be_bytes();
+    let cpu = gameboy.cpu_mut();
+    cpu.set_registers(crate::cpu::Registers { a, f: f & 0xF0, b, c, d, e, h, l, sp, pc });
+    let halted = matches!(core[0x16], HALTED | STOPPED);
+    cpu.set_interrupt_state(core[0x14] != 0, halted);
+
+    let image = MemoryImage {
+        io: core[0x18..0x98].try_into().unwrap(),
+        ie: core[0x15],
+        wram: buffer(data, core, 0x98)?.to_vec(),
+        vram: buffer(data, core, 0xA0)?.to_vec(),
+        oam: fixed(buffer(data, core, 0xB0)?),
+        hram: fixed(buffer(data, core, 0xB8)?),
+        bg_palettes: buffer(data, core, 0xC0)?.to_vec(),
+        obj_palettes: buffer(data, core, 0xC8)?.to_vec(),
+    };
+    let (ly, stat) = (image.io[0x44], image.io[0x41]);
+    gameboy.memory_mut().restore_bess_image(&image);
+    gameboy.gpu_mut().set_position(ly, stat & 0x03);
+    Ok(())
+}
+
+// Copy a buffer into a fixed-size array, zero-filling what's missing
+fn fixed<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut out = [0; N];
+    let count = data.len().min(N);
+    out[..count].copy_from_slice(&data[..count]);
+    out
+}
+
+fn apply_sgb(gameboy: &mut GameBoy, data: &[u8], block: &[u8]) -> io::Result<()> {
+    if block.len() < SGB_SIZE {
+        return Err(invalid("BESS SGB block is too short"));
+    }
+    let image = SgbImage {
+        border_tiles: buffer(data, block, 0x00)?.to_vec(),
+        border_map: buffer(data, block, 0x08)?.to_vec(),
+        border_palettes: buffer(data, block, 0x10)?.to_vec(),
+        palettes: buffer(data, block, 0x18)?.to_vec(),
+        system_palettes: buffer(data, block, 0x20)?.to_vec(),
+        attributes: buffer(data, block, 0x28)?.to_vec(),
+        multiplayer: block[0x38],
+    };
+    match gameboy.memory_mut().sgb_mut() {
+        Some(sgb) => {
+            sgb.restore_bess_image(&image);
+            Ok(())
+        }
+        None => Err(invalid("save state is from a Super Game Boy")),
+    }
+}
@@ -0,0 +1,333 @@
+// BGB 1.4 link protocol
+//
+// Lets the serial port talk to BGB and other emulators and tools that speak
+// its link protocol over TCP. Every packet is 8 bytes:
+//   b1 command, b2, b3, b4, i1 (32-bit little-endian timestamp)
+// Timestamps count a 2 MiHz clock (half the DMG CPU clock) and wrap at 31 bits.
+//
+// Commands:
+// - VERSION (1): b2-b4 = 1, 4, 0. Both peers send it first and must agree.
+// - JOYPAD (101): remote joypad input, ignored.
+// - SYNC1 (104): the clock master sends b2 = its SB byte, b3 = SC (0x81, plus
+//   bit 1 for the CGB fast clock).
+// - SYNC2 (105): a slave with a transfer pending answers SYNC1 with
+//   b2 = its SB byte, b3 = 0x80.
+// - SYNC3 (106): b2 = 0 is a timestamp update, sent periodically so the peer
+//   knows how far this side has emulated. b2 = 1 answers SYNC1 when no
+//   transfer is pending; the master then receives 0xFF.
+// - STATUS (108): b2 bit 0 = running, bit 1 = paused, bit 2 = supports reconnect.
+// - WANT_DISCONNECT (109): the peer is closing the connection.
+//
+// SYNC packets carry the sender's timestamp, and like the plain TCP link the
+// two sides run in lockstep on it: a side more than MAX_LEAD ahead of the
+// peer's last timestamp holds until the peer catches up, and the master waits
+// for each byte to be answered. A quiet peer (paused, stopped in a debugger)
+// is waited for up to PEER_TIMEOUT. After that this side stops its clock and
+// runs on alone, reading 0xFF for its transfers, until the peer speaks
+// again. The protocol can't withdraw a SYNC1, so a peer that comes back may
+// still answer one this side gave up on; the late answer is ignored. Only a
+// closed connection or WANT_DISCONNECT unplugs the peer.
+// The peer's clock needn't start at 0: its first SYNC timestamp is taken as
+// the start of the connection.
+
+use crate::pacing::CYCLES_PER_FRAME;
+use crate::serial::SerialDevice;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const VERSION: u8 = 1;
+const SYNC1: u8 = 104;
+const SYNC2: u8 = 105;
+const SYNC3: u8 = 106;
+const STATUS: u8 = 108;
+const WANT_DISCONNECT: u8 = 109;
+
+const PROTOCOL_VERSION: [u8; 3] = [1, 4, 0];
+
+const STATUS_RUNNING: u8 = 0x01;
+
+// Timestamps are 31 bits wide
+const TIMESTAMP_MASK: u32 = 0x7FFF_FFFF;
+
+// How far one side may run ahead of the other: a frame, in timestamp units
+const MAX_LEAD: u32 = CYCLES_PER_FRAME as u32 / 2;
+
+// Send a SYNC3 timestamp update four times per frame
+const SYNC3_INTERVAL: u32 = CYCLES_PER_FRAME as u32 / 4;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long to wait for the peer before treating it as quiet
+const PEER_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Packet {
+    pub command: u8,
+    pub b2: u8,
+    pub b3: u8,
+    pub b4: u8,
+    pub timestamp: u32,
+}
+
+impl Packet {
+    pub fn new(command: u8, b2: u8, b3: u8, b4: u8, timestamp: u32) -> Self {
+        Self { command, b2, b3, b4, timestamp }
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let t = self.timestamp.to_le_bytes();
+        [self.command, self.b2, self.b3, self.b4, t[0], t[1], t[2], t[3]]
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        let timestamp = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Self::new(bytes[0], bytes[1], bytes[2], bytes[3], timestamp)
+    }
+}
+
+pub struct BgbLink {
+    stream: TcpStream,
+    incoming: Receiver<Packet>,
+    // CPU cycles emulated since the connection was made
+    cycles: u64,
+    cycles_since_sync3: u32,
+    sent_at: Instant,
+    // SC bits sent with SYNC1: 0x81, plus 0x02 for the CGB fast clock
+    control: u8,
+    // SYNC1 received but not answered yet
+    pending_sync1: Option<Packet>,
+    peer_timestamp: u32,
+    // The peer's timestamp when it first synced
+    peer_origin: Option<u32>,
+    // Nothing heard from the peer for PEER_TIMEOUT while waiting for it. Our
+    // clock stands still until it speaks again.
+    quiet: bool,
+    connected: bool,
+}
+
+impl BgbLink {
+    // Wait for a peer to connect on `addr`
+    pub fn host(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        Self::from_stream(stream)
+    }
+
+    // Connect to a peer listening on `addr`, e.g. BGB's "Listen" mode
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+
+        let (sender, incoming) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut buf = [0; 8];
+            while reader.read_exact(&mut buf).is_ok() {
+                if sender.send(Packet::from_bytes(buf)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut link = Self {
+            stream,
+            incoming,
+            cycles: 0,
+            cycles_since_sync3: 0,
+            sent_at: Instant::now(),
+            control: 0x81,
+            pending_sync1: None,
+            peer_timestamp: 0,
+            peer_origin: None,
+            quiet: false,
+            connected: true,
+        };
+        link.handshake()?;
+        Ok(link)
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let [major, minor, patch] = PROTOCOL_VERSION;
+        self.write(Packet::new(VERSION, major, minor, patch, 0))?;
+
+        let version = self
+            .incoming
+            .recv_timeout(HANDSHAKE_TIMEOUT)
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no version packet from link peer"))?;
+        if version.command != VERSION || [version.b2, version.b3, version.b4] != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported link protocol version {}.{}.{}", version.b2, version.b3, version.b4),
+            ));
+        }
+
+        self.write(Packet::new(STATUS, STATUS_RUNNING, 0, 0, self.timestamp()))
+    }
+
+    // Current time in 2 MiHz units
+    fn timestamp(&self) -> u32 {
+        (self.cycles / 2) as u32 & TIMESTAMP_MASK
+    }
+
+    pub fn peer_timestamp(&self) -> u32 {
+        self.peer_timestamp
+    }
+
+    fn write(&mut self, packet: Packet) -> io::Result<()> {
+        self.stream.write_all(&packet.to_bytes())
+    }
+
+    fn send(&mut self, command: u8, b2: u8, b3: u8) {
+        let packet = Packet::new(command, b2, b3, 0, self.timestamp());
+        if self.write(packet).is_err() {
+            self.connected = false;
+        }
+        self.sent_at = Instant::now();
+    }
+
+    // Bookkeeping for everything except the transfer replies
+    fn handle(&mut self, packet: Packet) {
+        self.quiet = false;
+        if matches!(packet.command, SYNC1 | SYNC2 | SYNC3) {
+            self.peer_timestamp = packet.timestamp;
+            self.peer_origin.get_or_insert(packet.timestamp);
+        }
+        match packet.command {
+            SYNC1 => self.pending_sync1 = Some(packet),
+            WANT_DISCONNECT => self.connected = false,
+            // VERSION, JOYPAD, STATUS and unknown commands carry nothing we use
+            _ => {}
+        }
+    }
+
+    fn drain_incoming(&mut self) {
+        while let Ok(packet) = self.incoming.try_recv() {
+            self.handle(packet);
+        }
+    }
+
+    // Next packet from the peer, waiting up to PEER_TIMEOUT. None once the
+    // peer has gone quiet or closed the connection.
+    fn receive(&mut self) -> Option<Packet> {
+        match self.incoming.recv_timeout(PEER_TIMEOUT) {
+            Ok(packet) => Some(packet),
+            Err(RecvTimeoutError::Timeout) => {
+                self.quiet = true;
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+
+    // More than MAX_LEAD ahead of the peer's last timestamp. Until its first
+    // SYNC the peer counts as just connected.
+    fn ahead_of_peer(&self) -> bool {
+        let peer = match self.peer_origin {
+            Some(origin) => self.peer_timestamp.wrapping_sub(origin) & TIMESTAMP_MASK,
+            None => 0,
+        };
+        let lead = self.timestamp().wrapping_sub(peer) & TIMESTAMP_MASK;
+        // A lead over half the range is the peer being ahead of us
+        lead > MAX_LEAD && lead <= TIMESTAMP_MASK / 2
+    }
+}
+
+impl Drop for BgbLink {
+    fn drop(&mut self) {
+        // The reader thread holds a clone of the socket: shut it down explicitly
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl SerialDevice for BgbLink {
+    fn transfer_master(&mut self, data: u8) -> u8 {
+        self.drain_incoming();
+        // The peer's SYNC1 arrived first: both are master, answer it like a slave
+        if let Some(theirs) = self.pending_sync1.take() {
+            self.send(SYNC2, data, 0x80);
+            return theirs.b2;
+        }
+        if !self.connected || self.quiet {
+            return 0xFF;
+        }
+
+        self.send(SYNC1, data, self.control);
+        while self.connected {
+            let Some(packet) = self.receive() else {
+                break;
+            };
+            self.handle(packet);
+            match packet.command {
+                SYNC2 => return packet.b2,
+                // Slave had no transfer pending
+                SYNC3 if packet.b2 == 1 => return 0xFF,
+                SYNC1 => {
+                    self.pending_sync1 = None;
+                    self.send(SYNC2, data, 0x80);
+                    return packet.b2;
+                }
+                _ => {}
+            }
+        }
+        0xFF
+    }
+
+    fn start_master(&mut self, _data: u8, fast: bool) {
+        self.control = if fast { 0x83 } else { 0x81 };
+    }
+
+    fn poll_slave(&mut self, data: u8) -> Option<u8> {
+        let sync1 = self.pending_sync1.take()?;
+        self.send(SYNC2, data, 0x80);
+        Some(sync1.b2)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.quiet {
+            self.drain_incoming();
+            // Let the peer know we're still here, in case it went quiet
+            // waiting for us as well
+            if self.quiet {
+                if self.sent_at.elapsed() >= PEER_TIMEOUT {
+                    self.send(SYNC3, 0, 0);
+                }
+                return;
+            }
+        }
+        self.cycles += cycles as u64;
+
+        // A SYNC1 left over from the previous step arrived with no transfer pending
+        if self.pending_sync1.take().is_some() {
+            self.send(SYNC3, 1, 0);
+        }
+        self.drain_incoming();
+
+        self.cycles_since_sync3 += cycles;
+        if self.cycles_since_sync3 >= SYNC3_INTERVAL {
+            self.cycles_since_sync3 = 0;
+            self.send(SYNC3, 0, 0);
+        }
+
+        // Too far ahead: report where we are, so the peer can't be holding for
+        // us as well, and wait for it. A SYNC1 ends the wait: the peer's clock
+        // stands still until we answer.
+        if self.connected && self.ahead_of_peer() {
+            self.cycles_since_sync3 = 0;
+            self.send(SYNC3, 0, 0);
+            while self.connected && !self.quiet && self.pending_sync1.is_none() && self.ahead_of_peer() {
+                if let Some(packet) = self.receive() {
+                    self.handle(packet);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// Memory bus seen by the CPU
+//
+// The CPU is generic over the bus it runs against:
+// - Memory: the full system, with cartridge, video, I/O and peripherals
+// - FlatBus: 64 KB of plain RAM, for instruction-level tests
+// - TracingBus: wraps another bus and records every access
+//
+// Besides reads and writes, the bus gets the elapsed CPU cycles after every
+// step (tick) and can ask the CPU to stall (DMA) or switch speed (CGB STOP).
+// The defaults describe a plain DMG bus without any of that.
+
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    fn write_word(&mut self, addr: u16, value: u16) {
+        self.write(addr, value as u8);
+        self.write(addr.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    // Advance peripherals by `cycles` CPU cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    // CPU cycles the CPU must stall for (DMA) since the last call
+    fn take_stall_cycles(&mut self) -> u32 {
+        0
+    }
+
+    // CGB double speed mode: instructions take half as many system cycles
+    fn is_double_speed(&self) -> bool {
+        false
+    }
+
+    // Called on STOP. Returns true if a CGB speed switch happened.
+    fn speed_switch(&mut self) -> bool {
+        false
+    }
+}
+
+// 64 KB of RAM with no memory map at all
+pub struct FlatBus {
+    memory: Box<[u8; 0x10000]>,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        Self { memory: Box::new([0; 0x10000]) }
+    }
+
+    // Copy `data` into memory starting at `addr`
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        let start = addr as usize;
+        self.memory[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read { addr: u16, value: u8 },
+    Write { addr: u16, value: u8 },
+}
+
+// Records every access made through it, then forwards to the inner bus
+pub struct TracingBus<B: Bus> {
+    pub inner: B,
+    pub log: Vec<Access>,
+}
+
+impl<B: Bus> TracingBus<B> {
+    pub fn new(inner: B) -> Self {
+        Self { inner, log: Vec::new() }
+    }
+}
+
+impl<B: Bus> Bus for TracingBus<B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.inner.read(addr);
+        self.log.push(Access::Read { addr, value });
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.log.push(Access::Write { addr, value });
+        self.inner.write(addr, value);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.inner.tick(cycles);
+    }
+
+    fn take_stall_cycles(&mut self) -> u32 {
+        self.inner.take_stall_cycles()
+    }
+
+    fn is_double_speed(&self) -> bool {
+        self.inner.is_double_speed()
+    }
+
+    fn speed_switch(&mut self) -> bool {
+        self.inner.speed_switch()
+    }
+}
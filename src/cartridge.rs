@@ -0,0 +1,81 @@
+// Cartridge header parsing
+//
+// Every Game Boy ROM carries a header at 0x0100-0x014F describing the game.
+// Fields used by the emulator:
+// 0x0134-0x0143: Title (upper case ASCII, padded with 0x00)
+// 0x0143: CGB flag (0x80 = CGB enhanced, 0xC0 = CGB only)
+// 0x0144-0x0145: New licensee code (two ASCII characters)
+// 0x0146: SGB flag (0x03 = SGB functions supported)
+// 0x014B: Old licensee code (0x33 = use new licensee code)
+
+pub const HEADER_END: usize = 0x0150;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CgbSupport {
+    None,
+    Enhanced,
+    Only,
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: [u8; 16],
+    pub cgb_flag: u8,
+    pub new_licensee: [u8; 2],
+    pub sgb_flag: u8,
+    pub old_licensee: u8,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        if rom.len() < HEADER_END {
+            return None;
+        }
+
+        let mut title = [0; 16];
+        title.copy_from_slice(&rom[0x0134..=0x0143]);
+
+        Some(Self {
+            title,
+            cgb_flag: rom[0x0143],
+            new_licensee: [rom[0x0144], rom[0x0145]],
+            sgb_flag: rom[0x0146],
+            old_licensee: rom[0x014B],
+        })
+    }
+
+    // Title as printable text. On CGB carts the last title bytes double as
+    // the manufacturer code and CGB flag, so stop at the first non-ASCII byte.
+    pub fn title_string(&self) -> String {
+        self.title
+            .iter()
+            .take_while(|&&b| b.is_ascii_graphic() || b == b' ')
+            .map(|&b| b as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    // Published by Nintendo, according to either licensee code
+    pub fn is_nintendo(&self) -> bool {
+        self.old_licensee == 0x01 || (self.old_licensee == 0x33 && &self.new_licensee == b"01")
+    }
+
+    // Sum of the 16 title bytes, used by the CGB boot ROM to pick a palette
+    pub fn title_checksum(&self) -> u8 {
+        self.title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    // The SGB only enables its functions when the old licensee code is 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee == 0x33
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        match self.cgb_flag {
+            0xC0 => CgbSupport::Only,
+            flag if flag & 0x80 != 0 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        }
+    }
+}
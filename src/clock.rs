@@ -8,6 +8,8 @@ use std::thread::sleep;
 
 static CLOCK_SPEED: u32 = 4_194_304;
 
+#[allow(dead_code)]
+#[derive(Clone)]
 pub struct Clock {
     speed: u32,
     ticks: u128,
@@ -21,6 +23,7 @@ impl Clock {
         }
     }
     
+    #[allow(dead_code)]
     pub fn cycle(&mut self) {
         // actual period is 238.41857910156, maybe 238 is precise enough?
         let dur = Duration::from_nanos(238); 
@@ -35,4 +38,8 @@ impl Clock {
     pub fn get_ticks(&self) -> u128 {
         self.ticks
     }
+
+    pub fn set_ticks(&mut self, ticks: u128) {
+        self.ticks = ticks;
+    }
 }
@@ -0,0 +1,345 @@
+// DMG game colorization for the CGB compatibility mode
+//
+// When a DMG-only cartridge boots on a Game Boy Color, the boot ROM picks
+// a BG palette and two OBJ palettes for it:
+// 1. Only titles published by Nintendo (old licensee 0x01, or 0x33 with new
+//    licensee "01") are looked up; everything else gets the default palette.
+// 2. The title bytes 0x0134-0x0143 are summed into a checksum.
+// 3. The checksum is looked up in a table. A few checksums are shared by
+//    several games; for those the 4th title letter tells them apart.
+// 4. Holding one of twelve button combinations during the boot logo
+//    overrides the automatic choice.
+//
+// The tables below are the boot ROM's: 30 four-color palettes, 51
+// combinations of them (BG, OBJ0, OBJ1), 93 title checksums with the
+// combination each one picks, and the combinations behind the button
+// shortcuts.
+
+use crate::cartridge::CartridgeHeader;
+
+// A 4-color ramp in 0xRRGGBB, lightest first
+type Ramp = [u32; 4];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompatPalette {
+    pub bg: Ramp,
+    pub obj0: Ramp,
+    pub obj1: Ramp,
+}
+
+// Boot ROM palette data, RGB555
+const PALETTES: [u16; 30 * 4] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Combinations as offsets into PALETTES in colors: (OBJ0, OBJ1, BG). Most
+// start on a palette, but a few start mid-palette and run into the next one,
+// as the boot ROM's table does.
+const fn combo(obj0: usize, obj1: usize, bg: usize) -> (usize, usize, usize) {
+    (obj0 * 4, obj1 * 4, bg * 4)
+}
+
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    combo(4, 4, 29),
+    combo(18, 18, 18),
+    combo(20, 20, 20),
+    combo(24, 24, 24),
+    combo(9, 9, 9),
+    combo(0, 0, 0),
+    combo(27, 27, 27),
+    combo(5, 5, 5),
+    combo(12, 12, 12),
+    combo(26, 26, 26),
+    combo(16, 8, 8),
+    combo(4, 28, 28),
+    combo(4, 2, 2),
+    combo(3, 4, 4),
+    combo(4, 29, 29),
+    combo(28, 4, 28),
+    combo(2, 17, 2),
+    combo(16, 16, 8),
+    combo(4, 4, 7),
+    combo(4, 4, 18),
+    combo(4, 4, 20),
+    combo(19, 19, 9),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    combo(17, 17, 2),
+    combo(4, 4, 2),
+    combo(4, 4, 3),
+    combo(28, 28, 0),
+    combo(3, 3, 0),
+    combo(0, 0, 1),
+    combo(18, 22, 18),
+    combo(20, 22, 20),
+    combo(24, 22, 24),
+    combo(16, 22, 8),
+    combo(17, 4, 13),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    combo(19, 22, 9),
+    combo(16, 28, 10),
+    combo(4, 23, 28),
+    combo(17, 22, 2),
+    combo(4, 0, 2),
+    combo(4, 28, 3),
+    combo(28, 3, 0),
+    combo(3, 28, 4),
+    combo(21, 28, 4),
+    combo(3, 28, 0),
+    combo(25, 3, 28),
+    combo(0, 28, 8),
+    combo(4, 3, 28),
+    combo(28, 3, 6),
+    combo(4, 28, 29),
+];
+
+// 5-bit channel to 8 bits, rounded the way the palettes are usually quoted
+const fn expand(channel: u16) -> u32 {
+    ((channel as u32 & 0x1F) * 255 + 15) / 31
+}
+
+const fn rgb555_to_rgb888(color: u16) -> u32 {
+    (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+}
+
+const fn ramp(offset: usize) -> Ramp {
+    [
+        rgb555_to_rgb888(PALETTES[offset]),
+        rgb555_to_rgb888(PALETTES[offset + 1]),
+        rgb555_to_rgb888(PALETTES[offset + 2]),
+        rgb555_to_rgb888(PALETTES[offset + 3]),
+    ]
+}
+
+const fn combination(index: usize) -> CompatPalette {
+    let (obj0, obj1, bg) = COMBINATIONS[index];
+    CompatPalette { bg: ramp(bg), obj0: ramp(obj0), obj1: ramp(obj1) }
+}
+
+// Used for non-Nintendo titles and unknown checksums (same as Right + A)
+pub const DEFAULT_PALETTE: CompatPalette = combination(0);
+
+// (title checksum, 4th title letter, combination) in the boot ROM's order.
+// Entries with a letter share their checksum with other games and only
+// match when the 4th letter agrees; the search goes on past a mismatch.
+// Comments name the game where it's known.
+const TITLE_PALETTES: [(u8, Option<u8>, usize); 93] = [
+    (0x88, None, 4), // ALLEY WAY
+    (0x16, None, 5), // YAKUMAN
+    (0x36, None, 35), // BASEBALL, GAME&WATCH 2
+    (0xD1, None, 34), // TENNIS
+    (0xDB, None, 3), // TETRIS
+    (0xF2, None, 31), // QIX
+    (0x3C, None, 15), // DR.MARIO
+    (0x8C, None, 10), // RADARMISSION
+    (0x92, None, 5), // F1RACE
+    (0x3D, None, 19), // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7), // X
+    (0xC9, None, 37), // MARIOLAND2
+    (0x3E, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1D, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5), // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13), // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, None, 14), // POKEMON GREEN
+    (0x75, None, 5), // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5), // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6F, None, 9), // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2), // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4B, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xF7, None, 45), // BOY AND BLOB GB2
+    (0xF6, None, 42), // MEGAMAN
+    (0xA2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4E, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xE0, None, 30), // YOSHI'S COOKIE
+    (0x8B, None, 41), // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34), // TOPRANKINGTENNIS
+    (0x0C, None, 5), // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xE8, None, 6), // SPACE INVADERS
+    (0xB7, None, 5), // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONG5
+    (0x9A, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9D, None, 40), // KILLERINSTINCT95
+    (0x71, None, 14), // TETRIS BLAST
+    (0x9C, None, 16), // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 5), // BA.TOSHINDEN
+    (0x6D, None, 29), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 29), // TETRIS PLUS
+    (0x6B, None, 39), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xA5, Some(b'A'), 6), // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32), // GBWARS
+    (0xD3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 24), // KID ICARUS
+    (0x0D, Some(b'R'), 31), // TETRIS2
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), // GALAXIAN
+    (0xA5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0), // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0), // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 19), // MARIO & YOSHI
+    (0xBF, Some(b'C'), 34), // SOCCER
+    (0x0D, Some(b'E'), 23), // POKEBOM
+    (0xF4, Some(b' '), 18), // G&W GALLERY
+    (0xB3, Some(b'R'), 29), // TETRIS ATTACK
+];
+
+// The twelve palettes selectable by holding a button combination at boot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ButtonPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ButtonPalette {
+    pub const ALL: [ButtonPalette; 12] = [
+        ButtonPalette::Up,
+        ButtonPalette::UpA,
+        ButtonPalette::UpB,
+        ButtonPalette::Left,
+        ButtonPalette::LeftA,
+        ButtonPalette::LeftB,
+        ButtonPalette::Down,
+        ButtonPalette::DownA,
+        ButtonPalette::DownB,
+        ButtonPalette::Right,
+        ButtonPalette::RightA,
+        ButtonPalette::RightB,
+    ];
+
+    // Parse a combination like "up", "left+a" or "Right+B"
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|combo| combo.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ButtonPalette::Up => "up",
+            ButtonPalette::UpA => "up+a",
+            ButtonPalette::UpB => "up+b",
+            ButtonPalette::Left => "left",
+            ButtonPalette::LeftA => "left+a",
+            ButtonPalette::LeftB => "left+b",
+            ButtonPalette::Down => "down",
+            ButtonPalette::DownA => "down+a",
+            ButtonPalette::DownB => "down+b",
+            ButtonPalette::Right => "right",
+            ButtonPalette::RightA => "right+a",
+            ButtonPalette::RightB => "right+b",
+        }
+    }
+
+    // The boot ROM's combination for each shortcut
+    pub fn palette(self) -> CompatPalette {
+        combination(match self {
+            ButtonPalette::Up => 5,
+            ButtonPalette::UpA => 43,
+            ButtonPalette::UpB => 28,
+            ButtonPalette::Left => 48,
+            ButtonPalette::LeftA => 40,
+            ButtonPalette::LeftB => 7,
+            ButtonPalette::Down => 8,
+            ButtonPalette::DownA => 3,
+            ButtonPalette::DownB => 49,
+            ButtonPalette::Right => 1,
+            ButtonPalette::RightA => 0,
+            ButtonPalette::RightB => 6,
+        })
+    }
+}
+
+// Palette the CGB boot ROM would pick automatically for this cartridge
+pub fn palette_for_header(header: &CartridgeHeader) -> CompatPalette {
+    if !header.is_nintendo() {
+        return DEFAULT_PALETTE;
+    }
+
+    let checksum = header.title_checksum();
+    let fourth_letter = header.title[3];
+    TITLE_PALETTES
+        .iter()
+        .find(|&&(sum, letter, _)| sum == checksum && letter.is_none_or(|l| l == fourth_letter))
+        .map_or(DEFAULT_PALETTE, |&(_, _, index)| combination(index))
+}
+
+// Convert 0xRRGGBB to the CGB's RGB555 palette RAM format
+pub fn rgb888_to_rgb555(color: u32) -> u16 {
+    let r = (color >> 19) & 0x1F;
+    let g = (color >> 11) & 0x1F;
+    let b = (color >> 3) & 0x1F;
+    (r | (g << 5) | (b << 10)) as u16
+}
@@ -12,6 +12,8 @@
 // Timing:
 // The CPU runs at 4.194304 MHz with precise cycle counting for each instruction.
 // Each instruction's cycle count is accurately tracked to match original hardware timing.
+// In CGB double speed mode the CPU runs at 8.388608 MHz while the PPU and APU keep
+// their normal clock, so every instruction costs half as many system cycles.
 //
 // Instruction Execution Flow (Hot Path):
 // 1. Fetch opcode from memory at PC
@@ -21,16 +23,89 @@
 // 5. Advance PC and track clock cycles
 //
 // All 256 unprefixed opcodes and 256 CB-prefixed opcodes are fully implemented.
+//
+// The CPU is generic over the bus it talks to (see bus.rs). It defaults to
+// the full system Memory.
 
 use crate::{
+    bus::Bus,
     flags::FlagsRegister,
     clock::Clock,
-    memory::Memory,
-    opcodes::{load_opcodes, OpCode},
+    memory::{Memory, Model},
+    savestate::{StateReader, StateWriter},
 };
+use std::fmt;
+use std::io;
+
+// CPU cycles spent stopped while the clock settles after a CGB speed switch
+const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+// Register values at one point in time, for dumps and debuggers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |bit: u8, name: char| if self.f & bit != 0 { name } else { '-' };
+        write!(
+            f,
+            "AF={:02X}{:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} PC={:04X} [{}{}{}{}]",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc,
+            flag(0x80, 'Z'), flag(0x40, 'N'), flag(0x20, 'H'), flag(0x10, 'C'),
+        )
+    }
+}
+
+// Register pairs. The low nibble of F always reads 0.
+impl Registers {
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f & 0xF0])
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        [self.a, self.f] = value.to_be_bytes();
+        self.f &= 0xF0;
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        [self.b, self.c] = value.to_be_bytes();
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        [self.d, self.e] = value.to_be_bytes();
+    }
 
-#[allow(non_snake_case)]
-pub struct CPU {
+    pub fn set_hl(&mut self, value: u16) {
+        [self.h, self.l] = value.to_be_bytes();
+    }
+}
+
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct CPU<B: Bus = Memory> {
     // Registers
     a: u8,      // Accumulator
     f: FlagsRegister,
@@ -44,21 +119,42 @@ pub struct CPU {
     pc: u16,    // Program Counter
     
     // Memory and peripherals
-    memory: Memory,
+    bus: B,
     clock: Clock,
     
-    // Opcode tables
-    opcodes: Vec<OpCode>,
-    cb_opcodes: Vec<OpCode>,
-    
     // CPU state
     halted: bool,
     ime: bool,  // Interrupt Master Enable
+    stall_cycles: u32, // CPU cycles left before execution resumes (speed switch, VRAM DMA)
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
     pub fn new() -> Self {
-        let (opcodes, cb_opcodes) = load_opcodes().unwrap();
+        Self::with_bus(Memory::new())
+    }
+    
+    pub fn load_rom(&mut self, rom_data: Vec<u8>) {
+        self.bus.load_rom(rom_data);
+        if self.bus.model() == Model::Cgb {
+            // The CGB boot ROM leaves A = 0x11, which games use to detect Color hardware
+            self.a = 0x11;
+        }
+    }
+    
+    pub fn load_boot_rom(&mut self, boot_data: &[u8]) {
+        self.bus.load_boot_rom(boot_data);
+        self.pc = 0x0000; // Start at boot ROM
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
         Self {
             a: 0x01,    // Initial value after boot ROM
             f: FlagsRegister::init(),
@@ -70,39 +166,39 @@ impl CPU {
             l: 0x4D,
             sp: 0xFFFE,
             pc: 0x0100, // Start after boot ROM (or 0x0000 with boot ROM)
-            memory: Memory::new(),
+            bus,
             clock: Clock::new(),
-            opcodes,
-            cb_opcodes,
             halted: false,
             ime: false,
+            stall_cycles: 0,
         }
     }
-    
-    pub fn load_rom(&mut self, rom_data: Vec<u8>) {
-        self.memory.load_rom(rom_data);
-    }
-    
-    pub fn load_boot_rom(&mut self, boot_data: &[u8]) {
-        self.memory.load_boot_rom(boot_data);
-        self.pc = 0x0000; // Start at boot ROM
-    }
+
 
     // Main execution loop - the hot path
+    // Returns the elapsed system cycles (PPU/APU clock), which in CGB double
+    // speed mode is half the CPU cycle count of the instruction.
     pub fn step(&mut self) -> u8 {
-        if self.halted {
-            self.clock.tick(4);
-            return 4;
-        }
+        let cycles = if self.stall_cycles > 0 {
+            self.stall_cycles = self.stall_cycles.saturating_sub(4);
+            4
+        } else if self.halted {
+            4
+        } else {
+            let opcode = self.fetch_byte();
+            self.execute(opcode)
+        };
+        self.stall_cycles += self.bus.take_stall_cycles();
+        // Peripherals run off the CPU clock, so they speed up in double speed mode too
+        self.bus.tick(cycles as u32);
 
-        let opcode = self.fetch_byte();
-        let cycles = self.execute(opcode);
+        let cycles = if self.bus.is_double_speed() { cycles / 2 } else { cycles };
         self.clock.tick(cycles);
         cycles
     }
 
     fn fetch_byte(&mut self) -> u8 {
-        let byte = self.memory.read(self.pc);
+        let byte = self.bus.read(self.pc);
         self.pc = self.pc.wrapping_add(1);
         byte
     }
@@ -129,7 +225,7 @@ impl CPU {
             // LD (BC), A
             0x02 => {
                 let addr = self.get_bc();
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 8
             }
             
@@ -172,7 +268,7 @@ impl CPU {
             // LD (a16), SP
             0x08 => {
                 let addr = self.fetch_word();
-                self.memory.write_word(addr, self.sp);
+                self.bus.write_word(addr, self.sp);
                 20
             }
             
@@ -188,7 +284,7 @@ impl CPU {
             // LD A, (BC)
             0x0A => {
                 let addr = self.get_bc();
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 8
             }
             
@@ -231,6 +327,9 @@ impl CPU {
             // STOP
             0x10 => {
                 self.fetch_byte(); // STOP is 2 bytes
+                if self.bus.speed_switch() {
+                    self.stall_cycles = SPEED_SWITCH_CYCLES;
+                }
                 4
             }
             
@@ -244,7 +343,7 @@ impl CPU {
             // LD (DE), A
             0x12 => {
                 let addr = self.get_de();
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 8
             }
             
@@ -304,7 +403,7 @@ impl CPU {
             // LD A, (DE)
             0x1A => {
                 let addr = self.get_de();
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 8
             }
             
@@ -366,7 +465,7 @@ impl CPU {
             // LD (HL+), A / LDI (HL), A
             0x22 => {
                 let addr = self.get_hl();
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 self.set_hl(addr.wrapping_add(1));
                 8
             }
@@ -424,7 +523,7 @@ impl CPU {
             // LD A, (HL+) / LDI A, (HL)
             0x2A => {
                 let addr = self.get_hl();
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 self.set_hl(addr.wrapping_add(1));
                 8
             }
@@ -482,7 +581,7 @@ impl CPU {
             // LD (HL-), A / LDD (HL), A
             0x32 => {
                 let addr = self.get_hl();
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 self.set_hl(addr.wrapping_sub(1));
                 8
             }
@@ -496,18 +595,18 @@ impl CPU {
             // INC (HL)
             0x34 => {
                 let addr = self.get_hl();
-                let value = self.memory.read(addr);
+                let value = self.bus.read(addr);
                 let result = self.alu_inc(value);
-                self.memory.write(addr, result);
+                self.bus.write(addr, result);
                 12
             }
             
             // DEC (HL)
             0x35 => {
                 let addr = self.get_hl();
-                let value = self.memory.read(addr);
+                let value = self.bus.read(addr);
                 let result = self.alu_dec(value);
-                self.memory.write(addr, result);
+                self.bus.write(addr, result);
                 12
             }
             
@@ -515,7 +614,7 @@ impl CPU {
             0x36 => {
                 let value = self.fetch_byte();
                 let addr = self.get_hl();
-                self.memory.write(addr, value);
+                self.bus.write(addr, value);
                 12
             }
             
@@ -549,7 +648,7 @@ impl CPU {
             // LD A, (HL-) / LDD A, (HL)
             0x3A => {
                 let addr = self.get_hl();
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 self.set_hl(addr.wrapping_sub(1));
                 8
             }
@@ -923,7 +1022,7 @@ impl CPU {
             // LDH (a8), A
             0xE0 => {
                 let offset = self.fetch_byte() as u16;
-                self.memory.write(0xFF00 + offset, self.a);
+                self.bus.write(0xFF00 + offset, self.a);
                 12
             }
             
@@ -937,7 +1036,7 @@ impl CPU {
             // LD (C), A
             0xE2 => {
                 let addr = 0xFF00 + self.c as u16;
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 8
             }
             
@@ -989,12 +1088,12 @@ impl CPU {
             // LD (a16), A
             0xEA => {
                 let addr = self.fetch_word();
-                self.memory.write(addr, self.a);
+                self.bus.write(addr, self.a);
                 16
             }
             
             // Invalid opcodes 0xEB, 0xEC, 0xED
-            0xEB | 0xEC | 0xED => 4,
+            0xEB..=0xED => 4,
             
             // XOR d8
             0xEE => {
@@ -1013,7 +1112,7 @@ impl CPU {
             // LDH A, (a8)
             0xF0 => {
                 let offset = self.fetch_byte() as u16;
-                self.a = self.memory.read(0xFF00 + offset);
+                self.a = self.bus.read(0xFF00 + offset);
                 12
             }
             
@@ -1028,7 +1127,7 @@ impl CPU {
             // LD A, (C)
             0xF2 => {
                 let addr = 0xFF00 + self.c as u16;
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 8
             }
             
@@ -1087,7 +1186,7 @@ impl CPU {
             // LD A, (a16)
             0xFA => {
                 let addr = self.fetch_word();
-                self.a = self.memory.read(addr);
+                self.a = self.bus.read(addr);
                 16
             }
             
@@ -1212,7 +1311,7 @@ impl CPU {
     }
 
     // Register access helpers
-    fn read_r8(&self, reg: u8) -> u8 {
+    fn read_r8(&mut self, reg: u8) -> u8 {
         match reg {
             0 => self.b,
             1 => self.c,
@@ -1220,7 +1319,7 @@ impl CPU {
             3 => self.e,
             4 => self.h,
             5 => self.l,
-            6 => self.memory.read(self.get_hl()),
+            6 => self.bus.read(self.get_hl()),
             7 => self.a,
             _ => unreachable!(),
         }
@@ -1236,7 +1335,7 @@ impl CPU {
             5 => self.l = value,
             6 => {
                 let addr = self.get_hl();
-                self.memory.write(addr, value);
+                self.bus.write(addr, value);
             }
             7 => self.a = value,
             _ => unreachable!(),
@@ -1274,15 +1373,15 @@ impl CPU {
     // Stack operations
     fn push_stack(&mut self, value: u16) {
         self.sp = self.sp.wrapping_sub(1);
-        self.memory.write(self.sp, (value >> 8) as u8);
+        self.bus.write(self.sp, (value >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.memory.write(self.sp, (value & 0xFF) as u8);
+        self.bus.write(self.sp, (value & 0xFF) as u8);
     }
 
     fn pop_stack(&mut self) -> u16 {
-        let low = self.memory.read(self.sp) as u16;
+        let low = self.bus.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
-        let high = self.memory.read(self.sp) as u16;
+        let high = self.bus.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
         (high << 8) | low
     }
@@ -1494,12 +1593,96 @@ impl CPU {
     pub fn get_pc(&self) -> u16 {
         self.pc
     }
+
+    // Snapshot of the register file
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            f: self.f.clone().into(),
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
     
     pub fn get_ticks(&self) -> u128 {
         self.clock.get_ticks()
     }
     
-    pub fn get_memory(&self) -> &Memory {
-        &self.memory
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.f = FlagsRegister::from(registers.f);
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+    }
+
+    // Interrupt Master Enable
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // IME and whether the CPU is halted
+    pub(crate) fn interrupt_state(&self) -> (bool, bool) {
+        (self.ime, self.halted)
+    }
+
+    pub(crate) fn set_interrupt_state(&mut self, ime: bool, halted: bool) {
+        self.ime = ime;
+        self.halted = halted;
+        self.stall_cycles = 0;
+    }
+
+    // "CPU " save state section. The bus saves itself.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        let r = self.registers();
+        for value in [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l] {
+            w.u8(value);
+        }
+        w.u16(r.sp);
+        w.u16(r.pc);
+        w.bool(self.ime);
+        w.bool(self.halted);
+        w.u32(self.stall_cycles);
+        w.u128(self.clock.get_ticks());
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let mut registers = [0; 8];
+        r.bytes(&mut registers)?;
+        let [a, f, b, c, d, e, h, l] = registers;
+        let (sp, pc) = (r.u16()?, r.u16()?);
+        self.set_registers(Registers { a, f, b, c, d, e, h, l, sp, pc });
+        self.ime = r.bool()?;
+        self.halted = r.bool()?;
+        self.stall_cycles = r.u32()?;
+        // Stalls run down 4 cycles per step
+        if !self.stall_cycles.is_multiple_of(4) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state has a bad CPU stall count"));
+        }
+        self.clock.set_ticks(r.u128()?);
+        Ok(())
+    }
+
+    pub fn get_memory(&self) -> &B {
+        &self.bus
+    }
+    
+    pub fn get_memory_mut(&mut self) -> &mut B {
+        &mut self.bus
     }
 }
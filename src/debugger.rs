@@ -0,0 +1,371 @@
+// Command-line debugger
+//
+// The commands behind `--debug`: breakpoints, stepping, register and memory
+// inspection and editing, and a disassembly listing around PC. The REPL
+// itself (reading lines, printing) lives in the frontend; execute() takes one
+// command line and returns the text to show, so it can be driven from tests.
+//
+// Addresses and values are hexadecimal, with or without a 0x or $ prefix.
+// Running commands stop at breakpoints, or when the interrupt flag is set.
+// `continue` without a frame count and `next` over a CALL that never returns
+// only stop that way; the frontend sets the flag on Ctrl-C.
+
+use crate::disasm;
+use crate::gameboy::GameBoy;
+use crate::pacing::CYCLES_PER_FRAME;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub const HELP: &str = "\
+Commands (addresses, values and bytes in hex, counts in decimal):
+  s, step [n]            run n instructions (default 1)
+  n, next                step over CALL and RST
+  c, continue [frames]   run until a breakpoint, or for that many frames
+  b, break [addr]        set a breakpoint at addr, or list them
+  d, delete <addr>       remove a breakpoint
+  r, regs                show registers and flags
+  l, list [addr] [n]     disassemble n instructions (default: around PC);
+                         > marks PC, * a breakpoint
+  x, mem <addr> [len]    hex dump len bytes (default 64)
+  set <reg> <value>      write a register: a f b c d e h l af bc de hl sp pc
+  flag <z|n|h|c> <0|1>   write a flag
+  w, write <addr> <byte>...  write bytes to memory
+  h, help                this text
+  q, quit                exit
+An empty line repeats the last step, next or continue. Ctrl-C stops a
+running command.";
+
+// Instructions shown before PC in the default listing
+const LIST_BEFORE: usize = 4;
+const LIST_AFTER: usize = 6;
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    Text(String),
+    Quit,
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    // Repeated on an empty line
+    last_run: Option<String>,
+    // Set from outside (Ctrl-C) to stop a running command
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self { breakpoints: BTreeSet::new(), last_run: None, interrupt: Arc::new(AtomicBool::new(false)) }
+    }
+
+    // Setting this flag stops the running command at the next instruction.
+    // It's cleared when a command starts running.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Run one command line. Errors are messages for the user.
+    pub fn execute(&mut self, gameboy: &mut GameBoy, line: &str) -> Result<Response, String> {
+        let line = match (line.trim(), &self.last_run) {
+            ("", Some(last)) => last.clone(),
+            (line, _) => line.to_string(),
+        };
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Response::Text(String::new()));
+        };
+        let args: Vec<&str> = words.collect();
+
+        let text = match command {
+            "s" | "step" => {
+                self.last_run = Some(line.clone());
+                let count = args.first().map_or(Ok(1), |arg| parse_count(arg))?;
+                self.step(gameboy, count)
+            }
+            "n" | "next" => {
+                self.last_run = Some(line.clone());
+                self.next(gameboy)
+            }
+            "c" | "continue" => {
+                self.last_run = Some(line.clone());
+                let frames = args.first().map(|arg| parse_count(arg)).transpose()?;
+                self.run(gameboy, frames.map(|frames| frames as u128 * CYCLES_PER_FRAME as u128))
+            }
+            "b" | "break" => match args.first() {
+                Some(arg) => {
+                    let addr = parse_hex(arg)?;
+                    self.breakpoints.insert(addr);
+                    format!("Breakpoint set at {:04X}", addr)
+                }
+                None if self.breakpoints.is_empty() => "No breakpoints".to_string(),
+                None => self.breakpoints.iter().map(|addr| format!("{:04X}", addr)).collect::<Vec<_>>().join("\n"),
+            },
+            "d" | "delete" => {
+                let addr = parse_hex(args.first().ok_or("delete needs an address")?)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("No breakpoint at {:04X}", addr));
+                }
+                format!("Deleted breakpoint at {:04X}", addr)
+            }
+            "r" | "regs" => registers(gameboy),
+            "l" | "list" => match args.as_slice() {
+                [] => self.list_around_pc(gameboy),
+                [addr, rest @ ..] => {
+                    let count = rest.first().map_or(Ok(LIST_AFTER + 1), |arg| parse_length(arg))?;
+                    self.list(gameboy, parse_hex(addr)?, count)
+                }
+            },
+            "x" | "mem" => {
+                let addr = parse_hex(args.first().ok_or("mem needs an address")?)?;
+                let len = args.get(1).map_or(Ok(64), |arg| parse_length(arg))?;
+                hex_dump(gameboy, addr, len)
+            }
+            "set" => {
+                let [name, value] = args.as_slice() else {
+                    return Err("usage: set <reg> <value>".to_string());
+                };
+                set_register(gameboy, name, parse_hex(value)?)?;
+                registers(gameboy)
+            }
+            "flag" => {
+                let [name, value] = args.as_slice() else {
+                    return Err("usage: flag <z|n|h|c> <0|1>".to_string());
+                };
+                set_flag(gameboy, name, value)?;
+                registers(gameboy)
+            }
+            "w" | "write" => {
+                let (addr, bytes) = args.split_first().ok_or("write needs an address and bytes")?;
+                let addr = parse_hex(addr)?;
+                if bytes.is_empty() {
+                    return Err("write needs bytes to write".to_string());
+                }
+                let bytes = bytes.iter().map(|byte| parse_byte(byte)).collect::<Result<Vec<_>, _>>()?;
+                for (i, &byte) in bytes.iter().enumerate() {
+                    gameboy.memory_mut().write(addr.wrapping_add(i as u16), byte);
+                }
+                hex_dump(gameboy, addr, bytes.len())
+            }
+            "h" | "help" => HELP.to_string(),
+            "q" | "quit" => return Ok(Response::Quit),
+            _ => return Err(format!("Unknown command: {} (h for help)", command)),
+        };
+        Ok(Response::Text(text))
+    }
+
+    fn step(&mut self, gameboy: &mut GameBoy, count: usize) -> String {
+        self.interrupt.store(false, Ordering::Relaxed);
+        for i in 0..count {
+            gameboy.step();
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return format!("Interrupted\n{}", self.stopped(gameboy));
+            }
+            // Stop early at a breakpoint, but not on the last step where
+            // the listing shows it anyway
+            let pc = gameboy.cpu().get_pc();
+            if i + 1 < count && self.breakpoints.contains(&pc) {
+                return format!("Breakpoint at {:04X}\n{}", pc, self.stopped(gameboy));
+            }
+        }
+        self.stopped(gameboy)
+    }
+
+    // Run a call until it returns to the next instruction
+    fn next(&mut self, gameboy: &mut GameBoy) -> String {
+        let pc = gameboy.cpu().get_pc();
+        let instruction = disasm::decode(|addr| gameboy.memory().read(addr), pc);
+        if !matches!(instruction.mnemonic.as_str(), "CALL" | "RST") {
+            return self.step(gameboy, 1);
+        }
+        // Recursive calls come back to the same address deeper in the stack
+        let sp = gameboy.cpu().registers().sp;
+        let return_address = instruction.next_address();
+        self.run_until(gameboy, None, |gameboy| {
+            let registers = gameboy.cpu().registers();
+            registers.pc == return_address && registers.sp >= sp
+        })
+    }
+
+    // Run until a breakpoint, or for `cycles` system cycles
+    fn run(&mut self, gameboy: &mut GameBoy, cycles: Option<u128>) -> String {
+        self.run_until(gameboy, cycles, |_| false)
+    }
+
+    fn run_until(&mut self, gameboy: &mut GameBoy, cycles: Option<u128>, done: impl Fn(&GameBoy) -> bool) -> String {
+        let start = gameboy.cycles();
+        self.interrupt.store(false, Ordering::Relaxed);
+        loop {
+            gameboy.step();
+            let pc = gameboy.cpu().get_pc();
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return format!("Interrupted\n{}", self.stopped(gameboy));
+            }
+            if done(gameboy) {
+                return self.stopped(gameboy);
+            }
+            if self.breakpoints.contains(&pc) {
+                return format!("Breakpoint at {:04X}\n{}", pc, self.stopped(gameboy));
+            }
+            if cycles.is_some_and(|cycles| gameboy.cycles() - start >= cycles) {
+                gameboy.finish_frame();
+                return self.stopped(gameboy);
+            }
+        }
+    }
+
+    // Instructions before PC are found by decoding from a little further
+    // back and keeping the first start that lines up with PC. Data in between
+    // can throw that off, so it's a best guess.
+    fn list_around_pc(&self, gameboy: &GameBoy) -> String {
+        let pc = gameboy.cpu().get_pc();
+        let read = |addr: u16| gameboy.memory().read(addr);
+        let lengths = |back: u16| {
+            let mut lengths = Vec::new();
+            let mut offset = 0;
+            while offset < back {
+                let length = disasm::decode(read, pc.wrapping_sub(back - offset)).bytes.len() as u16;
+                lengths.push(length);
+                offset += length;
+            }
+            (offset == back).then_some(lengths)
+        };
+        // Longest lined-up run, then at most LIST_BEFORE of its last instructions
+        let (back, lengths) = (1..=LIST_BEFORE as u16 * 3)
+            .rev()
+            .find_map(|back| lengths(back).map(|lengths| (back, lengths)))
+            .unwrap_or((0, Vec::new()));
+        let skip = lengths.len().saturating_sub(LIST_BEFORE);
+        let skipped: u16 = lengths[..skip].iter().sum();
+        let start = pc.wrapping_sub(back - skipped);
+        self.list(gameboy, start, lengths.len() - skip + LIST_AFTER + 1)
+    }
+
+    // `count` instructions from `start`, with PC and breakpoints marked
+    fn list(&self, gameboy: &GameBoy, start: u16, count: usize) -> String {
+        let pc = gameboy.cpu().get_pc();
+        disasm::disassemble(|addr| gameboy.memory().read(addr), start, count)
+            .iter()
+            .map(|instruction| {
+                let current = if instruction.address == pc { '>' } else { ' ' };
+                let breakpoint = if self.breakpoints.contains(&instruction.address) { '*' } else { ' ' };
+                format!("{}{} {}", current, breakpoint, instruction.listing())
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Registers and the next instruction
+    fn stopped(&self, gameboy: &GameBoy) -> String {
+        let pc = gameboy.cpu().get_pc();
+        let instruction = disasm::decode(|addr| gameboy.memory().read(addr), pc);
+        format!("{}\n{}", registers(gameboy), instruction.listing())
+    }
+}
+
+fn registers(gameboy: &GameBoy) -> String {
+    let cpu = gameboy.cpu();
+    format!(
+        "{}  IME={} {}cycles={}",
+        cpu.registers(),
+        cpu.ime() as u8,
+        if cpu.is_halted() { "HALTED " } else { "" },
+        gameboy.cycles()
+    )
+}
+
+// 16 bytes per row with their ASCII
+fn hex_dump(gameboy: &GameBoy, start: u16, len: usize) -> String {
+    let bytes: Vec<u8> = (0..len).map(|i| gameboy.memory().read(start.wrapping_add(i as u16))).collect();
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:04X}  {:<47}  {}", start.wrapping_add(row as u16 * 16), hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn set_register(gameboy: &mut GameBoy, name: &str, value: u16) -> Result<(), String> {
+    let mut registers = gameboy.cpu().registers();
+    let byte = || u8::try_from(value).map_err(|_| format!("{} is an 8-bit register", name));
+    match name.to_ascii_lowercase().as_str() {
+        "a" => registers.a = byte()?,
+        "f" => registers.f = byte()? & 0xF0,
+        "b" => registers.b = byte()?,
+        "c" => registers.c = byte()?,
+        "d" => registers.d = byte()?,
+        "e" => registers.e = byte()?,
+        "h" => registers.h = byte()?,
+        "l" => registers.l = byte()?,
+        "af" => registers.set_af(value),
+        "bc" => registers.set_bc(value),
+        "de" => registers.set_de(value),
+        "hl" => registers.set_hl(value),
+        "sp" => registers.sp = value,
+        "pc" => registers.pc = value,
+        _ => return Err(format!("Unknown register: {}", name)),
+    }
+    gameboy.cpu_mut().set_registers(registers);
+    Ok(())
+}
+
+fn set_flag(gameboy: &mut GameBoy, name: &str, value: &str) -> Result<(), String> {
+    let bit = match name.to_ascii_lowercase().as_str() {
+        "z" => 0x80,
+        "n" => 0x40,
+        "h" => 0x20,
+        "c" => 0x10,
+        _ => return Err(format!("Unknown flag: {}", name)),
+    };
+    let mut registers = gameboy.cpu().registers();
+    match value {
+        "1" => registers.f |= bit,
+        "0" => registers.f &= !bit,
+        _ => return Err(format!("Flags are 0 or 1, got {}", value)),
+    }
+    gameboy.cpu_mut().set_registers(registers);
+    Ok(())
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Not a hex number: {}", value))
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    u8::try_from(parse_hex(value)?).map_err(|_| format!("Not a byte: {}", value))
+}
+
+// Counts are decimal, like in the usage text
+fn parse_count(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("Not a count: {}", value))
+}
+
+// Listings and dumps cover the address space at most once
+fn parse_length(value: &str) -> Result<usize, String> {
+    match parse_count(value)? {
+        count if count > disasm::MAX_COUNT => Err(format!("At most {} at a time, got {}", disasm::MAX_COUNT, count)),
+        count => Ok(count),
+    }
+}
@@ -0,0 +1,121 @@
+// Disassembler
+//
+// Decodes instructions with the opcode tables from opcodes/*.json: the table
+// entry gives the mnemonic, the length and the operands, and immediate
+// operands (d8, d16, a8, a16, r8) are filled in from the bytes that follow.
+// Relative jumps show their target address, so `20 FE` at 0x0150 reads
+// `JR NZ,$0150`.
+//
+// Bytes are read through a closure, so the same code lists a ROM file, the
+// live memory map or a plain slice.
+
+use crate::opcodes::{opcode_tables, OpCode};
+use std::fmt;
+
+// Listing more instructions than there are addresses only goes round again
+pub const MAX_COUNT: usize = 0x10000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+}
+
+impl Instruction {
+    // Address of the instruction after this one
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+
+    // Address, raw bytes and instruction, e.g. `0150  20 FE     JR NZ,$0150`
+    pub fn listing(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("{:04X}  {:<9} {}", self.address, bytes.join(" "), self)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands.join(","))
+        }
+    }
+}
+
+// Decode the instruction at `address`
+pub fn decode(read: impl Fn(u16) -> u8, address: u16) -> Instruction {
+    let (unprefixed, cbprefixed) = opcode_tables();
+    let opcode = read(address);
+    let entry = if opcode == 0xCB {
+        cbprefixed[read(address.wrapping_add(1)) as usize].as_ref()
+    } else {
+        unprefixed[opcode as usize].as_ref()
+    };
+    let Some(entry) = entry else {
+        // One of the unused opcodes, which lock up the CPU
+        return Instruction {
+            address,
+            bytes: vec![opcode],
+            mnemonic: "DB".to_string(),
+            operands: vec![format!("${:02X}", opcode)],
+        };
+    };
+
+    // The table lists STOP as 1 byte, but the CPU skips the byte after it
+    let length = if entry.mnemonic == "STOP" { 2 } else { entry.length };
+    let bytes: Vec<u8> = (0..length as u16).map(|i| read(address.wrapping_add(i))).collect();
+    let operands = if entry.mnemonic == "STOP" {
+        Vec::new()
+    } else {
+        [&entry.operand1, &entry.operand2]
+            .into_iter()
+            .flatten()
+            .map(|operand| format_operand(entry, operand, address, &bytes))
+            .collect()
+    };
+    Instruction { address, bytes, mnemonic: entry.mnemonic.clone(), operands }
+}
+
+// Decode `count` instructions one after the other from `start`
+pub fn disassemble(read: impl Fn(u16) -> u8, start: u16, count: usize) -> Vec<Instruction> {
+    let mut address = start;
+    // Not preallocated: the count may come straight from the user
+    let mut instructions = Vec::new();
+    for _ in 0..count {
+        let instruction = decode(&read, address);
+        address = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+fn format_operand(entry: &OpCode, operand: &str, address: u16, bytes: &[u8]) -> String {
+    let d8 = || bytes[1];
+    let d16 = || u16::from_le_bytes([bytes[1], bytes[2]]);
+    match operand {
+        "d8" => format!("${:02X}", d8()),
+        "d16" | "a16" => format!("${:04X}", d16()),
+        "(a16)" => format!("(${:04X})", d16()),
+        "(a8)" => format!("(${:02X})", d8()),
+        "r8" if entry.mnemonic == "JR" => {
+            let target = address.wrapping_add(bytes.len() as u16).wrapping_add(d8() as i8 as u16);
+            format!("${:04X}", target)
+        }
+        "r8" => signed(d8() as i8),
+        "SP+r8" => match d8() as i8 {
+            offset if offset < 0 => format!("SP{}", signed(offset)),
+            offset => format!("SP+{}", signed(offset)),
+        },
+        // RST vectors are written 00H-38H in the table
+        vector if vector.len() == 3 && vector.ends_with('H') => format!("${}", vector.trim_end_matches('H')),
+        register => register.to_string(),
+    }
+}
+
+fn signed(value: i8) -> String {
+    if value < 0 { format!("-${:02X}", value.unsigned_abs()) } else { format!("${:02X}", value) }
+}
@@ -0,0 +1,416 @@
+// DMG-07 Four Player Adapter
+//
+// The adapter is a hub with four link ports. It always drives the clock, so
+// every Game Boy talks to it as a slave (external clock). It goes through
+// two phases:
+//
+// Ping phase: the hub repeatedly sends each port a 4-byte ping
+//   0xFE, STAT, STAT, STAT
+// where STAT holds the player's ID (1-4) in bits 0-2 and a mask of the
+// connected players in bits 4-7. A Game Boy answers 0x88 (ACK) to the
+// 0xFE header and to the first STAT byte, then its transmission RATE and
+// packet SIZE to the other two. Player 1's RATE and SIZE are used for the
+// session. Player 1 ends the ping phase by answering a whole ping with 0xAA;
+// the hub then sends 0xCC four times and switches to transmission.
+//
+// Transmission phase: each round, every Game Boy sends its SIZE-byte packet
+// in the first SIZE transfers of a 4 x SIZE transfer round. Meanwhile the hub
+// sends everybody the combined packets of the previous round (player 1's
+// SIZE bytes first, then players 2-4). A round in which player 1 sends only
+// 0xFF restarts the ping phase.
+//
+// Bytes are clocked out every PING_INTERVAL cycles during the ping phase and
+// every BYTE_CYCLES + RATE x RATE_STEP_CYCLES cycles during transmission.
+// The hub's clock follows player 1's emulation: port 1's tick advances it.
+// Before clocking the next byte the hub waits (up to RESPONSE_TIMEOUT cycles)
+// until every attached Game Boy has exchanged the current one, so slower
+// partners, such as instances on the other end of a socket, don't miss bytes.
+//
+// Ports can be used in-process (Dmg07::port) or served to other emulator
+// instances over TCP (Dmg07::serve, RemotePort::connect). Remote players
+// coming and going are reported to the frontend's event handler.
+
+use crate::serial::SerialDevice;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const PLAYERS: usize = 4;
+
+const PING_HEADER: u8 = 0xFE;
+const ACK: u8 = 0x88;
+const START: u8 = 0xAA;
+const STARTING: u8 = 0xCC;
+const RESTART: u8 = 0xFF;
+
+const PING_INTERVAL: u32 = 16384;
+// One byte at the normal 8192 Hz serial clock
+const BYTE_CYCLES: u32 = 8 * 512;
+const RATE_STEP_CYCLES: u32 = 1024;
+// About one frame
+const RESPONSE_TIMEOUT: u32 = 70224;
+
+const MAX_PACKET_SIZE: usize = 16;
+
+// Remote port messages: hub byte to the Game Boy, Game Boy's byte back
+const CLOCK: u8 = 0x01;
+const REPLY: u8 = 0x02;
+
+// Remote players coming and going, for the frontend to report. Players are
+// numbered 1-4.
+#[derive(Debug)]
+pub enum Dmg07Event {
+    Connected(usize),
+    Disconnected(usize),
+    ConnectFailed(usize, io::Error),
+    // An instance was turned away because every port is taken
+    PortsFull,
+}
+
+pub type Dmg07EventFn = Arc<dyn Fn(Dmg07Event) + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Ping,
+    // Sending 0xCC before transmission starts
+    Starting,
+    Transmission,
+}
+
+#[derive(Default)]
+struct Port {
+    attached: bool,
+    // Answered the last ping header
+    connected: bool,
+    // Byte clocked out, waiting for a local Game Boy to take it
+    clocked: Option<u8>,
+    // Game Boy's byte for the current transfer
+    response: Option<u8>,
+    // Socket bridge of a remote Game Boy
+    remote: Option<Sender<u8>>,
+}
+
+struct Hub {
+    ports: [Port; PLAYERS],
+    phase: Phase,
+    // Index of the current byte within the ping, start sequence or round
+    position: usize,
+    rate: u8,
+    size: usize,
+    // Player 1 answered every byte of the current ping with 0xAA
+    start_requested: bool,
+    // Combined packets sent this round and collected for the next one
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+    cycles: u32,
+    awaiting_responses: bool,
+}
+
+impl Hub {
+    fn new() -> Self {
+        Self {
+            ports: Default::default(),
+            phase: Phase::Ping,
+            position: 0,
+            rate: 0,
+            size: 1,
+            start_requested: true,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+            cycles: 0,
+            awaiting_responses: false,
+        }
+    }
+
+    fn connected_mask(&self) -> u8 {
+        self.ports
+            .iter()
+            .enumerate()
+            .filter(|(_, port)| port.connected)
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
+    fn byte_interval(&self) -> u32 {
+        match self.phase {
+            Phase::Ping | Phase::Starting => PING_INTERVAL,
+            Phase::Transmission => BYTE_CYCLES + (self.rate & 0x0F) as u32 * RATE_STEP_CYCLES,
+        }
+    }
+
+    fn byte_for(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping if self.position == 0 => PING_HEADER,
+            Phase::Ping => (self.connected_mask() << 4) | (player as u8 + 1),
+            Phase::Starting => STARTING,
+            Phase::Transmission => self.outgoing[self.position],
+        }
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        if self.awaiting_responses {
+            let answered = self.ports.iter().all(|port| !port.attached || port.response.is_some());
+            if !answered && self.cycles < RESPONSE_TIMEOUT {
+                return;
+            }
+            self.finish_byte();
+            self.awaiting_responses = false;
+            self.cycles = 0;
+        }
+        if self.cycles >= self.byte_interval() {
+            self.cycles = 0;
+            self.clock_byte();
+        }
+    }
+
+    fn clock_byte(&mut self) {
+        for player in 0..PLAYERS {
+            let byte = self.byte_for(player);
+            let port = &mut self.ports[player];
+            port.response = None;
+            if !port.attached {
+                continue;
+            }
+            match &port.remote {
+                Some(remote) => {
+                    if remote.send(byte).is_err() {
+                        // The remote instance went away
+                        *port = Port::default();
+                    }
+                }
+                None => port.clocked = Some(byte),
+            }
+        }
+        self.awaiting_responses = true;
+    }
+
+    // Handle the Game Boys' bytes for the transfer that just ended
+    fn finish_byte(&mut self) {
+        let responses: Vec<u8> = self
+            .ports
+            .iter_mut()
+            .map(|port| {
+                // A Game Boy that didn't take the byte missed it
+                port.clocked = None;
+                port.response.take().unwrap_or(0xFF)
+            })
+            .collect();
+
+        match self.phase {
+            Phase::Ping => {
+                match self.position {
+                    0 => {
+                        for (port, &response) in self.ports.iter_mut().zip(&responses) {
+                            port.connected = port.attached && (response == ACK || response == START);
+                        }
+                    }
+                    2 if responses[0] != START => self.rate = responses[0],
+                    3 if responses[0] != START => {
+                        self.size = (responses[0] as usize).clamp(1, MAX_PACKET_SIZE);
+                    }
+                    _ => {}
+                }
+                self.start_requested &= responses[0] == START;
+                self.position += 1;
+                if self.position == 4 {
+                    self.position = 0;
+                    if self.start_requested {
+                        self.phase = Phase::Starting;
+                    }
+                    self.start_requested = true;
+                }
+            }
+            Phase::Starting => {
+                self.position += 1;
+                if self.position == 4 {
+                    self.position = 0;
+                    self.phase = Phase::Transmission;
+                    self.outgoing = vec![0; PLAYERS * self.size];
+                    self.incoming = vec![0; PLAYERS * self.size];
+                }
+            }
+            Phase::Transmission => {
+                if self.position < self.size {
+                    for (player, &response) in responses.iter().enumerate() {
+                        let byte = if self.ports[player].connected { response } else { 0x00 };
+                        self.incoming[player * self.size + self.position] = byte;
+                    }
+                }
+                self.position += 1;
+                if self.position == PLAYERS * self.size {
+                    self.position = 0;
+                    if self.incoming[..self.size].iter().all(|&b| b == RESTART) {
+                        self.phase = Phase::Ping;
+                        self.start_requested = true;
+                    } else {
+                        std::mem::swap(&mut self.outgoing, &mut self.incoming);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The adapter, shared by its ports
+#[derive(Clone)]
+pub struct Dmg07 {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl Default for Dmg07 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dmg07 {
+    pub fn new() -> Self {
+        Self { hub: Arc::new(Mutex::new(Hub::new())) }
+    }
+
+    // Plug an in-process Game Boy into port `player` (0-3). Port 0's emulation
+    // drives the adapter's clock.
+    pub fn port(&self, player: usize) -> Dmg07Port {
+        self.hub.lock().unwrap().ports[player].attached = true;
+        Dmg07Port { hub: self.hub.clone(), player }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.hub.lock().unwrap().phase
+    }
+
+    // Serve ports 2-4 to other emulator instances connecting on `addr`.
+    // Connections are accepted in the background and reported to `on_event`.
+    pub fn serve(
+        &self,
+        addr: impl ToSocketAddrs,
+        on_event: impl Fn(Dmg07Event) + Send + Sync + 'static,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let hub = self.hub.clone();
+        let on_event: Dmg07EventFn = Arc::new(on_event);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let free = hub.lock().unwrap().ports.iter().skip(1).position(|port| !port.attached);
+                match free {
+                    Some(i) => {
+                        if let Err(e) = bridge(hub.clone(), i + 1, stream, on_event.clone()) {
+                            on_event(Dmg07Event::ConnectFailed(i + 2, e));
+                        }
+                    }
+                    // All ports taken: dropping the stream turns the instance away
+                    None => on_event(Dmg07Event::PortsFull),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// Relay a hub port to a remote instance
+fn bridge(hub: Arc<Mutex<Hub>>, player: usize, stream: TcpStream, on_event: Dmg07EventFn) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = stream;
+
+    let (sender, bytes) = mpsc::channel::<u8>();
+    {
+        let mut hub = hub.lock().unwrap();
+        hub.ports[player] = Port { attached: true, remote: Some(sender), ..Port::default() };
+    }
+    on_event(Dmg07Event::Connected(player + 1));
+
+    thread::spawn(move || {
+        for byte in bytes {
+            if writer.write_all(&[CLOCK, byte]).is_err() {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut buf = [0; 2];
+        while reader.read_exact(&mut buf).is_ok() {
+            if buf[0] == REPLY {
+                hub.lock().unwrap().ports[player].response = Some(buf[1]);
+            }
+        }
+        hub.lock().unwrap().ports[player] = Port::default();
+        on_event(Dmg07Event::Disconnected(player + 1));
+    });
+    Ok(())
+}
+
+// A Game Boy plugged into the adapter in the same process
+pub struct Dmg07Port {
+    hub: Arc<Mutex<Hub>>,
+    player: usize,
+}
+
+impl SerialDevice for Dmg07Port {
+    // The adapter ignores Game Boys trying to drive the clock
+    fn transfer_master(&mut self, _data: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll_slave(&mut self, data: u8) -> Option<u8> {
+        let mut hub = self.hub.lock().unwrap();
+        let port = &mut hub.ports[self.player];
+        let byte = port.clocked.take()?;
+        port.response = Some(data);
+        Some(byte)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.player == 0 {
+            self.hub.lock().unwrap().advance(cycles);
+        }
+    }
+}
+
+impl Drop for Dmg07Port {
+    fn drop(&mut self) {
+        if let Ok(mut hub) = self.hub.lock() {
+            hub.ports[self.player] = Port::default();
+        }
+    }
+}
+
+// A Game Boy plugged into an adapter served by another instance
+pub struct RemotePort {
+    stream: TcpStream,
+    incoming: Receiver<u8>,
+}
+
+impl RemotePort {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let (sender, incoming) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut buf = [0; 2];
+            while reader.read_exact(&mut buf).is_ok() {
+                if buf[0] == CLOCK && sender.send(buf[1]).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { stream, incoming })
+    }
+}
+
+impl SerialDevice for RemotePort {
+    fn transfer_master(&mut self, _data: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll_slave(&mut self, data: u8) -> Option<u8> {
+        let byte = self.incoming.try_recv().ok()?;
+        // A write error means the adapter is gone; no more bytes will arrive
+        let _ = self.stream.write_all(&[REPLY, data]);
+        Some(byte)
+    }
+}
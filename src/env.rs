@@ -0,0 +1,172 @@
+// Reinforcement-learning environment
+//
+// A gym-style wrapper around GameBoy: reset() goes back to the start state,
+// step() holds the buttons for a few frames and returns what the agent sees,
+// the reward and whether the episode ended. Rewards and episode ends are
+// defined by the caller from RAM (score, lives, position...).
+//
+// Environments are Send and cheap to clone: clones share the ROM, the start
+// state and the reward and done functions, so one set-up environment can be
+// cloned once per worker thread. Serial devices aren't cloned; a clone runs
+// with an empty link port.
+
+use crate::gameboy::GameBoy;
+use crate::input::Input;
+use crate::memory::Memory;
+use std::sync::Arc;
+
+// Reward for a step from RAM before and after it, e.g. the score difference
+pub type RewardFn = Arc<dyn Fn(&Memory, &Memory) -> f32 + Send + Sync>;
+// Whether the episode is over, e.g. no lives left
+pub type DoneFn = Arc<dyn Fn(&Memory) -> bool + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ObservationKind {
+    // The framebuffer as it is
+    #[default]
+    Rgb,
+    // Luma, averaged over `factor` x `factor` blocks. Pixels left over at the
+    // right and bottom edges are dropped.
+    Grayscale { factor: usize },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Observation {
+    // 0xRRGGBB
+    Rgb { width: usize, height: usize, pixels: Vec<u32> },
+    // 0 black to 255 white
+    Grayscale { width: usize, height: usize, pixels: Vec<u8> },
+}
+
+impl Observation {
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            Observation::Rgb { width, height, .. } | Observation::Grayscale { width, height, .. } => (*width, *height),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub observation: Observation,
+    pub reward: f32,
+    pub done: bool,
+}
+
+#[derive(Clone)]
+pub struct Env {
+    // Boxed: the machine is a few hundred KB, too much to move around on
+    // worker thread stacks
+    gameboy: Box<GameBoy>,
+    // Save state reset() goes back to
+    start: Arc<[u8]>,
+    observation: ObservationKind,
+    reward: Option<RewardFn>,
+    done: Option<DoneFn>,
+    // Frames run since the last reset
+    frames: u64,
+}
+
+impl Env {
+    // Episodes start from the machine as it is now; load the cartridge and
+    // get past the title screen before handing it over
+    pub fn new(gameboy: GameBoy) -> Self {
+        Self {
+            start: gameboy.save_state().into(),
+            gameboy: Box::new(gameboy),
+            observation: ObservationKind::default(),
+            reward: None,
+            done: None,
+            frames: 0,
+        }
+    }
+
+    pub fn set_observation(&mut self, kind: ObservationKind) {
+        self.observation = kind;
+    }
+
+    // Without a reward function every step is worth 0
+    pub fn set_reward(&mut self, reward: impl Fn(&Memory, &Memory) -> f32 + Send + Sync + 'static) {
+        self.reward = Some(Arc::new(reward));
+    }
+
+    // Without a done function episodes never end on their own
+    pub fn set_done(&mut self, done: impl Fn(&Memory) -> bool + Send + Sync + 'static) {
+        self.done = Some(Arc::new(done));
+    }
+
+    // Back to the start state, with no buttons held
+    pub fn reset(&mut self) -> Observation {
+        self.gameboy.load_state(&self.start).expect("start state comes from this game");
+        self.gameboy.set_buttons(Input::new());
+        self.frames = 0;
+        self.observe()
+    }
+
+    // Hold `action` for `frameskip` frames (at least one) and observe the
+    // last. Stops early when the episode ends. The reward covers all the
+    // frames run.
+    pub fn step(&mut self, action: Input, frameskip: u32) -> Step {
+        let before = self.reward.is_some().then(|| self.gameboy.memory().clone());
+        self.gameboy.set_buttons(action);
+
+        let mut done = false;
+        for _ in 0..frameskip.max(1) {
+            self.gameboy.run_frame();
+            self.frames += 1;
+            done = self.done.as_ref().is_some_and(|done| done(self.gameboy.memory()));
+            if done {
+                break;
+            }
+        }
+
+        let reward = match (&self.reward, &before) {
+            (Some(reward), Some(before)) => reward(before, self.gameboy.memory()),
+            _ => 0.0,
+        };
+        Step { observation: self.observe(), reward, done }
+    }
+
+    pub fn observe(&self) -> Observation {
+        let (width, height) = self.gameboy.screen_size();
+        let framebuffer = self.gameboy.framebuffer();
+        match self.observation {
+            ObservationKind::Rgb => Observation::Rgb { width, height, pixels: framebuffer.to_vec() },
+            ObservationKind::Grayscale { factor } => {
+                let factor = factor.max(1);
+                let (out_width, out_height) = (width / factor, height / factor);
+                let mut pixels = Vec::with_capacity(out_width * out_height);
+                for y in 0..out_height {
+                    for x in 0..out_width {
+                        let mut sum = 0;
+                        for dy in 0..factor {
+                            let row = (y * factor + dy) * width + x * factor;
+                            sum += framebuffer[row..row + factor].iter().map(|&pixel| luma(pixel)).sum::<u32>();
+                        }
+                        pixels.push((sum / (factor * factor) as u32) as u8);
+                    }
+                }
+                Observation::Grayscale { width: out_width, height: out_height, pixels }
+            }
+        }
+    }
+
+    // Frames run since the last reset
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn gameboy(&self) -> &GameBoy {
+        &self.gameboy
+    }
+
+    pub fn gameboy_mut(&mut self) -> &mut GameBoy {
+        &mut self.gameboy
+    }
+}
+
+// ITU-R BT.601 weights, 0-255
+fn luma(pixel: u32) -> u32 {
+    let (r, g, b) = ((pixel >> 16) & 0xFF, (pixel >> 8) & 0xFF, pixel & 0xFF);
+    (r * 299 + g * 587 + b * 114) / 1000
+}
@@ -0,0 +1,247 @@
+// Top-level emulator API
+//
+// GameBoy ties together the CPU (which owns memory and the peripherals on the
+// bus), the GPU and the joypad behind a small interface for frontends: load a
+// cartridge, set the buttons, run a frame, read the framebuffer.
+//
+// Timers and sound aren't emulated yet. When they are, they belong in here so
+// frontends keep driving everything through run_frame().
+
+use crate::bess;
+use crate::cartridge::CartridgeHeader;
+use crate::colorization::{palette_for_header, ButtonPalette};
+use crate::cpu::CPU;
+use crate::gpu::{GPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::image::crc32;
+use crate::input::Input;
+use crate::memory::{Memory, Model};
+use crate::pacing::CYCLES_PER_FRAME;
+use crate::savestate::{StateReader, StateWriter};
+use crate::serial::SerialDevice;
+use crate::sgb::{SGB_HEIGHT, SGB_WIDTH};
+use std::io;
+
+// Clones are independent emulators. They share the ROM, but a serial device
+// stays with the original.
+#[derive(Clone)]
+pub struct GameBoy {
+    cpu: CPU,
+    gpu: GPU,
+    input: Input,
+    // Button combo palette chosen at boot, instead of the automatic one
+    palette_override: Option<ButtonPalette>,
+    // Super Game Boy output with the border
+    sgb_frame: Vec<u32>,
+    // CRC-32 of the cartridge ROM, so states are only loaded into the game
+    // they were saved from
+    rom_crc: u32,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GameBoy {
+    pub fn new() -> Self {
+        Self {
+            cpu: CPU::new(),
+            gpu: GPU::new(),
+            input: Input::new(),
+            palette_override: None,
+            sgb_frame: Vec::new(),
+            rom_crc: 0,
+        }
+    }
+
+    // Force a hardware model instead of picking it from the cartridge header.
+    // Must be called before load_cartridge.
+    pub fn set_model(&mut self, model: Model) {
+        self.cpu.get_memory_mut().set_model(model);
+    }
+
+    // Palette for DMG games in CGB compatibility mode. Must be called before
+    // load_cartridge.
+    pub fn set_palette_override(&mut self, palette: ButtonPalette) {
+        self.palette_override = Some(palette);
+    }
+
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.gpu.set_color_correction(enabled);
+    }
+
+    pub fn load_boot_rom(&mut self, data: &[u8]) {
+        self.cpu.load_boot_rom(data);
+    }
+
+    // Insert a cartridge. Returns its header, or None if the ROM is too small
+    // to have one.
+    pub fn load_cartridge(&mut self, rom: Vec<u8>) -> Option<CartridgeHeader> {
+        let header = CartridgeHeader::parse(&rom);
+        self.rom_crc = crc32(&rom);
+        self.cpu.load_rom(rom);
+
+        let memory = self.cpu.get_memory_mut();
+        if let Some(header) = &header
+            && memory.is_dmg_compatibility()
+        {
+            // Colorize like the CGB boot ROM, unless a button combo palette was chosen
+            let palette = match self.palette_override {
+                Some(combo) => combo.palette(),
+                None => palette_for_header(header),
+            };
+            memory.load_compat_palette(&palette);
+        }
+        if memory.sgb().is_some() {
+            self.sgb_frame = vec![0; SGB_WIDTH * SGB_HEIGHT];
+        }
+        header
+    }
+
+    // Plug a link cable, printer or adapter into the serial port
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.get_memory_mut().connect_serial(device);
+    }
+
+    pub fn set_buttons(&mut self, input: Input) {
+        self.input = input;
+        self.cpu.get_memory_mut().set_joypad(input);
+    }
+
+    pub fn buttons(&self) -> Input {
+        self.input
+    }
+
+    // Run one instruction. Returns the elapsed system cycles.
+    pub fn step(&mut self) -> u8 {
+        let cycles = self.cpu.step();
+        self.gpu.step(cycles, self.cpu.get_memory_mut());
+        cycles
+    }
+
+    // Run until a frame's worth of cycles (70224) has passed
+    pub fn run_frame(&mut self) {
+        let start = self.cpu.get_ticks();
+        while self.cpu.get_ticks() - start < CYCLES_PER_FRAME as u128 {
+            self.step();
+        }
+        self.finish_frame();
+    }
+
+    // Compose the output picture from the current screen. run_frame does this
+    // after every frame; callers that step on their own call it before
+    // reading the framebuffer.
+    pub fn finish_frame(&mut self) {
+        if let Some(sgb) = self.cpu.get_memory_mut().sgb_mut() {
+            sgb.render(&self.gpu.shades, &mut self.sgb_frame);
+        }
+    }
+
+    // CRC-32 of the cartridge ROM, which save states and movies are tied to
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    // Snapshot of the whole machine, see savestate.rs for the format. The
+    // cartridge, boot ROM and connected serial device aren't included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.rom_crc);
+        w.section(b"CPU ", |w| self.cpu.save_state(w));
+        self.cpu.get_memory().save_state(&mut w);
+        w.section(b"GPU ", |w| self.gpu.save_state(w));
+        w.finish()
+    }
+
+    // Restore a snapshot made by save_state. On error the machine is left
+    // as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let backup = self.save_state();
+        let result = self.apply_state(data);
+        if result.is_err() {
+            self.apply_state(&backup).expect("restoring the state from before a failed load");
+        }
+        result
+    }
+
+    fn apply_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut r = StateReader::new(data);
+        if r.header()? != self.rom_crc {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "save state is from a different game"));
+        }
+        let mut required = [&b"CPU "[..], b"MEM ", b"GPU "].to_vec();
+        while let Some((tag, mut section)) = r.section()? {
+            match &tag {
+                b"CPU " => self.cpu.load_state(&mut section)?,
+                b"GPU " => self.gpu.load_state(&mut section)?,
+                // Sections from newer versions are skipped
+                _ => {
+                    self.cpu.get_memory_mut().load_section(&tag, &mut section)?;
+                }
+            }
+            required.retain(|&t| t != tag);
+        }
+        if let Some(missing) = required.first() {
+            let message = format!("save state has no {} section", String::from_utf8_lossy(missing).trim());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        self.finish_frame();
+        Ok(())
+    }
+
+    // Snapshot in the BESS format shared with other emulators, see bess.rs
+    pub fn export_bess(&self) -> Vec<u8> {
+        bess::export(self)
+    }
+
+    // Load a BESS state from this or another emulator. On error the machine
+    // is left as it was.
+    pub fn import_bess(&mut self, data: &[u8]) -> io::Result<()> {
+        bess::import(self, data)?;
+        self.finish_frame();
+        Ok(())
+    }
+
+    // The picture to show, 0xRRGGBB, screen_size() pixels. On the Super Game
+    // Boy this is the whole 256x224 output including the border.
+    pub fn framebuffer(&self) -> &[u32] {
+        if self.is_sgb() { &self.sgb_frame } else { &self.gpu.framebuffer }
+    }
+
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.is_sgb() { (SGB_WIDTH, SGB_HEIGHT) } else { (SCREEN_WIDTH, SCREEN_HEIGHT) }
+    }
+
+    fn is_sgb(&self) -> bool {
+        self.cpu.get_memory().sgb().is_some()
+    }
+
+    // Total system cycles emulated
+    pub fn cycles(&self) -> u128 {
+        self.cpu.get_ticks()
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn gpu(&self) -> &GPU {
+        &self.gpu
+    }
+
+    pub(crate) fn gpu_mut(&mut self) -> &mut GPU {
+        &mut self.gpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        self.cpu.get_memory()
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        self.cpu.get_memory_mut()
+    }
+}
@@ -0,0 +1,255 @@
+// Golden-image regression tests
+//
+// Runs ROMs headlessly for a fixed number of frames and compares the final
+// framebuffer with a stored reference image. The tests are listed in a JSON
+// manifest; paths are relative to the manifest's directory:
+//
+//   {
+//     "tests": [
+//       {
+//         "name": "tetris-title",
+//         "rom": "roms/tetris.gb",
+//         "frames": 300,
+//         "model": "dmg",
+//         "input": [
+//           { "frame": 120, "buttons": ["start"] },
+//           { "frame": 125, "buttons": [] }
+//         ],
+//         "expected": "expected/tetris-title.png"
+//       }
+//     ]
+//   }
+//
+// "model" and "input" are optional. Each input entry holds the buttons down
+// from the start of that frame until the next entry. Expected images are PNG
+// or PPM files.
+//
+// On a mismatch the actual picture and a diff image (differing pixels in red
+// over a faded copy of the expected picture) are written to the output
+// directory as <name>.actual.png and <name>.diff.png. Blessing writes the
+// actual pictures as the new expected images instead of comparing. Names are
+// used as file names, so they can't contain path separators.
+
+use crate::gameboy::GameBoy;
+use crate::image::{crc32, read_image, write_image, write_png, Image};
+use crate::input::Input;
+use crate::memory::Model;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    pub tests: Vec<TestCase>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestCase {
+    pub name: String,
+    pub rom: PathBuf,
+    pub frames: u64,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub input: Vec<InputEvent>,
+    pub expected: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub buttons: Vec<String>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GoldenOptions {
+    // Where actual and diff images of failed tests go
+    pub output_dir: PathBuf,
+    // Save the actual pictures as the expected images
+    pub bless: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    // Differing pixel count and the diff image
+    Mismatch { pixels: usize, diff: PathBuf },
+    // The picture has a different size than the expected image
+    SizeMismatch { actual: (usize, usize), expected: (usize, usize) },
+    Blessed,
+    // The test couldn't run: missing ROM, bad manifest entry, unreadable image
+    Error(String),
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    // CRC-32 of the final framebuffer, handy for spotting identical runs
+    pub hash: Option<u32>,
+    pub outcome: Outcome,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, Outcome::Pass | Outcome::Blessed)
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hash = self.hash.map(|h| format!(" [{:08X}]", h)).unwrap_or_default();
+        match &self.outcome {
+            Outcome::Pass => write!(f, "PASS {}{}", self.name, hash),
+            Outcome::Blessed => write!(f, "BLESSED {}{}", self.name, hash),
+            Outcome::Mismatch { pixels, diff } => {
+                write!(f, "FAIL {}{}: {} pixels differ, see {}", self.name, hash, pixels, diff.display())
+            }
+            Outcome::SizeMismatch { actual, expected } => write!(
+                f,
+                "FAIL {}{}: picture is {}x{}, expected {}x{}",
+                self.name, hash, actual.0, actual.1, expected.0, expected.1
+            ),
+            Outcome::Error(message) => write!(f, "ERROR {}: {}", self.name, message),
+        }
+    }
+}
+
+// Run every test in the manifest at `path`
+pub fn run_manifest(path: &Path, options: &GoldenOptions) -> io::Result<Vec<TestResult>> {
+    let manifest = Manifest::load(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(manifest.tests.iter().map(|test| run_test(test, base, options)).collect())
+}
+
+pub fn run_test(test: &TestCase, base: &Path, options: &GoldenOptions) -> TestResult {
+    let (hash, outcome) = match try_run_test(test, base, options) {
+        Ok((hash, outcome)) => (Some(hash), outcome),
+        Err(e) => (None, Outcome::Error(e.to_string())),
+    };
+    TestResult { name: test.name.clone(), hash, outcome }
+}
+
+fn try_run_test(test: &TestCase, base: &Path, options: &GoldenOptions) -> io::Result<(u32, Outcome)> {
+    check_name(&test.name)?;
+    let rom = fs::read(base.join(&test.rom))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", test.rom.display(), e)))?;
+    let actual = run_rom(test, rom)?;
+    let hash = crc32(&actual.pixels.iter().flat_map(|p| p.to_le_bytes()).collect::<Vec<u8>>());
+
+    let expected_path = base.join(&test.expected);
+    if options.bless {
+        if let Some(dir) = expected_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_image(&expected_path, actual.width, actual.height, &actual.pixels)?;
+        return Ok((hash, Outcome::Blessed));
+    }
+
+    let expected = read_image(&expected_path)?;
+    if (actual.width, actual.height) != (expected.width, expected.height) {
+        let outcome = Outcome::SizeMismatch {
+            actual: (actual.width, actual.height),
+            expected: (expected.width, expected.height),
+        };
+        return Ok((hash, outcome));
+    }
+
+    let differing = actual.pixels.iter().zip(&expected.pixels).filter(|(a, e)| a != e).count();
+    if differing == 0 {
+        return Ok((hash, Outcome::Pass));
+    }
+
+    fs::create_dir_all(&options.output_dir)?;
+    let actual_path = options.output_dir.join(format!("{}.actual.png", test.name));
+    write_png(&actual_path, actual.width, actual.height, &actual.pixels)?;
+    let diff = options.output_dir.join(format!("{}.diff.png", test.name));
+    write_png(&diff, actual.width, actual.height, &diff_pixels(&actual, &expected))?;
+    Ok((hash, Outcome::Mismatch { pixels: differing, diff }))
+}
+
+// Run a test's ROM with its input script and return the final picture
+fn run_rom(test: &TestCase, rom: Vec<u8>) -> io::Result<Image> {
+    let mut gameboy = GameBoy::new();
+    if let Some(name) = &test.model {
+        gameboy.set_model(parse_model(name)?);
+    }
+    gameboy.load_cartridge(rom);
+
+    let mut events = test.input.iter().peekable();
+    for frame in 0..test.frames {
+        while let Some(event) = events.next_if(|event| event.frame <= frame) {
+            gameboy.set_buttons(parse_buttons(&event.buttons)?);
+        }
+        gameboy.run_frame();
+    }
+
+    let (width, height) = gameboy.screen_size();
+    Ok(Image { width, height, pixels: gameboy.framebuffer().to_vec() })
+}
+
+// Red where the pictures differ, a faded expected picture elsewhere
+fn diff_pixels(actual: &Image, expected: &Image) -> Vec<u32> {
+    actual
+        .pixels
+        .iter()
+        .zip(&expected.pixels)
+        .map(|(&a, &e)| {
+            if a != e {
+                0xFF0000
+            } else {
+                // Halfway to white
+                ((e >> 1) & 0x7F7F7F) + 0x808080
+            }
+        })
+        .collect()
+}
+
+// The output files must stay inside the output directory
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == ".." || name.contains(['/', '\\']) {
+        return Err(invalid(format!("test name can't be used as a file name: {:?}", name)));
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_model(name: &str) -> io::Result<Model> {
+    match name {
+        "dmg" => Ok(Model::Dmg),
+        "cgb" => Ok(Model::Cgb),
+        "sgb" => Ok(Model::Sgb),
+        other => Err(invalid(format!("unknown model: {}", other))),
+    }
+}
+
+fn parse_buttons(names: &[String]) -> io::Result<Input> {
+    let mut input = Input::new();
+    for name in names {
+        let button = match name.as_str() {
+            "a" => &mut input.a,
+            "b" => &mut input.b,
+            "select" => &mut input.select,
+            "start" => &mut input.start,
+            "up" => &mut input.up,
+            "down" => &mut input.down,
+            "left" => &mut input.left,
+            "right" => &mut input.right,
+            other => return Err(invalid(format!("unknown button: {}", other))),
+        };
+        *button = true;
+    }
+    Ok(input)
+}
@@ -1,8 +1,23 @@
 use crate::memory::Memory;
+use crate::savestate::{StateReader, StateWriter};
+use std::io;
 
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 
+// Scanline timing in system cycles
+const CYCLES_PER_LINE: u32 = 456;
+const LINES_PER_FRAME: u32 = 154;
+const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE * LINES_PER_FRAME; // 70224
+const OAM_SCAN_CYCLES: u32 = 80;
+const PIXEL_TRANSFER_CYCLES: u32 = 172;
+
+// STAT modes
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_PIXEL_TRANSFER: u8 = 3;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum Color {
     White = 0xFFFFFF,
@@ -27,9 +42,48 @@ impl Color {
     }
 }
 
+// Convert a CGB RGB555 color to the framebuffer's 0x00RRGGBB format.
+// With color correction the channels are mixed to mimic the washed-out
+// colors of the CGB LCD instead of showing the raw values at full saturation.
+pub fn rgb555_to_u32(color: u16, color_correction: bool) -> u32 {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+
+    let (r, g, b) = if color_correction {
+        (
+            (r * 26 + g * 4 + b * 2).min(960) >> 2,
+            (g * 24 + b * 8).min(960) >> 2,
+            (r * 6 + g * 4 + b * 22).min(960) >> 2,
+        )
+    } else {
+        ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2))
+    };
+
+    (r << 16) | (g << 8) | b
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct GPU {
     pub framebuffer: [u32; SCREEN_WIDTH * SCREEN_HEIGHT],
     pub cycles: u32,
+    pub line: u8,
+    pub mode: u8,
+    // DMG shade (0-3, after BGP/OBP mapping) of each pixel, colorized by the SGB
+    pub shades: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    color_correction: bool,
+    // DMG game on CGB hardware: DMG palettes map shades to CGB palette RAM colors
+    dmg_compatibility: bool,
+    // BG color index and CGB BG-to-OBJ priority per pixel, used to resolve sprite priority
+    bg_color_ids: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    bg_priority: [bool; SCREEN_WIDTH * SCREEN_HEIGHT],
+}
+
+impl Default for GPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GPU {
@@ -37,16 +91,86 @@ impl GPU {
         Self {
             framebuffer: [Color::White.to_u32(); SCREEN_WIDTH * SCREEN_HEIGHT],
             cycles: 0,
+            line: 0,
+            mode: MODE_OAM_SCAN,
+            shades: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_correction: false,
+            dmg_compatibility: false,
+            bg_color_ids: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            bg_priority: [false; SCREEN_WIDTH * SCREEN_HEIGHT],
         }
     }
 
-    pub fn step(&mut self, cycles: u8, memory: &Memory) {
-        self.cycles += cycles as u32;
-        
-        // Simple rendering: just update the framebuffer based on VRAM
-        // In a full implementation, this would handle different GPU modes and timing
-        if self.cycles >= 70224 { // Full frame
-            self.cycles = 0;
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
+
+    // The whole screen is rendered at once when VBlank starts, so only the
+    // LCD position and the last picture are state. Color correction is a
+    // frontend setting and stays as it is.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.cycles);
+        w.u8(self.line);
+        w.u8(self.mode);
+        w.u32s(&self.framebuffer);
+        w.bytes(&self.shades);
+    }
+
+    // Move the LCD to the start of `mode` on `line`, for states that only
+    // record LY and STAT
+    pub(crate) fn set_position(&mut self, line: u8, mode: u8) {
+        let line = line as u32 % LINES_PER_FRAME;
+        let (dot, mode) = match mode & 0x03 {
+            _ if line as usize >= SCREEN_HEIGHT => (0, MODE_VBLANK),
+            MODE_PIXEL_TRANSFER => (OAM_SCAN_CYCLES, MODE_PIXEL_TRANSFER),
+            MODE_HBLANK => (OAM_SCAN_CYCLES + PIXEL_TRANSFER_CYCLES, MODE_HBLANK),
+            _ => (0, MODE_OAM_SCAN),
+        };
+        self.cycles = line * CYCLES_PER_LINE + dot;
+        self.line = line as u8;
+        self.mode = mode;
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.cycles = r.u32()? % CYCLES_PER_FRAME;
+        self.line = r.u8()?;
+        self.mode = r.u8()?;
+        r.u32s(&mut self.framebuffer)?;
+        r.bytes(&mut self.shades)?;
+        Ok(())
+    }
+
+    // Advance the LCD by the given system cycles. Tracks LY and the STAT mode
+    // so HBlank DMA can run, and renders the whole screen when VBlank starts.
+    pub fn step(&mut self, cycles: u8, memory: &mut Memory) {
+        self.cycles = (self.cycles + cycles as u32) % CYCLES_PER_FRAME;
+
+        let line = (self.cycles / CYCLES_PER_LINE) as u8;
+        let dot = self.cycles % CYCLES_PER_LINE;
+        let mode = if line as usize >= SCREEN_HEIGHT {
+            MODE_VBLANK
+        } else if dot < OAM_SCAN_CYCLES {
+            MODE_OAM_SCAN
+        } else if dot < OAM_SCAN_CYCLES + PIXEL_TRANSFER_CYCLES {
+            MODE_PIXEL_TRANSFER
+        } else {
+            MODE_HBLANK
+        };
+
+        // A single instruction can never span a whole line, so comparing against
+        // the previous line/mode is enough to catch every transition
+        let line_changed = line != self.line;
+        let mode_changed = mode != self.mode;
+        self.line = line;
+        self.mode = mode;
+
+        if line_changed || mode_changed {
+            memory.set_lcd_status(line, mode);
+        }
+        if mode_changed && mode == MODE_HBLANK {
+            memory.hblank();
+        }
+        if mode_changed && mode == MODE_VBLANK {
             self.render_screen(memory);
         }
     }
@@ -56,18 +180,30 @@ impl GPU {
         for pixel in self.framebuffer.iter_mut() {
             *pixel = Color::White.to_u32();
         }
+        self.shades.fill(0);
+        self.bg_color_ids.fill(0);
+        self.bg_priority.fill(false);
 
         // Read LCD control register
         let lcdc = memory.read(0xFF40);
-        let bg_enabled = (lcdc & 0x01) != 0;
-        
-        if !bg_enabled {
-            return;
+        let cgb = memory.is_cgb_mode();
+        self.dmg_compatibility = memory.is_dmg_compatibility();
+
+        // On CGB, LCDC bit 0 is the BG master priority instead of a BG enable
+        if cgb || (lcdc & 0x01) != 0 {
+            self.render_background(memory, lcdc, cgb);
+        }
+
+        if (lcdc & 0x02) != 0 {
+            self.render_sprites(memory, lcdc, cgb);
         }
+    }
 
-        // Get scroll positions
+    fn render_background(&mut self, memory: &Memory, lcdc: u8, cgb: bool) {
+        // Get scroll positions and the DMG BG palette
         let scy = memory.read(0xFF42);
         let scx = memory.read(0xFF43);
+        let bgp = memory.read(0xFF47);
 
         // Determine tile map and tile data addresses
         let bg_map = if (lcdc & 0x08) != 0 { 0x9C00 } else { 0x9800 };
@@ -82,12 +218,23 @@ impl GPU {
                 
                 let tile_row = map_y / 8;
                 let tile_col = map_x / 8;
-                let tile_y = map_y % 8;
-                let tile_x = map_x % 8;
+                let mut tile_y = map_y % 8;
+                let mut tile_x = map_x % 8;
 
                 // Get tile number from background map
                 let tile_addr = bg_map + (tile_row as u16 % 32) * 32 + (tile_col as u16 % 32);
-                let tile_num = memory.read(tile_addr);
+                let tile_num = memory.read_vram(0, tile_addr);
+
+                // CGB BG attributes live at the same map address in VRAM bank 1:
+                // bits 0-2 palette, bit 3 tile bank, bit 5 X flip, bit 6 Y flip, bit 7 priority
+                let attributes = if cgb { memory.read_vram(1, tile_addr) } else { 0 };
+                let tile_bank = ((attributes >> 3) & 0x01) as usize;
+                if (attributes & 0x20) != 0 {
+                    tile_x = 7 - tile_x;
+                }
+                if (attributes & 0x40) != 0 {
+                    tile_y = 7 - tile_y;
+                }
 
                 // Calculate tile data address
                 let tile_data_addr = if use_signed {
@@ -98,8 +245,8 @@ impl GPU {
                 };
 
                 // Each tile is 16 bytes, 2 bytes per row
-                let byte1 = memory.read(tile_data_addr + (tile_y as u16 * 2));
-                let byte2 = memory.read(tile_data_addr + (tile_y as u16 * 2) + 1);
+                let byte1 = memory.read_vram(tile_bank, tile_data_addr + (tile_y as u16 * 2));
+                let byte2 = memory.read_vram(tile_bank, tile_data_addr + (tile_y as u16 * 2) + 1);
 
                 // Get color for this pixel (bits are in reverse order)
                 let bit_pos = 7 - tile_x;
@@ -107,8 +254,104 @@ impl GPU {
                 let color_high = (byte2 >> bit_pos) & 1;
                 let color_id = (color_high << 1) | color_low;
 
-                let color = Color::from_id(color_id);
-                self.framebuffer[y * SCREEN_WIDTH + x] = color.to_u32();
+                let index = y * SCREEN_WIDTH + x;
+                self.bg_color_ids[index] = color_id;
+                self.bg_priority[index] = (attributes & 0x80) != 0;
+                self.framebuffer[index] = if cgb {
+                    let color = memory.bg_palette_color(attributes & 0x07, color_id);
+                    rgb555_to_u32(color, self.color_correction)
+                } else {
+                    let shade = (bgp >> (color_id * 2)) & 0x03;
+                    self.shades[index] = shade;
+                    if self.dmg_compatibility {
+                        rgb555_to_u32(memory.bg_palette_color(0, shade), self.color_correction)
+                    } else {
+                        Color::from_id(shade).to_u32()
+                    }
+                };
+            }
+        }
+    }
+
+    fn render_sprites(&mut self, memory: &Memory, lcdc: u8, cgb: bool) {
+        let height: i32 = if (lcdc & 0x04) != 0 { 16 } else { 8 };
+        let bg_master_priority = !cgb || (lcdc & 0x01) != 0;
+
+        for y in 0..SCREEN_HEIGHT as i32 {
+            // Up to 10 sprites per line, in OAM order: (y, x, tile, attributes)
+            let mut line_sprites = [(0i32, 0i32, 0u8, 0u8); 10];
+            let mut count = 0;
+            for i in 0..40u16 {
+                let base = 0xFE00 + i * 4;
+                let sprite_y = memory.read(base) as i32 - 16;
+                if y >= sprite_y && y < sprite_y + height {
+                    line_sprites[count] = (
+                        sprite_y,
+                        memory.read(base + 1) as i32 - 8,
+                        memory.read(base + 2),
+                        memory.read(base + 3),
+                    );
+                    count += 1;
+                    if count == line_sprites.len() {
+                        break;
+                    }
+                }
+            }
+            let sprites = &mut line_sprites[..count];
+
+            // DMG gives priority to the lowest X coordinate, CGB to the lowest OAM index
+            if !cgb {
+                sprites.sort_by_key(|&(_, sprite_x, _, _)| sprite_x);
+            }
+
+            let mut occupied = [false; SCREEN_WIDTH];
+            for &(sprite_y, sprite_x, tile, attributes) in sprites.iter() {
+                let mut row = y - sprite_y;
+                if (attributes & 0x40) != 0 {
+                    row = height - 1 - row;
+                }
+                let tile = if height == 16 { tile & 0xFE } else { tile };
+                let tile_bank = if cgb { ((attributes >> 3) & 0x01) as usize } else { 0 };
+                let addr = 0x8000 + tile as u16 * 16 + row as u16 * 2;
+                let byte1 = memory.read_vram(tile_bank, addr);
+                let byte2 = memory.read_vram(tile_bank, addr + 1);
+
+                for px in 0..8 {
+                    let x = sprite_x + px;
+                    if x < 0 || x >= SCREEN_WIDTH as i32 || occupied[x as usize] {
+                        continue;
+                    }
+
+                    let bit_pos = if (attributes & 0x20) != 0 { px } else { 7 - px };
+                    let color_id = (((byte2 >> bit_pos) & 1) << 1) | ((byte1 >> bit_pos) & 1);
+                    if color_id == 0 {
+                        continue;
+                    }
+                    occupied[x as usize] = true;
+
+                    let index = y as usize * SCREEN_WIDTH + x as usize;
+                    let bg_wins = self.bg_color_ids[index] != 0
+                        && ((attributes & 0x80) != 0 || self.bg_priority[index]);
+                    if bg_master_priority && bg_wins {
+                        continue;
+                    }
+
+                    self.framebuffer[index] = if cgb {
+                        let color = memory.obj_palette_color(attributes & 0x07, color_id);
+                        rgb555_to_u32(color, self.color_correction)
+                    } else {
+                        let obp_select = (attributes >> 4) & 0x01;
+                        let obp = memory.read(0xFF48 + obp_select as u16);
+                        let shade = (obp >> (color_id * 2)) & 0x03;
+                        self.shades[index] = shade;
+                        if self.dmg_compatibility {
+                            let color = memory.obj_palette_color(obp_select, shade);
+                            rgb555_to_u32(color, self.color_correction)
+                        } else {
+                            Color::from_id(shade).to_u32()
+                        }
+                    };
+                }
             }
         }
     }
@@ -1,34 +1,175 @@
-use crate::cpu::CPU;
-use crate::gpu::{GPU, SCREEN_WIDTH, SCREEN_HEIGHT};
-use crate::input::Input;
-use minifb::{Window, WindowOptions, Key};
-use std::time::{Duration, Instant};
+use gb_emulator::movie::{Player, Recorder};
+use gb_emulator::pacing::{frame_duration, FramePacer, Speed, SpeedMeter};
+use gb_emulator::rewind::{Rewind, RewindConfig};
+use gb_emulator::runahead::RunAhead;
+use gb_emulator::{GameBoy, Input};
+use minifb::{Window, WindowOptions, Key, KeyRepeat};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-// Constants for timing
-const TARGET_FRAME_TIME_MICROS: u64 = 16666; // ~60 FPS (1/60 second in microseconds)
-const CYCLES_PER_FRAME: u128 = 69905; // Game Boy runs at ~4.194 MHz, at 60 FPS that's about 69905 cycles per frame
+const TITLE: &str = "Game Boy Emulator";
 
-pub fn run_gui(mut cpu: CPU) {
+// F1-F10 load save state slots 1-10, with Shift they save
+const SLOT_KEYS: [Key; 10] = [
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5,
+    Key::F6, Key::F7, Key::F8, Key::F9, Key::F10,
+];
+
+// Game Boy buttons, the keys that press them and the button's field in Input.
+// The controls help is printed from this table too.
+type Button = (&'static str, [Key; 2], fn(&mut Input) -> &mut bool);
+
+const BUTTONS: [Button; 8] = [
+    ("D-Pad Up", [Key::Up, Key::W], |input| &mut input.up),
+    ("D-Pad Down", [Key::Down, Key::S], |input| &mut input.down),
+    ("D-Pad Left", [Key::Left, Key::A], |input| &mut input.left),
+    ("D-Pad Right", [Key::Right, Key::D], |input| &mut input.right),
+    ("A Button", [Key::Z, Key::J], |input| &mut input.a),
+    ("B Button", [Key::X, Key::K], |input| &mut input.b),
+    ("Start", [Key::Enter, Key::I], |input| &mut input.start),
+    ("Select", [Key::Backspace, Key::U], |input| &mut input.select),
+];
+
+fn input_from_keys(keys: &[Key]) -> Input {
+    let mut input = Input::default();
+    for (_, bound, pressed) in BUTTONS {
+        *pressed(&mut input) = bound.iter().any(|key| keys.contains(key));
+    }
+    input
+}
+
+pub struct GuiOptions {
+    // Save state slots are stored next to the ROM
+    pub rom_path: PathBuf,
+    // None disables rewinding
+    pub rewind: Option<RewindConfig>,
+    // Speed while Tab is held
+    pub fast_forward: Speed,
+    // Speed while slow motion is toggled on
+    pub slow_motion: Speed,
+    // Movie to play back before handing over to the keyboard
+    pub player: Option<Player>,
+    // Movie being recorded and where it's saved on exit
+    pub recorder: Option<(Recorder, PathBuf)>,
+    // Frames to run ahead, 0 for off
+    pub run_ahead: u32,
+}
+
+// Save states live next to the ROM: game.gb -> game.ss1 ... game.ss10
+fn slot_path(rom_path: &Path, slot: usize) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+fn save_slot(gameboy: &GameBoy, path: &Path) {
+    match fs::write(path, gameboy.save_state()) {
+        Ok(()) => println!("State saved to {}", path.display()),
+        Err(e) => eprintln!("Failed to save state to {}: {}", path.display(), e),
+    }
+}
+
+fn load_slot(gameboy: &mut GameBoy, path: &Path) -> bool {
+    match fs::read(path).and_then(|data| gameboy.load_state(&data)) {
+        Ok(()) => {
+            println!("State loaded from {}", path.display());
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to load state from {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn save_movie(recorder: Recorder, path: &Path) {
+    let movie = recorder.into_movie();
+    match movie.save(path) {
+        Ok(()) => println!(
+            "Movie saved to {} ({} frames, {} re-records)",
+            path.display(),
+            movie.frames.len(),
+            movie.rerecords
+        ),
+        Err(e) => eprintln!("Failed to save movie to {}: {}", path.display(), e),
+    }
+}
+
+// Run one real frame: the movie's input while one is playing, the keyboard
+// otherwise
+fn run_real_frame(
+    gameboy: &mut GameBoy,
+    input: Input,
+    player: &mut Option<Player>,
+    recorder: &mut Option<(Recorder, PathBuf)>,
+) {
+    if let Some(movie) = player {
+        match movie.run_frame(gameboy) {
+            Some(Ok(())) => return,
+            Some(Err(desync)) => {
+                eprintln!("Movie {}", desync);
+                *player = None;
+                return;
+            }
+            None => {
+                println!("Movie finished, the keyboard has control");
+                *player = None;
+            }
+        }
+    }
+    match recorder {
+        Some((movie, _)) => movie.run_frame(gameboy, input),
+        None => {
+            gameboy.set_buttons(input);
+            gameboy.run_frame();
+        }
+    }
+}
+
+// The machine jumped to another state (slot load or rewind). Playback can't
+// go on from there. A recording goes back to the loaded frame, or stops if
+// the state isn't one it passed through.
+fn state_changed(gameboy: &GameBoy, player: &mut Option<Player>, recorder: &mut Option<(Recorder, PathBuf)>) {
+    if player.take().is_some() {
+        println!("Movie playback stopped");
+    }
+    if let Some((movie, _)) = recorder
+        && !movie.resync(gameboy)
+        && let Some((movie, path)) = recorder.take()
+    {
+        eprintln!("The loaded state isn't part of the recording, so recording stops here");
+        save_movie(movie, &path);
+    }
+}
+
+pub fn run_gui(mut gameboy: GameBoy, options: GuiOptions) {
+    let rom_path = options.rom_path.as_path();
     println!("\nEmulator started!");
     println!("Controls:");
-    println!("  Arrow Keys / WASD - D-Pad");
-    println!("  Z / J - A Button");
-    println!("  X / K - B Button");
-    println!("  Enter / I - Start");
-    println!("  Backspace / U - Select");
+    for (name, [key, alternative], _) in BUTTONS {
+        println!("  {:?} / {:?} - {}", key, alternative, name);
+    }
+    println!("  F1-F10 - Load state slot 1-10");
+    println!("  Shift + F1-F10 - Save state slot 1-10");
+    if options.rewind.is_some() {
+        println!("  R (hold) - Rewind");
+    }
+    println!("  Tab (hold) - Fast-forward ({})", options.fast_forward);
+    println!("  M - Slow motion ({}) on/off", options.slow_motion);
+    println!("  P - Pause");
+    println!("  N - Advance one frame (pauses)");
+    if options.run_ahead > 0 {
+        println!("  Running {} frame(s) ahead", options.run_ahead);
+    }
     println!("  ESC - Quit\n");
     
-    // Create GPU
-    let mut gpu = GPU::new();
-    
-    // Create input handler
-    let mut input = Input::new();
+    // The Super Game Boy shows the screen inside a 256x224 border
+    let (width, height) = gameboy.screen_size();
     
     // Create window
     let mut window = Window::new(
-        "Game Boy Emulator",
-        SCREEN_WIDTH,
-        SCREEN_HEIGHT,
+        TITLE,
+        width,
+        height,
         WindowOptions {
             resize: false,
             scale: minifb::Scale::X4,
@@ -98,39 +239,163 @@ pub fn run_gui(mut cpu: CPU) {
         std::process::exit(1);
     });
     
-    // Limit to 60 FPS (approximately Game Boy refresh rate)
-    window.set_target_fps(60);
+    // Frame pacing is done by FramePacer at the real DMG refresh rate (~59.73 Hz)
+    window.set_target_fps(0);
+    
+    let mut pacer = FramePacer::new();
+    
+    let mut rewind = options.rewind.map(Rewind::new);
     
-    let mut last_frame_time = Instant::now();
-    let target_frame_time = Duration::from_micros(TARGET_FRAME_TIME_MICROS);
+    let mut paused = false;
+    let mut slow_motion = false;
+    let mut meter = SpeedMeter::new();
+    let mut title = String::from(TITLE);
+    let mut last_present = Instant::now();
+    
+    let mut player = options.player;
+    let mut recorder = options.recorder;
+    let mut run_ahead = (options.run_ahead > 0).then(|| RunAhead::new(options.run_ahead));
     
     // Main emulation loop
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        // Update input state
-        let keys = window.get_keys();
-        input.update_from_keys(&keys);
+        // Save states
+        let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        for (i, &key) in SLOT_KEYS.iter().enumerate() {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                let path = slot_path(rom_path, i + 1);
+                if shift {
+                    save_slot(&gameboy, &path);
+                } else if load_slot(&mut gameboy, &path) {
+                    state_changed(&gameboy, &mut player, &mut recorder);
+                    if let Some(ahead) = &mut run_ahead {
+                        ahead.invalidate();
+                    }
+                }
+            }
+        }
         
-        // Run CPU for one frame's worth of cycles
-        let start_cycles = cpu.get_ticks();
+        // Speed controls
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            paused = !paused;
+        }
+        if window.is_key_pressed(Key::M, KeyRepeat::No) {
+            slow_motion = !slow_motion;
+        }
+        // Holding N keeps advancing at the key repeat rate
+        let advance = window.is_key_pressed(Key::N, KeyRepeat::Yes);
+        if advance {
+            paused = true;
+        }
         
-        while cpu.get_ticks() - start_cycles < CYCLES_PER_FRAME {
-            let cycles = cpu.step();
-            gpu.step(cycles, cpu.get_memory());
+        let speed = if paused {
+            Speed::NORMAL
+        } else if window.is_key_down(Key::Tab) {
+            options.fast_forward
+        } else if slow_motion {
+            options.slow_motion
+        } else {
+            Speed::NORMAL
+        };
+        if speed != pacer.speed() {
+            pacer.set_speed(speed);
+            meter.reset();
         }
         
-        // Update window with framebuffer
-        window
-            .update_with_buffer(&gpu.framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT)
-            .unwrap();
+        // Holding R steps back one snapshot per frame instead of emulating
+        let rewinding = match &mut rewind {
+            Some(rewind) if window.is_key_down(Key::R) => {
+                if rewind.rewind(&mut gameboy) {
+                    state_changed(&gameboy, &mut player, &mut recorder);
+                }
+                true
+            }
+            _ => false,
+        };
         
-        // Frame timing
-        let elapsed = last_frame_time.elapsed();
-        if elapsed < target_frame_time {
-            std::thread::sleep(target_frame_time - elapsed);
+        // Fast-forwarded frames are shown at most at the normal refresh rate
+        let fast = speed == Speed::Uncapped || matches!(speed, Speed::Scaled(multiplier) if multiplier > 1.0);
+        
+        let running = !paused || advance;
+        if !rewinding && running {
+            // Update input state
+            let input = input_from_keys(&window.get_keys());
+            match &mut run_ahead {
+                // Looking ahead is wasted on frames that aren't shown
+                Some(ahead) if ahead.is_enabled() && !fast => {
+                    let real_frame = |gameboy: &mut GameBoy| run_real_frame(gameboy, input, &mut player, &mut recorder);
+                    if !ahead.run_frame(&mut gameboy, real_frame) {
+                        eprintln!(
+                            "Run-ahead turned off: frames took {:.1} ms on average, more than one frame's time",
+                            ahead.average_frame_time().as_secs_f64() * 1000.0
+                        );
+                    }
+                }
+                _ => {
+                    if let Some(ahead) = &mut run_ahead {
+                        ahead.invalidate();
+                    }
+                    run_real_frame(&mut gameboy, input, &mut player, &mut recorder);
+                }
+            }
+            if let Some(rewind) = &mut rewind {
+                rewind.record(&gameboy);
+            }
+        }
+        if rewinding && let Some(ahead) = &mut run_ahead {
+            ahead.invalidate();
+        }
+        if rewinding || running {
+            meter.frame();
+        } else {
+            meter.reset();
+        }
+        
+        let status = if paused {
+            "paused".to_string()
+        } else if rewinding {
+            "rewinding".to_string()
+        } else {
+            match (speed, meter.speed()) {
+                (Speed::NORMAL, _) | (_, None) => speed.to_string(),
+                (_, Some(actual)) => format!("{} ({:.2}x)", speed, actual),
+            }
+        };
+        let mut new_title = format!("{} - {}", TITLE, status);
+        if player.is_some() {
+            new_title.push_str(" - playing movie");
+        } else if recorder.is_some() {
+            new_title.push_str(" - recording");
+        }
+        if new_title != title {
+            window.set_title(&new_title);
+            title = new_title;
         }
-        last_frame_time = Instant::now();
+        
+        let picture = match &run_ahead {
+            Some(ahead) => ahead.framebuffer(&gameboy),
+            None => gameboy.framebuffer(),
+        };
+        // In between fast-forwarded frames only the input is polled
+        if fast {
+            if last_present.elapsed() >= frame_duration() {
+                window.update_with_buffer(picture, width, height).unwrap();
+                last_present = Instant::now();
+            } else {
+                window.update();
+            }
+        } else {
+            window.update_with_buffer(picture, width, height).unwrap();
+            last_present = Instant::now();
+        }
+        
+        // Frame timing
+        pacer.wait();
+    }
+    
+    if let Some((movie, path)) = recorder {
+        save_movie(movie, &path);
     }
     
     println!("\nEmulator closed.");
-    println!("Total CPU cycles: {}", cpu.get_ticks());
+    println!("Total CPU cycles: {}", gameboy.cycles());
 }
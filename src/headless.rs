@@ -0,0 +1,82 @@
+// Headless runner
+//
+// Runs the emulator without a window until one of the limits is hit, for CI
+// and scripted test ROM runs:
+// - frames: a number of frames (run_frame's worth of cycles each)
+// - until_pc: the CPU is about to execute the instruction at this address
+// - timeout_cycles: a number of system cycles has passed
+// Whichever comes first stops the run. The framebuffer is composed before
+// returning, so it can be saved straight away.
+
+use crate::gameboy::GameBoy;
+use crate::image::write_image;
+use crate::pacing::CYCLES_PER_FRAME;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunLimits {
+    pub frames: Option<u64>,
+    pub until_pc: Option<u16>,
+    pub timeout_cycles: Option<u128>,
+}
+
+impl RunLimits {
+    // Without any limit a run would never end
+    pub fn is_bounded(&self) -> bool {
+        self.frames.is_some() || self.until_pc.is_some() || self.timeout_cycles.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Frames,
+    ReachedPc,
+    Timeout,
+}
+
+// Where a run stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunResult {
+    pub reason: StopReason,
+    pub frames: u64,
+    pub cycles: u128,
+}
+
+pub fn run(gameboy: &mut GameBoy, limits: &RunLimits) -> RunResult {
+    assert!(limits.is_bounded(), "headless run needs at least one limit");
+
+    let start = gameboy.cycles();
+    let mut frames = 0;
+    let mut frame_start = start;
+    let reason = loop {
+        if limits.frames == Some(frames) {
+            break StopReason::Frames;
+        }
+        if limits.until_pc == Some(gameboy.cpu().get_pc()) {
+            break StopReason::ReachedPc;
+        }
+        if let Some(timeout) = limits.timeout_cycles
+            && gameboy.cycles() - start >= timeout
+        {
+            break StopReason::Timeout;
+        }
+
+        gameboy.step();
+        // Same frame boundaries as run_frame, so both produce the same pictures
+        if gameboy.cycles() - frame_start >= CYCLES_PER_FRAME as u128 {
+            frames += 1;
+            frame_start = gameboy.cycles();
+            gameboy.finish_frame();
+        }
+    };
+    gameboy.finish_frame();
+
+    RunResult { reason, frames, cycles: gameboy.cycles() - start }
+}
+
+// Save the framebuffer as a PNG or PPM file, depending on the extension
+pub fn save_screenshot(gameboy: &GameBoy, path: &Path) -> io::Result<()> {
+    let (width, height) = gameboy.screen_size();
+    write_image(path, width, height, gameboy.framebuffer())
+}
@@ -0,0 +1,220 @@
+// Minimal PNG and PPM reader and writers
+//
+// Writes 8-bit RGB images without compression (deflate "stored" blocks), so
+// no compression library is needed. Files are larger than they could be, but
+// Game Boy sized images stay small anyway. Binary PPM (P6) is there for tools
+// that would rather not parse PNG.
+//
+// Reading supports what reference screenshots are saved as: PPM, and PNGs
+// from any encoder, decoded by the png crate. Images read are untrusted
+// input, so their size is checked before anything is allocated for them.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Largest payload of a deflate stored block
+const MAX_STORED_BLOCK: usize = 65535;
+
+// Largest image read, far more than any Game Boy screenshot needs
+const MAX_PIXELS: usize = 4096 * 4096;
+
+// Encode `pixels` (0xRRGGBB, row-major) as a PNG file
+pub fn encode_png(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height, "pixel count doesn't match image size");
+
+    // Every scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        for &pixel in row {
+            raw.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), default compression, filter and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn write_png(path: &Path, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    fs::write(path, encode_png(width, height, pixels))
+}
+
+// Encode `pixels` (0xRRGGBB, row-major) as a binary PPM file
+pub fn encode_ppm(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height, "pixel count doesn't match image size");
+
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for &pixel in pixels {
+        ppm.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+    }
+    ppm
+}
+
+// Write a PNG or PPM file, picked by the extension of `path`
+pub fn write_image(path: &Path, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    let data = match extension.as_deref() {
+        Some("png") => encode_png(width, height, pixels),
+        Some("ppm") => encode_ppm(width, height, pixels),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: expected a .png or .ppm file name", path.display()),
+            ));
+        }
+    };
+    fs::write(path, data)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // An empty stream still needs one final block
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Decoded image, 0xRRGGBB pixels in row-major order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+// Read a PNG or PPM file, recognized by its contents
+pub fn read_image(path: &Path) -> io::Result<Image> {
+    let data = fs::read(path)?;
+    let image = if data.starts_with(&SIGNATURE) { decode_png(&data) } else { decode_ppm(&data) };
+    image.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn decode_ppm(data: &[u8]) -> io::Result<Image> {
+    // Header: P6, width, height and maximum value separated by whitespace,
+    // with # comments, then a single whitespace byte before the pixels
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        match data.get(pos) {
+            None => return Err(invalid("truncated PPM header")),
+            Some(b'#') => {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            }
+            Some(byte) if byte.is_ascii_whitespace() => pos += 1,
+            Some(_) => {
+                let start = pos;
+                while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                fields.push(&data[start..pos]);
+            }
+        }
+    }
+    pos += 1;
+
+    if fields[0] != b"P6" {
+        return Err(invalid("not a binary PPM (P6) file"));
+    }
+    let number = |field: &[u8]| -> io::Result<usize> {
+        std::str::from_utf8(field).ok().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("bad PPM header"))
+    };
+    let (width, height) = (number(fields[1])?, number(fields[2])?);
+    if number(fields[3])? != 255 {
+        return Err(invalid("only 8-bit PPM files are supported"));
+    }
+    let size = checked_size(width, height)? * 3;
+    let rgb = data.get(pos..pos + size).ok_or_else(|| invalid("truncated PPM data"))?;
+    let pixels = rgb.chunks(3).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32).collect();
+    Ok(Image { width, height, pixels })
+}
+
+pub fn decode_png(data: &[u8]) -> io::Result<Image> {
+    let mut decoder = png::Decoder::new(io::Cursor::new(data));
+    // Palette, 16-bit and low bit depth images all come out as 8-bit samples
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+    checked_size(width, height)?;
+
+    let size = reader.output_buffer_size().ok_or_else(|| invalid("PNG is too large"))?;
+    let mut buffer = vec![0; size];
+    let info = reader.next_frame(&mut buffer)?;
+    let channels = info.color_type.samples();
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in buffer.chunks(info.line_size).take(height) {
+        for p in row[..width * channels].chunks(channels) {
+            // Gray, with or without alpha, or RGB(A)
+            let (r, g, b) = if channels < 3 { (p[0], p[0], p[0]) } else { (p[0], p[1], p[2]) };
+            pixels.push((r as u32) << 16 | (g as u32) << 8 | b as u32);
+        }
+    }
+    Ok(Image { width, height, pixels })
+}
+
+// Number of pixels in an image of this size, if it isn't too large to read
+fn checked_size(width: usize, height: usize) -> io::Result<usize> {
+    width
+        .checked_mul(height)
+        .filter(|&pixels| pixels <= MAX_PIXELS)
+        .ok_or_else(|| invalid("image is too large"))
+}
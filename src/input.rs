@@ -9,10 +9,7 @@
 // Bit 1 - P11 (Left or B)
 // Bit 0 - P10 (Right or A)
 
-#[cfg(feature = "gui")]
-use minifb::Key;
-
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Input {
     // Direction keys
     pub right: bool,
@@ -31,17 +28,27 @@ impl Input {
         Self::default()
     }
 
-    #[cfg(feature = "gui")]
-    pub fn update_from_keys(&mut self, keys: &[Key]) {
-        self.right = keys.contains(&Key::Right) || keys.contains(&Key::D);
-        self.left = keys.contains(&Key::Left) || keys.contains(&Key::A);
-        self.up = keys.contains(&Key::Up) || keys.contains(&Key::W);
-        self.down = keys.contains(&Key::Down) || keys.contains(&Key::S);
-        
-        self.a = keys.contains(&Key::Z) || keys.contains(&Key::J);
-        self.b = keys.contains(&Key::X) || keys.contains(&Key::K);
-        self.select = keys.contains(&Key::Backspace) || keys.contains(&Key::U);
-        self.start = keys.contains(&Key::Enter) || keys.contains(&Key::I);
+    // Packed into a byte for movies: Right, Left, Up, Down, A, B, Select,
+    // Start from bit 0 up
+    pub fn to_bits(&self) -> u8 {
+        [self.right, self.left, self.up, self.down, self.a, self.b, self.select, self.start]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &pressed)| bits | (pressed as u8) << i)
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        let pressed = |i: u8| bits & (1 << i) != 0;
+        Self {
+            right: pressed(0),
+            left: pressed(1),
+            up: pressed(2),
+            down: pressed(3),
+            a: pressed(4),
+            b: pressed(5),
+            select: pressed(6),
+            start: pressed(7),
+        }
     }
 
     pub fn get_joypad_state(&self, joypad_register: u8) -> u8 {
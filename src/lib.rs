@@ -0,0 +1,43 @@
+// Game Boy emulator library
+//
+// The emulator core, usable from other tools. Frontends normally only need
+// GameBoy; the modules are public for tools that need to look deeper
+// (debuggers, test harnesses, link cable partners).
+
+pub mod bess;
+pub mod bgb;
+pub mod bus;
+pub mod cartridge;
+mod clock;
+pub mod colorization;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod dmg07;
+pub mod env;
+mod flags;
+pub mod gameboy;
+pub mod golden;
+pub mod gpu;
+pub mod headless;
+pub mod image;
+pub mod input;
+pub mod link;
+pub mod memory;
+pub mod movie;
+mod opcodes;
+pub mod pacing;
+pub mod printer;
+pub mod rewind;
+pub mod runahead;
+pub mod savestate;
+pub mod serial;
+pub mod sgb;
+#[cfg(test)]
+mod test_roms;
+#[cfg(test)]
+mod tests;
+
+pub use gameboy::GameBoy;
+pub use input::Input;
+pub use memory::Model;
@@ -0,0 +1,329 @@
+// Link cable between two emulator instances over TCP
+//
+// Either side can be the clock master; which one is decided by the game
+// through SC bit 0, just like on real hardware. Every message carries the
+// sender's CPU cycle count, and the two instances run in lockstep on it:
+// - Each side reports its count every SYNC_INTERVAL cycles, and a side that
+//   gets more than MAX_LEAD cycles ahead of the last count it heard holds
+//   until the partner catches up.
+// - The master finishes shifting its 8 bits (8 x 512 cycles of its own clock)
+//   and sends a TRANSFER with its byte, a sequence number and the cycle T it
+//   finished at, then waits for the answer.
+// - The slave takes the transfer once its own clock has reached T. With a
+//   transfer pending on the external clock it answers with a REPLY carrying
+//   its byte; otherwise it answers NOT_READY and the master receives 0xFF.
+// So a transfer completes on both sides at the same emulated time, give or
+// take how far the slave had already run ahead, and games that count cycles
+// between bytes (Tetris 2-player, Pokemon trading) see consistent timing.
+// The master normally only waits for the network round trip, not for a
+// timeout.
+//
+// If both games act as master at once, each answers the other's request
+// instead of waiting, so both transfers complete with the partner's byte.
+//
+// A partner that goes quiet (paused, window being dragged, stopped in the
+// debugger) isn't a missing one, but this side doesn't wait for it longer
+// than PARTNER_TIMEOUT either, so its own frontend stays responsive. After
+// that the link stops its clock and lets the emulator run on: a transfer
+// started meanwhile receives 0xFF straight away, and one that was waiting for
+// an answer is cancelled. As soon as the partner speaks again both sides pick
+// up the lockstep where it stopped. Only a partner that closes the connection
+// unplugs the cable: every transfer receives 0xFF from then on.
+//
+// Messages are 11 bytes: kind, data, sequence number, cycle count (64-bit
+// little-endian).
+
+use crate::pacing::CYCLES_PER_FRAME;
+use crate::serial::SerialDevice;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRANSFER: u8 = 0x01;
+const REPLY: u8 = 0x02;
+const NOT_READY: u8 = 0x03;
+const SYNC: u8 = 0x04;
+const CANCEL: u8 = 0x05;
+
+// How far one side may run ahead of the other: a frame
+const MAX_LEAD: u64 = CYCLES_PER_FRAME;
+const SYNC_INTERVAL: u64 = MAX_LEAD / 4;
+
+// How long to wait for the partner before treating it as quiet
+const PARTNER_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Message {
+    kind: u8,
+    data: u8,
+    seq: u8,
+    cycles: u64,
+}
+
+impl Message {
+    fn to_bytes(self) -> [u8; 11] {
+        let mut bytes = [0; 11];
+        bytes[..3].copy_from_slice(&[self.kind, self.data, self.seq]);
+        bytes[3..].copy_from_slice(&self.cycles.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 11]) -> Self {
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&bytes[3..]);
+        Self { kind: bytes[0], data: bytes[1], seq: bytes[2], cycles: u64::from_le_bytes(cycles) }
+    }
+}
+
+pub struct TcpLink {
+    stream: TcpStream,
+    incoming: Receiver<Message>,
+    seq: u8,
+    // CPU cycles run on this side, the last count sent and the partner's latest count
+    cycles: u64,
+    sent_cycles: u64,
+    peer_cycles: u64,
+    sent_at: Instant,
+    // The partner's transfer, taken once our clock reaches it. `offered`: it was
+    // already due on the previous step and nothing took it.
+    incoming_transfer: Option<Message>,
+    offered: bool,
+    // Our own byte is being shifted out on the internal clock
+    mastering: bool,
+    // Nothing heard from the partner for PARTNER_TIMEOUT while waiting for it.
+    // Our clock stands still until it speaks again.
+    quiet: bool,
+    connected: bool,
+}
+
+impl TcpLink {
+    // Wait for a partner to connect on `addr`
+    pub fn host(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::accept(&TcpListener::bind(addr)?)
+    }
+
+    // Wait for a partner on a listener that is already bound
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        Self::from_stream(stream)
+    }
+
+    // Connect to a partner waiting with `host`
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        // Small messages must not wait for Nagle's algorithm
+        stream.set_nodelay(true)?;
+
+        // Reading happens on a thread so the emulator can poll without blocking
+        let (sender, incoming) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut buf = [0; 11];
+            while reader.read_exact(&mut buf).is_ok() {
+                if sender.send(Message::from_bytes(buf)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            stream,
+            incoming,
+            seq: 0,
+            cycles: 0,
+            sent_cycles: 0,
+            peer_cycles: 0,
+            sent_at: Instant::now(),
+            incoming_transfer: None,
+            offered: false,
+            mastering: false,
+            quiet: false,
+            connected: true,
+        })
+    }
+
+    fn send(&mut self, kind: u8, data: u8, seq: u8) {
+        let message = Message { kind, data, seq, cycles: self.cycles };
+        if self.stream.write_all(&message.to_bytes()).is_err() {
+            self.unplug();
+        }
+        self.sent_cycles = self.cycles;
+        self.sent_at = Instant::now();
+    }
+
+    // The partner is gone: close our end too
+    fn unplug(&mut self) {
+        self.connected = false;
+        self.incoming_transfer = None;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    // Next message from the partner, waiting up to PARTNER_TIMEOUT for one if
+    // `wait`. Every message updates the partner's cycle count; transfers are
+    // kept until our clock reaches them.
+    fn receive(&mut self, wait: bool) -> Option<Message> {
+        let message = if wait {
+            match self.incoming.recv_timeout(PARTNER_TIMEOUT) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.quiet = true;
+                    return None;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.unplug();
+                    return None;
+                }
+            }
+        } else {
+            match self.incoming.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    self.unplug();
+                    return None;
+                }
+            }
+        };
+
+        self.quiet = false;
+        self.peer_cycles = self.peer_cycles.max(message.cycles);
+        match message.kind {
+            TRANSFER => {
+                self.incoming_transfer = Some(message);
+                self.offered = false;
+            }
+            CANCEL if self.incoming_transfer.is_some_and(|transfer| transfer.seq == message.seq) => {
+                self.incoming_transfer = None;
+            }
+            _ => {}
+        }
+        Some(message)
+    }
+
+    // The partner's transfer, once our clock has reached the cycle it was sent at
+    fn due_transfer(&self) -> Option<Message> {
+        self.incoming_transfer.filter(|transfer| transfer.cycles <= self.cycles)
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        // The reader thread holds a clone of the socket: shut it down explicitly
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer_master(&mut self, data: u8) -> u8 {
+        self.mastering = false;
+        while self.receive(false).is_some() {}
+        if !self.connected || self.quiet {
+            return 0xFF;
+        }
+
+        // Both sides are master and the partner's request is already here:
+        // answer it with our byte
+        if let Some(theirs) = self.incoming_transfer.take() {
+            self.send(REPLY, data, theirs.seq);
+            return theirs.data;
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        self.send(TRANSFER, data, self.seq);
+        loop {
+            let Some(message) = self.receive(true) else {
+                // Gave up on the partner: make sure it doesn't take the
+                // transfer when it comes back
+                if self.quiet {
+                    self.send(CANCEL, 0, self.seq);
+                }
+                return 0xFF;
+            };
+            match message.kind {
+                REPLY if message.seq == self.seq => return message.data,
+                NOT_READY if message.seq == self.seq => return 0xFF,
+                TRANSFER => {
+                    self.incoming_transfer = None;
+                    self.send(REPLY, data, message.seq);
+                    return message.data;
+                }
+                // Sync updates, and replies to transfers we answered
+                // ourselves as the other master
+                _ => {}
+            }
+        }
+    }
+
+    fn start_master(&mut self, _data: u8, _fast: bool) {
+        self.mastering = true;
+    }
+
+    fn poll_slave(&mut self, data: u8) -> Option<u8> {
+        let theirs = self.due_transfer()?;
+        self.incoming_transfer = None;
+        self.send(REPLY, data, theirs.seq);
+        Some(theirs.data)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.connected {
+            return;
+        }
+        while self.receive(false).is_some() {}
+        if self.quiet {
+            // Let the partner know we're still here, in case it went quiet
+            // waiting for us as well
+            if self.sent_at.elapsed() >= PARTNER_TIMEOUT {
+                self.send(SYNC, 0, 0);
+            }
+            return;
+        }
+        self.cycles += cycles as u64;
+
+        // The partner clocked a transfer and a whole step passed without this
+        // side taking it: nothing is pending on the external clock. A master
+        // keeps it and answers it when its own transfer finishes.
+        if let Some(transfer) = self.due_transfer()
+            && !self.mastering
+        {
+            if self.offered {
+                self.incoming_transfer = None;
+                self.send(NOT_READY, 0xFF, transfer.seq);
+            } else {
+                self.offered = true;
+            }
+        }
+
+        if self.cycles >= self.sent_cycles + SYNC_INTERVAL {
+            self.send(SYNC, 0, 0);
+        }
+
+        // Too far ahead: report where we are, so the partner can't be holding
+        // for us as well, and wait for it. A transfer from the partner ends the
+        // wait: its clock stands still until we answer.
+        if self.cycles > self.peer_cycles + MAX_LEAD {
+            self.send(SYNC, 0, 0);
+            while self.connected
+                && !self.quiet
+                && self.incoming_transfer.is_none()
+                && self.cycles > self.peer_cycles + MAX_LEAD
+            {
+                self.receive(true);
+            }
+        }
+    }
+}
+
+// Address for a link option: a bare port means localhost
+pub fn link_address(value: &str) -> String {
+    if value.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", value)
+    } else {
+        value.to_string()
+    }
+}
@@ -1,75 +1,64 @@
-mod clock;
-mod cpu;
-mod flags;
-mod gpu;
 #[cfg(feature = "gui")]
 mod gui;
-mod input;
-mod memory;
-mod opcodes;
-mod tests;
+mod modes;
+mod options;
+mod setup;
 
-use cpu::CPU;
+use options::usage_error;
 use std::env;
-use std::fs;
-use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "disasm") {
+        modes::run_disasm(options::parse_disasm_args(&args[1..]).unwrap_or_else(|e| usage_error(e)));
+    }
+
     println!("Game Boy Emulator");
     println!("==================\n");
     
     // Parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    let rom_path = if args.len() > 1 {
-        args[1].clone()
-    } else {
-        // Default to boot ROM if no argument provided
-        "dmg_boot.bin".to_string()
-    };
-    
-    // Create a CPU instance
-    let mut cpu = CPU::new();
+    let options = options::parse_args(&args).unwrap_or_else(|e| usage_error(e));
+    if let Some(manifest) = &options.golden {
+        modes::run_golden(manifest, &options.golden_options);
+    }
     
-    // Load ROM file
-    if Path::new(&rom_path).exists() {
-        match fs::read(&rom_path) {
-            Ok(rom_data) => {
-                println!("Loading ROM from {}...", rom_path);
-                
-                // Check if this is a boot ROM (256 bytes) or a game ROM
-                if rom_data.len() == 256 {
-                    cpu.load_boot_rom(&rom_data);
-                    println!("Boot ROM loaded successfully ({} bytes)", rom_data.len());
-                } else {
-                    cpu.load_rom(rom_data.clone());
-                    println!("Game ROM loaded successfully ({} bytes)", rom_data.len());
-                }
-            }
-            Err(e) => {
-                eprintln!("Error reading ROM file {}: {}", rom_path, e);
-                eprintln!("Usage: cargo run -- <path_to_rom.gb>");
-                std::process::exit(1);
-            }
+    let movie = setup::load_movie(&options);
+    let mut gameboy = setup::build_gameboy(&options, movie.as_ref());
+    setup::connect_serial(&mut gameboy, &options);
+    setup::load_start_state(&mut gameboy, &options);
+
+    if options.debug {
+        modes::run_debugger(gameboy);
+    }
+
+    #[cfg_attr(not(feature = "gui"), allow(unused_variables))]
+    let (player, recorder) = setup::start_movies(&mut gameboy, &options, movie);
+
+    if options.headless {
+        match player {
+            Some(player) => modes::run_movie(gameboy, player, &options),
+            None => modes::run_headless(gameboy, &options),
         }
-    } else {
-        eprintln!("ROM file not found: {}", rom_path);
-        eprintln!("Usage: cargo run -- <path_to_rom.gb>");
-        std::process::exit(1);
     }
-    
+
     // Run with GUI if feature is enabled
     #[cfg(feature = "gui")]
     {
-        gui::run_gui(cpu);
+        let rewind = (options.rewind.budget > 0).then_some(options.rewind);
+        gui::run_gui(gameboy, gui::GuiOptions {
+            rom_path: PathBuf::from(&options.rom_path),
+            rewind,
+            fast_forward: options.fast_forward,
+            slow_motion: options.slow_motion,
+            player,
+            recorder,
+            run_ahead: options.run_ahead,
+        });
     }
     
     // Run without GUI (for WASM or headless builds)
     #[cfg(not(feature = "gui"))]
-    {
-        println!("\nGUI feature is disabled. Build completed successfully!");
-        println!("To enable GUI, build with: cargo build --features gui");
-        println!("Or use default features: cargo build");
-        println!("\nThis headless build is suitable for WASM or other non-GUI environments.");
-        println!("Total CPU cycles initialized: {}", cpu.get_ticks());
-    }
+    modes::run_without_window(&gameboy);
 }
@@ -16,31 +16,216 @@
 // 0xFF00-0xFF7F: I/O Registers
 // 0xFF80-0xFFFE: High RAM (HRAM)
 // 0xFFFF: Interrupt Enable Register
+//
+// Game Boy Color mode (enabled when the cartridge header at 0x0143 has bit 7 set
+// and the hardware model is CGB):
+// 0x8000-0x9FFF: VRAM bank 0-1, selected by VBK (0xFF4F)
+// 0xC000-0xCFFF: WRAM bank 0 (fixed)
+// 0xD000-0xDFFF: WRAM bank 1-7, selected by SVBK (0xFF70), 0 maps to 1
+// 0xFF4D: KEY1 - bit 7 current speed, bit 0 arms a speed switch on STOP
+// 0xFF68-0xFF69: BCPS/BCPD - BG palette RAM index (bit 7 auto-increment) and data
+// 0xFF6A-0xFF6B: OCPS/OCPD - OBJ palette RAM index (bit 7 auto-increment) and data
+// 0xFF51-0xFF55: HDMA1-5 - VRAM DMA source, destination and length/mode
+//
+// Peripherals:
+// 0xFF01-0xFF02: SB/SC - serial port, see serial.rs
+
+use crate::bess::MemoryImage;
+use crate::bus::Bus;
+use crate::cartridge::CartridgeHeader;
+use crate::colorization::{rgb888_to_rgb555, CompatPalette};
+use crate::input::Input;
+use crate::savestate::{StateReader, StateWriter};
+use crate::serial::{Serial, SerialDevice, SERIAL_INTERRUPT};
+use crate::sgb::Sgb;
+use std::io;
+use std::sync::Arc;
+
+const JOYP: u16 = 0xFF00;
+const SB: u16 = 0xFF01;
+const SC: u16 = 0xFF02;
+const IF: u16 = 0xFF0F;
+const VBK: u16 = 0xFF4F;
+const SVBK: u16 = 0xFF70;
+const KEY1: u16 = 0xFF4D;
+const BCPS: u16 = 0xFF68;
+const BCPD: u16 = 0xFF69;
+const OCPS: u16 = 0xFF6A;
+const OCPD: u16 = 0xFF6B;
+const HDMA1: u16 = 0xFF51;
+const HDMA2: u16 = 0xFF52;
+const HDMA3: u16 = 0xFF53;
+const HDMA4: u16 = 0xFF54;
+const HDMA5: u16 = 0xFF55;
+
+// CPU cycles a 16-byte VRAM DMA block takes in normal speed (8 M-cycles)
+const DMA_BLOCK_CYCLES: u32 = 32;
+
+// 8 palettes x 4 colors x 2 bytes (little-endian RGB555)
+const PALETTE_RAM_SIZE: usize = 64;
+
+// Emulated hardware model. The values are stored in save states.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg = 0,
+    Cgb = 1,
+    Sgb = 2,
+}
+
+// CGB VRAM DMA (HDMA). General-purpose DMA copies everything at once,
+// HBlank DMA copies one 16-byte block at the start of every HBlank.
+#[derive(Clone)]
+struct VramDma {
+    source: u16,
+    destination: u16,
+    // 16-byte blocks left to copy
+    remaining: u8,
+    hblank_active: bool,
+}
+
+impl VramDma {
+    fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining: 0,
+            hblank_active: false,
+        }
+    }
+
+    fn read_hdma5(&self) -> u8 {
+        // Bit 7 reads 0 while an HBlank transfer is running; 0xFF once finished
+        let length = self.remaining.wrapping_sub(1) & 0x7F;
+        if self.hblank_active { length } else { 0x80 | length }
+    }
+}
+
+// CGB palette RAM with its index/auto-increment register
+#[derive(Clone)]
+struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    fn new() -> Self {
+        Self {
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
 
+    fn read_spec(&self) -> u8 {
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    fn write_spec(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    fn set_color(&mut self, palette: u8, color: u8, value: u16) {
+        let offset = (palette as usize & 0x07) * 8 + (color as usize & 0x03) * 2;
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = (palette as usize & 0x07) * 8 + (color as usize & 0x03) * 2;
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) & 0x7FFF
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.data);
+        w.u8(self.index);
+        w.bool(self.auto_increment);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.bytes(&mut self.data)?;
+        self.index = r.u8()? & 0x3F;
+        self.auto_increment = r.bool()?;
+        Ok(())
+    }
+}
+
+// Clones share the cartridge ROM, which is never written
+#[derive(Clone)]
 pub struct Memory {
     boot_rom: [u8; 256],
-    rom: Vec<u8>,
-    vram: [u8; 8192],
-    wram: [u8; 8192],
+    rom: Arc<[u8]>,
+    vram: [[u8; 8192]; 2],
+    wram: [[u8; 4096]; 8],
     oam: [u8; 160],
     hram: [u8; 127],
     io: [u8; 128],
     boot_rom_enabled: bool,
     ie_register: u8, // Interrupt Enable at 0xFFFF
+    joypad: Input,
+    sgb: Option<Sgb>,
+    serial: Serial,
+    
+    // CGB state
+    // Model forced by set_model. None picks it from the cartridge header
+    // every time a ROM is loaded.
+    forced_model: Option<Model>,
+    model: Model,
+    cgb_mode: bool,
+    vram_bank: usize,
+    wram_bank: usize,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    vram_dma: VramDma,
+    // CPU cycles the CPU must stall for DMA transfers since the last take_dma_stall
+    dma_stall: u32,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
         Self {
             boot_rom: [0; 256],
-            rom: vec![0; 32768], // Minimum 32KB ROM
-            vram: [0; 8192],
-            wram: [0; 8192],
+            rom: vec![0; 32768].into(), // Minimum 32KB ROM
+            vram: [[0; 8192]; 2],
+            wram: [[0; 4096]; 8],
             oam: [0; 160],
             hram: [0; 127],
-            io: [0; 128],
+            io: Self::initial_io(),
             boot_rom_enabled: true,
             ie_register: 0,
+            joypad: Input::new(),
+            sgb: None,
+            serial: Serial::new(),
+            forced_model: None,
+            model: Model::Dmg,
+            cgb_mode: false,
+            vram_bank: 0,
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            vram_dma: VramDma::new(),
+            dma_stall: 0,
         }
     }
 
@@ -48,8 +233,268 @@ impl Memory {
         self.boot_rom.copy_from_slice(&data[0..256.min(data.len())]);
     }
 
+    // I/O register values the boot ROM leaves behind
+    fn initial_io() -> [u8; 128] {
+        let mut io = [0; 128];
+        io[0x00] = 0x30; // JOYP: no line selected
+        io[0x47] = 0xFC; // BGP
+        io
+    }
+
+    // Force a hardware model instead of picking it from the cartridge header.
+    // Must be called before load_rom.
+    pub fn set_model(&mut self, model: Model) {
+        self.forced_model = Some(model);
+        self.model = model;
+    }
+
     pub fn load_rom(&mut self, data: Vec<u8>) {
-        self.rom = data;
+        let cgb_rom = CartridgeHeader::parse(&data)
+            .is_some_and(|header| header.cgb_flag & 0x80 != 0);
+        self.model = self.forced_model
+            .unwrap_or(if cgb_rom { Model::Cgb } else { Model::Dmg });
+        self.cgb_mode = self.model == Model::Cgb && cgb_rom;
+        self.sgb = (self.model == Model::Sgb).then(Sgb::new);
+        self.rom = data.into();
+    }
+
+    // ROM data of the inserted cartridge
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // Work RAM banks back to back, for frontends that map memory directly.
+    // Outside CGB mode only the first two banks are used.
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        self.wram.as_flattened_mut()
+    }
+
+    pub fn hram_mut(&mut self) -> &mut [u8] {
+        &mut self.hram
+    }
+
+    // Plug a link partner into the serial port
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect(device);
+    }
+
+    // Advance peripherals by `cycles` CPU cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if self.serial.tick(cycles) {
+            self.request_interrupt(SERIAL_INTERRUPT);
+        }
+    }
+
+    pub fn request_interrupt(&mut self, mask: u8) {
+        self.io[(IF - 0xFF00) as usize] |= mask;
+    }
+
+    // Button state seen by the game through the joypad register
+    pub fn set_joypad(&mut self, input: Input) {
+        self.joypad = input;
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_mut()
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // CGB features (banking, palette RAM, HDMA) are enabled
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // A DMG-only game running on CGB hardware: BGP/OBP0/OBP1 index into
+    // palettes set up by the boot ROM's colorization
+    pub fn is_dmg_compatibility(&self) -> bool {
+        self.model() == Model::Cgb && !self.cgb_mode
+    }
+
+    // Load the colorization palettes the CGB boot ROM sets up for DMG games
+    pub fn load_compat_palette(&mut self, palette: &CompatPalette) {
+        for color in 0..4 {
+            self.bg_palettes.set_color(0, color, rgb888_to_rgb555(palette.bg[color as usize]));
+            self.obj_palettes.set_color(0, color, rgb888_to_rgb555(palette.obj0[color as usize]));
+            self.obj_palettes.set_color(1, color, rgb888_to_rgb555(palette.obj1[color as usize]));
+        }
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called by STOP. Toggles CPU speed if KEY1 armed a switch, returns whether it did.
+    pub fn perform_speed_switch(&mut self) -> bool {
+        if !(self.cgb_mode && self.speed_switch_armed) {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    // Direct VRAM access for the GPU, independent of the bank selected by VBK
+    pub fn read_vram(&self, bank: usize, addr: u16) -> u8 {
+        self.vram[bank][(addr - 0x8000) as usize]
+    }
+
+    // RGB555 color from CGB BG palette RAM
+    pub fn bg_palette_color(&self, palette: u8, color: u8) -> u16 {
+        self.bg_palettes.color(palette, color)
+    }
+
+    // RGB555 color from CGB OBJ palette RAM
+    pub fn obj_palette_color(&self, palette: u8, color: u8) -> u16 {
+        self.obj_palettes.color(palette, color)
+    }
+
+    // Update LY (0xFF44) and the STAT (0xFF41) mode bits from the GPU
+    pub fn set_lcd_status(&mut self, ly: u8, mode: u8) {
+        self.io[0x44] = ly;
+        self.io[0x41] = (self.io[0x41] & !0x03) | (mode & 0x03);
+    }
+
+    // Called by the GPU at the start of each visible line's HBlank
+    pub fn hblank(&mut self) {
+        if self.vram_dma.hblank_active {
+            self.copy_dma_block();
+            if self.vram_dma.remaining == 0 {
+                self.vram_dma.hblank_active = false;
+            }
+        }
+    }
+
+    // CPU cycles spent on DMA since the last call, for the CPU to stall
+    pub fn take_dma_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.dma_stall)
+    }
+
+    fn start_vram_dma(&mut self, value: u8) {
+        if self.vram_dma.hblank_active && value & 0x80 == 0 {
+            // Writing bit 7 = 0 during an HBlank transfer cancels it
+            self.vram_dma.hblank_active = false;
+            return;
+        }
+
+        self.vram_dma.remaining = (value & 0x7F) + 1;
+        if value & 0x80 != 0 {
+            self.vram_dma.hblank_active = true;
+        } else {
+            while self.vram_dma.remaining > 0 {
+                self.copy_dma_block();
+            }
+        }
+    }
+
+    fn copy_dma_block(&mut self) {
+        for _ in 0..16 {
+            let value = self.read(self.vram_dma.source);
+            let offset = (self.vram_dma.destination & 0x1FFF) as usize;
+            self.vram[self.vram_bank][offset] = value;
+            self.vram_dma.source = self.vram_dma.source.wrapping_add(1);
+            self.vram_dma.destination = (self.vram_dma.destination + 1) & 0x1FFF;
+        }
+        self.vram_dma.remaining -= 1;
+
+        // The transfer takes the same real time in both speeds
+        self.dma_stall += if self.double_speed { DMA_BLOCK_CYCLES * 2 } else { DMA_BLOCK_CYCLES };
+    }
+
+    fn wram_index(&self, addr: u16) -> (usize, usize) {
+        let offset = (addr as usize) & 0x1FFF;
+        if offset < 0x1000 {
+            (0, offset)
+        } else {
+            (self.wram_bank, offset - 0x1000)
+        }
+    }
+
+    fn read_joypad(&self) -> u8 {
+        let select = self.io[0] & 0x30;
+        if let Some(sgb) = &self.sgb {
+            // With MLT_REQ active and no line selected, the low nibble is the controller ID
+            if sgb.players() > 1 && select == 0x30 {
+                return 0xF0 | (0x0F - sgb.current_player());
+            }
+            // Only the first controller is connected
+            if sgb.current_player() != 0 {
+                return 0xC0 | select | 0x0F;
+            }
+        }
+        0xC0 | self.joypad.get_joypad_state(select)
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            JOYP => self.read_joypad(),
+            SB => self.serial.read_data(),
+            SC => self.serial.read_control(self.cgb_mode),
+            VBK if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            SVBK if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            KEY1 if self.cgb_mode => {
+                0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+            }
+            BCPS if self.cgb_mode => self.bg_palettes.read_spec(),
+            BCPD if self.cgb_mode => self.bg_palettes.read_data(),
+            OCPS if self.cgb_mode => self.obj_palettes.read_spec(),
+            OCPD if self.cgb_mode => self.obj_palettes.read_data(),
+            HDMA5 if self.cgb_mode => self.vram_dma.read_hdma5(),
+            // HDMA1-4 are write-only
+            VBK | SVBK | KEY1 | BCPS..=OCPD | HDMA1..=HDMA5 => 0xFF,
+            _ => self.io[(addr - 0xFF00) as usize],
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            JOYP => {
+                self.io[0] = value & 0x30;
+                // Packets are decoded from the select lines; the SGB gets read access to
+                // VRAM for its transfer commands
+                if let Some(mut sgb) = self.sgb.take() {
+                    sgb.write_joypad(value, self);
+                    self.sgb = Some(sgb);
+                }
+            }
+            SB => self.serial.write_data(value),
+            SC => self.serial.write_control(value, self.cgb_mode),
+            VBK if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,
+            SVBK if self.cgb_mode => self.wram_bank = ((value & 0x07) as usize).max(1),
+            KEY1 if self.cgb_mode => self.speed_switch_armed = value & 0x01 != 0,
+            BCPS if self.cgb_mode => self.bg_palettes.write_spec(value),
+            BCPD if self.cgb_mode => self.bg_palettes.write_data(value),
+            OCPS if self.cgb_mode => self.obj_palettes.write_spec(value),
+            OCPD if self.cgb_mode => self.obj_palettes.write_data(value),
+            HDMA1 if self.cgb_mode => {
+                self.vram_dma.source = (self.vram_dma.source & 0x00FF) | ((value as u16) << 8);
+            }
+            HDMA2 if self.cgb_mode => {
+                self.vram_dma.source = (self.vram_dma.source & 0xFF00) | (value & 0xF0) as u16;
+            }
+            HDMA3 if self.cgb_mode => {
+                self.vram_dma.destination =
+                    (self.vram_dma.destination & 0x00FF) | (((value & 0x1F) as u16) << 8);
+            }
+            HDMA4 if self.cgb_mode => {
+                self.vram_dma.destination =
+                    (self.vram_dma.destination & 0x1F00) | (value & 0xF0) as u16;
+            }
+            HDMA5 if self.cgb_mode => self.start_vram_dma(value),
+            _ => {
+                // Special handling for boot rom disable
+                if addr == 0xFF50 && value != 0 {
+                    self.boot_rom_enabled = false;
+                }
+                self.io[(addr - 0xFF00) as usize] = value;
+            }
+        }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
@@ -64,19 +509,20 @@ impl Memory {
             }
             0x0100..=0x7FFF => self.rom.get(addr as usize).copied().unwrap_or(0xFF),
             // VRAM
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize],
+            0x8000..=0x9FFF => self.vram[self.vram_bank][(addr - 0x8000) as usize],
             // External RAM (not implemented, returns 0xFF)
             0xA000..=0xBFFF => 0xFF,
-            // WRAM
-            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize],
-            // Echo RAM (mirror of WRAM)
-            0xE000..=0xFDFF => self.wram[(addr - 0xE000) as usize],
+            // WRAM and Echo RAM (mirror of WRAM)
+            0xC000..=0xFDFF => {
+                let (bank, offset) = self.wram_index(addr);
+                self.wram[bank][offset]
+            }
             // OAM
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             // Not usable
             0xFEA0..=0xFEFF => 0xFF,
             // I/O Registers
-            0xFF00..=0xFF7F => self.io[(addr - 0xFF00) as usize],
+            0xFF00..=0xFF7F => self.read_io(addr),
             // HRAM
             0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
             // IE Register
@@ -89,25 +535,20 @@ impl Memory {
             // ROM (read-only, but writing can trigger bank switching in real hardware)
             0x0000..=0x7FFF => {}
             // VRAM
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize] = value,
+            0x8000..=0x9FFF => self.vram[self.vram_bank][(addr - 0x8000) as usize] = value,
             // External RAM (not implemented)
             0xA000..=0xBFFF => {}
-            // WRAM
-            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize] = value,
-            // Echo RAM (mirror of WRAM)
-            0xE000..=0xFDFF => self.wram[(addr - 0xE000) as usize] = value,
+            // WRAM and Echo RAM (mirror of WRAM)
+            0xC000..=0xFDFF => {
+                let (bank, offset) = self.wram_index(addr);
+                self.wram[bank][offset] = value;
+            }
             // OAM
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = value,
             // Not usable
             0xFEA0..=0xFEFF => {}
             // I/O Registers
-            0xFF00..=0xFF7F => {
-                // Special handling for boot rom disable
-                if addr == 0xFF50 && value != 0 {
-                    self.boot_rom_enabled = false;
-                }
-                self.io[(addr - 0xFF00) as usize] = value;
-            }
+            0xFF00..=0xFF7F => self.write_io(addr, value),
             // HRAM
             0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = value,
             // IE Register
@@ -115,6 +556,170 @@ impl Memory {
         }
     }
 
+    // Write the "MEM ", "SER " and "SGB " save state sections. The ROM and
+    // boot ROM aren't saved: they come from files, not from the machine.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.section(b"MEM ", |w| {
+            for bank in &self.vram {
+                w.bytes(bank);
+            }
+            for bank in &self.wram {
+                w.bytes(bank);
+            }
+            w.bytes(&self.oam);
+            w.bytes(&self.hram);
+            w.bytes(&self.io);
+            w.bool(self.boot_rom_enabled);
+            w.u8(self.ie_register);
+            w.u8(self.model() as u8);
+            w.bool(self.cgb_mode);
+            w.u8(self.vram_bank as u8);
+            w.u8(self.wram_bank as u8);
+            w.bool(self.double_speed);
+            w.bool(self.speed_switch_armed);
+            self.bg_palettes.save_state(w);
+            self.obj_palettes.save_state(w);
+            w.u16(self.vram_dma.source);
+            w.u16(self.vram_dma.destination);
+            w.u8(self.vram_dma.remaining);
+            w.bool(self.vram_dma.hblank_active);
+            w.u32(self.dma_stall);
+        });
+        w.section(b"SER ", |w| self.serial.save_state(w));
+        if let Some(sgb) = &self.sgb {
+            w.section(b"SGB ", |w| sgb.save_state(w));
+        }
+    }
+
+    // Load one of the sections written by save_state. Returns false for
+    // sections that don't belong to memory.
+    pub(crate) fn load_section(&mut self, tag: &[u8; 4], r: &mut StateReader) -> io::Result<bool> {
+        match tag {
+            b"MEM " => self.load_memory_state(r)?,
+            b"SER " => self.serial.load_state(r)?,
+            b"SGB " => match &mut self.sgb {
+                Some(sgb) => sgb.load_state(r)?,
+                None => return Err(invalid_state("save state is from a Super Game Boy")),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn load_memory_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for bank in &mut self.vram {
+            r.bytes(bank)?;
+        }
+        for bank in &mut self.wram {
+            r.bytes(bank)?;
+        }
+        r.bytes(&mut self.oam)?;
+        r.bytes(&mut self.hram)?;
+        r.bytes(&mut self.io)?;
+        self.boot_rom_enabled = r.bool()?;
+        self.ie_register = r.u8()?;
+        // The model decides what the rest of the machine looks like (SGB,
+        // CGB palettes), so it has to match
+        if r.u8()? != self.model() as u8 {
+            return Err(invalid_state("save state is from a different hardware model"));
+        }
+        self.cgb_mode = r.bool()?;
+        self.vram_bank = (r.u8()? & 0x01) as usize;
+        self.wram_bank = ((r.u8()? & 0x07) as usize).max(1);
+        self.double_speed = r.bool()?;
+        self.speed_switch_armed = r.bool()?;
+        self.bg_palettes.load_state(r)?;
+        self.obj_palettes.load_state(r)?;
+        self.vram_dma.source = r.u16()?;
+        self.vram_dma.destination = r.u16()? & 0x1FFF;
+        self.vram_dma.remaining = r.u8()?;
+        self.vram_dma.hblank_active = r.bool()?;
+        // An HBlank transfer ends as its last block is copied, and HDMA5 can
+        // ask for at most 0x80 blocks
+        if (self.vram_dma.hblank_active && self.vram_dma.remaining == 0) || self.vram_dma.remaining > 0x80 {
+            return Err(invalid_state("save state has a bad HDMA block count"));
+        }
+        self.dma_stall = r.u32()?;
+        // The CPU runs the stall down 4 cycles per step
+        if !self.dma_stall.is_multiple_of(4) {
+            return Err(invalid_state("save state has a bad DMA stall count"));
+        }
+        Ok(())
+    }
+
+    // Memory as a BESS CORE block stores it. I/O registers hold what they
+    // read back, except write-only ones, which hold what was last written.
+    pub(crate) fn bess_image(&self) -> MemoryImage {
+        let mut io = [0; 128];
+        for (i, value) in io.iter_mut().enumerate() {
+            *value = self.read_io(0xFF00 + i as u16);
+        }
+        io[(HDMA1 - 0xFF00) as usize] = (self.vram_dma.source >> 8) as u8;
+        io[(HDMA2 - 0xFF00) as usize] = self.vram_dma.source as u8;
+        io[(HDMA3 - 0xFF00) as usize] = (self.vram_dma.destination >> 8) as u8;
+        io[(HDMA4 - 0xFF00) as usize] = self.vram_dma.destination as u8;
+        // BOOT: non-zero once the boot ROM is unmapped
+        io[0x50] = !self.boot_rom_enabled as u8;
+
+        let cgb = self.model() == Model::Cgb;
+        let (wram_banks, vram_banks) = if cgb { (8, 2) } else { (2, 1) };
+        MemoryImage {
+            io,
+            ie: self.ie_register,
+            wram: self.wram[..wram_banks].concat(),
+            vram: self.vram[..vram_banks].concat(),
+            oam: self.oam,
+            hram: self.hram,
+            bg_palettes: if cgb { self.bg_palettes.data.to_vec() } else { Vec::new() },
+            obj_palettes: if cgb { self.obj_palettes.data.to_vec() } else { Vec::new() },
+        }
+    }
+
+    // Load memory from a BESS CORE block. Buffers may be shorter than ours
+    // (a DMG state has 2 WRAM banks); the rest is left as it is.
+    pub(crate) fn restore_bess_image(&mut self, image: &MemoryImage) {
+        for (bank, data) in self.wram.iter_mut().zip(image.wram.chunks(4096)) {
+            bank[..data.len()].copy_from_slice(data);
+        }
+        for (bank, data) in self.vram.iter_mut().zip(image.vram.chunks(8192)) {
+            bank[..data.len()].copy_from_slice(data);
+        }
+        self.oam = image.oam;
+        self.hram = image.hram;
+        self.ie_register = image.ie;
+        if image.bg_palettes.len() == PALETTE_RAM_SIZE {
+            self.bg_palettes.data.copy_from_slice(&image.bg_palettes);
+        }
+        if image.obj_palettes.len() == PALETTE_RAM_SIZE {
+            self.obj_palettes.data.copy_from_slice(&image.obj_palettes);
+        }
+
+        // Registers backed by state outside the I/O array
+        let io = &image.io;
+        let reg = |addr: u16| io[(addr - 0xFF00) as usize];
+        self.io = *io;
+        self.io[0] = reg(JOYP) & 0x30;
+        self.boot_rom_enabled = reg(0xFF50) == 0;
+        self.serial.write_data(reg(SB));
+        self.serial.write_control(reg(SC), self.cgb_mode);
+        if self.cgb_mode {
+            self.vram_bank = (reg(VBK) & 0x01) as usize;
+            self.wram_bank = ((reg(SVBK) & 0x07) as usize).max(1);
+            self.double_speed = reg(KEY1) & 0x80 != 0;
+            self.speed_switch_armed = reg(KEY1) & 0x01 != 0;
+            self.bg_palettes.write_spec(reg(BCPS));
+            self.obj_palettes.write_spec(reg(OCPS));
+            self.vram_dma.source = u16::from_be_bytes([reg(HDMA1), reg(HDMA2) & 0xF0]);
+            self.vram_dma.destination = u16::from_be_bytes([reg(HDMA3) & 0x1F, reg(HDMA4) & 0xF0]);
+            // HDMA5 bit 7 reads 0 while an HBlank transfer is running
+            let hdma5 = reg(HDMA5);
+            self.vram_dma.hblank_active = hdma5 & 0x80 == 0;
+            self.vram_dma.remaining = if self.vram_dma.hblank_active { (hdma5 & 0x7F) + 1 } else { 0 };
+        }
+        self.dma_stall = 0;
+    }
+
+    #[allow(dead_code)]
     pub fn read_word(&self, addr: u16) -> u16 {
         let low = self.read(addr) as u16;
         let high = self.read(addr.wrapping_add(1)) as u16;
@@ -126,3 +731,33 @@ impl Memory {
         self.write(addr.wrapping_add(1), (value >> 8) as u8);
     }
 }
+
+impl Bus for Memory {
+    fn read(&mut self, addr: u16) -> u8 {
+        Memory::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        Memory::write(self, addr, value);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        Memory::tick(self, cycles);
+    }
+
+    fn take_stall_cycles(&mut self) -> u32 {
+        self.take_dma_stall()
+    }
+
+    fn is_double_speed(&self) -> bool {
+        Memory::is_double_speed(self)
+    }
+
+    fn speed_switch(&mut self) -> bool {
+        self.perform_speed_switch()
+    }
+}
+
+fn invalid_state(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,156 @@
+// Run modes of the emulator binary that don't open a window. Each one
+// reports on stdout and exits the process with its own status.
+
+use crate::options::{DisasmOptions, Options};
+use gb_emulator::debugger::{Debugger, Response};
+use gb_emulator::disasm;
+use gb_emulator::golden::{self, GoldenOptions};
+use gb_emulator::headless::{self, StopReason};
+use gb_emulator::movie::Player;
+use gb_emulator::GameBoy;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+// Run without a window, then save the screen and print the registers.
+// Exits with status 2 if --until-pc was given but never reached, so CI can
+// tell a test ROM that hung from one that finished.
+pub fn run_headless(mut gameboy: GameBoy, options: &Options) -> ! {
+    let result = headless::run(&mut gameboy, &options.limits);
+    match result.reason {
+        StopReason::Frames => println!("Stopped after {} frames", result.frames),
+        StopReason::ReachedPc => println!("Reached PC {:04X}", gameboy.cpu().get_pc()),
+        StopReason::Timeout => println!("Timed out after {} cycles", result.cycles),
+    }
+    println!("Frames: {}  Cycles: {}", result.frames, result.cycles);
+    println!("{}", gameboy.cpu().registers());
+    save_outputs(&gameboy, options);
+
+    let missed_pc = options.limits.until_pc.is_some() && result.reason != StopReason::ReachedPc;
+    std::process::exit(if missed_pc { 2 } else { 0 });
+}
+
+// Play a movie without a window, checking every frame's state. Exits with
+// status 1 at the first frame that doesn't match the recording.
+pub fn run_movie(mut gameboy: GameBoy, mut player: Player, options: &Options) -> ! {
+    let movie = player.movie();
+    println!("Playing {} frames ({} re-records)", movie.frames.len(), movie.rerecords);
+    let result = player.run_to_end(&mut gameboy);
+    println!("{}", gameboy.cpu().registers());
+    save_outputs(&gameboy, options);
+    match result {
+        Ok(()) => {
+            println!("Movie verified: all {} frames match", player.position());
+            std::process::exit(0);
+        }
+        Err(desync) => {
+            eprintln!("Movie {}", desync);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Screenshot and save state at the end of a headless run
+fn save_outputs(gameboy: &GameBoy, options: &Options) {
+    if let Some(path) = &options.screenshot {
+        match headless::save_screenshot(gameboy, path) {
+            Ok(()) => println!("Screenshot saved to {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to save screenshot: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &options.save_state {
+        let is_bess = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("bess"));
+        let data = if is_bess { gameboy.export_bess() } else { gameboy.save_state() };
+        match fs::write(path, data) {
+            Ok(()) => println!("State saved to {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to save state: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Command-line debugger on stdin. Exits on quit or end of input.
+pub fn run_debugger(mut gameboy: GameBoy) -> ! {
+    let mut debugger = Debugger::new();
+    // Ctrl-C stops a running command instead of the whole emulator
+    let interrupt = debugger.interrupt_flag();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed)) {
+        eprintln!("Ctrl-C won't stop running commands: {}", e);
+    }
+    println!("Debugger ready, h for help");
+    if let Ok(Response::Text(text)) = debugger.execute(&mut gameboy, "list") {
+        println!("{}", text);
+    }
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("(gbdb) ");
+        let _ = io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            std::process::exit(0);
+        };
+        match debugger.execute(&mut gameboy, &line) {
+            Ok(Response::Text(text)) if !text.is_empty() => println!("{}", text),
+            Ok(Response::Text(_)) => {}
+            Ok(Response::Quit) => std::process::exit(0),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+// List instructions from a ROM file. Addresses are as the CPU sees them,
+// with the chosen bank switched in at 0x4000-0x7FFF.
+pub fn run_disasm(options: DisasmOptions) -> ! {
+    let DisasmOptions { rom_path, start, count, bank } = options;
+    let rom = fs::read(&rom_path).unwrap_or_else(|e| {
+        eprintln!("Error reading ROM file {}: {}", rom_path, e);
+        std::process::exit(1);
+    });
+
+    // Outside the ROM reads like an empty bus
+    let read = |addr: u16| {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => bank * 0x4000 + (addr as usize - 0x4000),
+            _ => return 0xFF,
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    };
+    for instruction in disasm::disassemble(read, start, count) {
+        println!("{}", instruction.listing());
+    }
+    std::process::exit(0);
+}
+
+// Run a golden-image manifest and report every test. Exits with status 1
+// if any failed.
+pub fn run_golden(manifest: &Path, options: &GoldenOptions) -> ! {
+    let results = golden::run_manifest(manifest, options).unwrap_or_else(|e| {
+        eprintln!("Failed to load test manifest: {}", e);
+        std::process::exit(1);
+    });
+    for result in &results {
+        println!("{}", result);
+    }
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    println!("\n{} passed, {} failed", results.len() - failed, failed);
+    std::process::exit(if failed > 0 { 1 } else { 0 });
+}
+
+// Without the window there's nothing left to run
+#[cfg(not(feature = "gui"))]
+pub fn run_without_window(gameboy: &GameBoy) {
+    println!("\nGUI feature is disabled. Build completed successfully!");
+    println!("To enable GUI, build with: cargo build --features gui");
+    println!("Or use default features: cargo build");
+    println!("\nThis headless build is suitable for WASM or other non-GUI environments.");
+    println!("Run a ROM without a window with --frames, --until-pc or --timeout-cycles.");
+    println!("Total CPU cycles initialized: {}", gameboy.cycles());
+}
@@ -0,0 +1,298 @@
+// Input movies
+//
+// A movie is the joypad state of every frame from a known starting point,
+// enough to replay a session exactly. Next to each frame's input it holds a
+// hash of the whole machine state after that frame, so playback notices the
+// first frame where it goes a different way than the recording.
+//
+// File format, built from the same sections as save states:
+//   header    "GBMV", format version (u16), CRC-32 of the ROM (u32)
+//   "INFO"    hardware model (u8), re-record count (u32)
+//   "STRT"    save state to start from; without it the movie starts at
+//             power-on
+//   "INPT"    frame count (u32), then per frame the buttons (u8, see
+//             Input::to_bits) and the state hash (u64)
+//
+// Power-on movies need the same machine setup as the recording: no boot
+// ROM, the same --palette and --color-correction. A different setup shows
+// up as a mismatch on the first frame. Link cable partners aren't recorded,
+// so sessions using one don't replay.
+
+use crate::gameboy::GameBoy;
+use crate::input::Input;
+use crate::memory::Model;
+use crate::savestate::{StateReader, StateWriter};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const MAGIC: [u8; 4] = *b"GBMV";
+pub const VERSION: u16 = 1;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// 64-bit FNV-1a of the save state, see savestate.rs for what it covers
+pub fn state_hash(gameboy: &GameBoy) -> u64 {
+    gameboy
+        .save_state()
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Start {
+    PowerOn,
+    // Native save state
+    State(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    pub input: Input,
+    // state_hash after the frame ran
+    pub hash: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_crc: u32,
+    pub model: Model,
+    pub start: Start,
+    // How many times recording went back to an earlier frame
+    pub rerecords: u32,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::headerless();
+        w.bytes(&MAGIC);
+        w.u16(VERSION);
+        w.u32(self.rom_crc);
+        w.section(b"INFO", |w| {
+            w.u8(self.model as u8);
+            w.u32(self.rerecords);
+        });
+        if let Start::State(state) = &self.start {
+            w.section(b"STRT", |w| w.blob(state));
+        }
+        w.section(b"INPT", |w| {
+            w.u32(self.frames.len() as u32);
+            for frame in &self.frames {
+                w.u8(frame.input.to_bits());
+                w.u64(frame.hash);
+            }
+        });
+        w.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        if r.bytes(&mut magic).is_err() || magic != MAGIC {
+            return Err(invalid("not a movie".to_string()));
+        }
+        let version = r.u16()?;
+        if version > VERSION {
+            return Err(invalid(format!("movie version {} is newer than this emulator ({})", version, VERSION)));
+        }
+        let rom_crc = r.u32()?;
+
+        let mut info = None;
+        let mut start = Start::PowerOn;
+        let mut frames = None;
+        while let Some((tag, mut section)) = r.section()? {
+            match &tag {
+                b"INFO" => {
+                    let model = match section.u8()? {
+                        0 => Model::Dmg,
+                        1 => Model::Cgb,
+                        2 => Model::Sgb,
+                        other => return Err(invalid(format!("unknown hardware model {}", other))),
+                    };
+                    info = Some((model, section.u32()?));
+                }
+                b"STRT" => start = Start::State(section.blob()?),
+                b"INPT" => {
+                    let count = section.u32()? as usize;
+                    let mut list = Vec::new();
+                    for _ in 0..count {
+                        let input = Input::from_bits(section.u8()?);
+                        list.push(MovieFrame { input, hash: section.u64()? });
+                    }
+                    frames = Some(list);
+                }
+                // Sections from newer versions are skipped
+                _ => {}
+            }
+        }
+        let (model, rerecords) = info.ok_or_else(|| invalid("movie has no INFO section".to_string()))?;
+        let frames = frames.ok_or_else(|| invalid("movie has no INPT section".to_string()))?;
+        Ok(Self { rom_crc, model, start, rerecords, frames })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+// Records a movie while the frontend runs frames through it
+pub struct Recorder {
+    movie: Movie,
+    // Went back to an earlier frame; counted as a re-record once recording
+    // carries on from there
+    rewound: bool,
+}
+
+impl Recorder {
+    // Record from power-on. The machine must not have run yet.
+    pub fn power_on(gameboy: &GameBoy) -> io::Result<Self> {
+        if gameboy.cycles() != 0 {
+            return Err(invalid("a power-on movie has to start before the first frame".to_string()));
+        }
+        Ok(Self::with_start(gameboy, Start::PowerOn))
+    }
+
+    // Record from the machine's current state, which is embedded in the movie
+    pub fn from_state(gameboy: &GameBoy) -> Self {
+        Self::with_start(gameboy, Start::State(gameboy.save_state()))
+    }
+
+    fn with_start(gameboy: &GameBoy, start: Start) -> Self {
+        let movie = Movie {
+            rom_crc: gameboy.rom_crc(),
+            model: gameboy.memory().model(),
+            start,
+            rerecords: 0,
+            frames: Vec::new(),
+        };
+        Self { movie, rewound: false }
+    }
+
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy, input: Input) {
+        if self.rewound {
+            self.movie.rerecords += 1;
+            self.rewound = false;
+        }
+        gameboy.set_buttons(input);
+        gameboy.run_frame();
+        self.movie.frames.push(MovieFrame { input, hash: state_hash(gameboy) });
+    }
+
+    // Call after loading a state or rewinding. Cuts the movie back to the
+    // frame the machine is at now, found by its hash. Returns false if the
+    // state isn't one from this recording; the movie can't continue from
+    // it then.
+    pub fn resync(&mut self, gameboy: &GameBoy) -> bool {
+        let hash = state_hash(gameboy);
+        let frame = self.movie.frames.iter().rposition(|frame| frame.hash == hash).map(|i| i + 1);
+        let at_start = match &self.movie.start {
+            Start::State(state) => *state == gameboy.save_state(),
+            Start::PowerOn => false,
+        };
+        match frame.or(at_start.then_some(0)) {
+            Some(length) => {
+                self.movie.frames.truncate(length);
+                self.rewound = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+// Playback went a different way than the recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Desync {
+    // Zero-based index of the first mismatching frame
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "playback diverged at frame {}: state hash {:016X}, recorded {:016X}",
+            self.frame, self.actual, self.expected
+        )
+    }
+}
+
+pub struct Player {
+    movie: Movie,
+    next: usize,
+}
+
+impl Player {
+    // Put the machine at the movie's start. It has to be set up with the
+    // movie's model and ROM, and for power-on movies not have run yet.
+    pub fn start(movie: Movie, gameboy: &mut GameBoy) -> io::Result<Self> {
+        if gameboy.rom_crc() != movie.rom_crc {
+            return Err(invalid("movie is for a different game".to_string()));
+        }
+        if gameboy.memory().model() != movie.model {
+            return Err(invalid(format!("movie was recorded on {:?} hardware", movie.model)));
+        }
+        match &movie.start {
+            Start::PowerOn if gameboy.cycles() != 0 => {
+                return Err(invalid("a power-on movie has to start before the first frame".to_string()));
+            }
+            Start::PowerOn => {}
+            Start::State(state) => gameboy.load_state(state)?,
+        }
+        Ok(Self { movie, next: 0 })
+    }
+
+    // Run the next frame with its recorded input and check the result. None
+    // once the movie is over.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Option<Result<(), Desync>> {
+        let frame = *self.movie.frames.get(self.next)?;
+        gameboy.set_buttons(frame.input);
+        gameboy.run_frame();
+        let actual = state_hash(gameboy);
+        let index = self.next;
+        self.next += 1;
+        if actual != frame.hash {
+            return Some(Err(Desync { frame: index, expected: frame.hash, actual }));
+        }
+        Some(Ok(()))
+    }
+
+    // Play the rest of the movie, stopping at the first mismatch
+    pub fn run_to_end(&mut self, gameboy: &mut GameBoy) -> Result<(), Desync> {
+        while let Some(result) = self.run_frame(gameboy) {
+            result?;
+        }
+        Ok(())
+    }
+
+    // Frames played so far
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.movie.frames.len()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
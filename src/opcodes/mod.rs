@@ -1,6 +1,7 @@
 use crate::flags::FlagOps;
 use serde::Deserialize;
 use std::error::Error;
+use std::sync::OnceLock;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct OpCodeRaw {
@@ -14,6 +15,7 @@ pub struct OpCodeRaw {
     operand2: Option<String>
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct OpCode {
     pub prefixed: bool,
@@ -65,3 +67,25 @@ pub fn load_opcodes() -> Result<(Vec<OpCode>, Vec<OpCode>), Box<dyn Error>> {
 
     Ok((unprefixed_opcodes, cbprefixed_opcodes))
 }
+
+// 256 entries indexed by opcode
+pub type OpCodeTable = Vec<Option<OpCode>>;
+
+// Unprefixed and CB-prefixed tables, parsed once. The unprefixed table has no
+// entries for the 11 unused opcodes.
+pub fn opcode_tables() -> &'static (OpCodeTable, OpCodeTable) {
+    static TABLES: OnceLock<(OpCodeTable, OpCodeTable)> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let (unprefixed, cbprefixed) = load_opcodes().unwrap();
+        (index_by_opcode(unprefixed), index_by_opcode(cbprefixed))
+    })
+}
+
+fn index_by_opcode(opcodes: Vec<OpCode>) -> OpCodeTable {
+    let mut table = vec![None; 256];
+    for opcode in opcodes {
+        let index = opcode.addr as usize;
+        table[index] = Some(opcode);
+    }
+    table
+}
@@ -0,0 +1,272 @@
+// Command line options of the emulator binary
+
+use gb_emulator::colorization::ButtonPalette;
+use gb_emulator::disasm;
+use gb_emulator::golden::GoldenOptions;
+use gb_emulator::headless::RunLimits;
+use gb_emulator::link;
+use gb_emulator::pacing::Speed;
+use gb_emulator::rewind::RewindConfig;
+use gb_emulator::Model;
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage: cargo run -- [--model dmg|cgb|sgb] [--palette <combo>] [--color-correction] [--link-host <port> | --link-connect <addr:port>] [--link-protocol raw|bgb] [--printer <dir>] [--dmg07-host <port> | --dmg07-connect <addr:port>] [--headless] [--frames <n>] [--until-pc <addr>] [--timeout-cycles <n>] [--screenshot <file.png|file.ppm>] [--load-state <file>] [--save-state <file>] [--rewind-budget <MB>] [--rewind-interval <frames>] [--fast-forward <speed>] [--slow-motion <speed>] [--record-movie <file> | --play-movie <file>] [--run-ahead <frames>] [--debug] <path_to_rom.gb>
+       cargo run -- --golden <manifest.json> [--golden-output <dir>] [--bless]
+       cargo run -- disasm <path_to_rom.gb> [--start <addr>] [--count <n>] [--bank <n>]";
+
+
+// Report a command line mistake with the usage text and exit
+pub fn usage_error(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+// Which end of a TCP connection this instance is
+pub enum LinkMode {
+    Host(String),
+    Connect(String),
+}
+
+// Wire format spoken over the link connection
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkProtocol {
+    // Our own protocol between two instances of this emulator
+    Raw,
+    // BGB 1.4, for linking with other emulators
+    Bgb,
+}
+
+// Command line options
+pub struct Options {
+    pub rom_path: String,
+    pub model: Option<Model>,
+    pub palette: Option<ButtonPalette>,
+    pub color_correction: bool,
+    pub link: Option<LinkMode>,
+    pub link_protocol: LinkProtocol,
+    // Directory printed images are saved to
+    pub printer: Option<String>,
+    // Four Player Adapter: host it as player 1, or join one as players 2-4
+    pub dmg07: Option<LinkMode>,
+    // Run without a window until one of the limits is hit
+    pub headless: bool,
+    pub limits: RunLimits,
+    pub screenshot: Option<PathBuf>,
+    // Save state to start from, ours or BESS
+    pub load_state: Option<PathBuf>,
+    // Where a headless run saves its final state; .bess files are BESS
+    pub save_state: Option<PathBuf>,
+    // Rewind buffer settings; a zero budget disables rewinding
+    pub rewind: RewindConfig,
+    // Speeds for the fast-forward and slow motion keys
+    pub fast_forward: Speed,
+    pub slow_motion: Speed,
+    // Input movie to record in the window, or to play back
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    // Frames to run ahead in the window to hide input lag
+    pub run_ahead: u32,
+    // Run under the command-line debugger instead
+    pub debug: bool,
+    // Golden-image test manifest to run instead of a ROM
+    pub golden: Option<PathBuf>,
+    pub golden_options: GoldenOptions,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        // Default to boot ROM if no argument provided
+        rom_path: "dmg_boot.bin".to_string(),
+        model: None,
+        palette: None,
+        color_correction: false,
+        link: None,
+        link_protocol: LinkProtocol::Raw,
+        printer: None,
+        dmg07: None,
+        headless: false,
+        limits: RunLimits::default(),
+        screenshot: None,
+        load_state: None,
+        save_state: None,
+        rewind: RewindConfig::default(),
+        fast_forward: Speed::Uncapped,
+        slow_motion: Speed::Scaled(0.25),
+        record_movie: None,
+        play_movie: None,
+        run_ahead: 0,
+        debug: false,
+        golden: None,
+        golden_options: GoldenOptions { output_dir: PathBuf::from("golden-failures"), bless: false },
+    };
+    let mut rom_path = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--color-correction" => options.color_correction = true,
+            "--model" => {
+                options.model = match value()?.as_str() {
+                    "dmg" => Some(Model::Dmg),
+                    "cgb" => Some(Model::Cgb),
+                    "sgb" => Some(Model::Sgb),
+                    other => return Err(format!("Unknown model: {}", other)),
+                };
+            }
+            "--palette" => {
+                let name = value()?;
+                let palette = ButtonPalette::from_name(name).ok_or_else(|| {
+                    let names: Vec<&str> = ButtonPalette::ALL.iter().map(|p| p.name()).collect();
+                    format!("Unknown palette: {} (expected one of {})", name, names.join(", "))
+                })?;
+                options.palette = Some(palette);
+            }
+            "--link-host" => options.link = Some(LinkMode::Host(link::link_address(value()?))),
+            "--link-connect" => options.link = Some(LinkMode::Connect(link::link_address(value()?))),
+            "--link-protocol" => {
+                options.link_protocol = match value()?.as_str() {
+                    "raw" => LinkProtocol::Raw,
+                    "bgb" => LinkProtocol::Bgb,
+                    other => return Err(format!("Unknown link protocol: {}", other)),
+                };
+            }
+            "--printer" => options.printer = Some(value()?.to_string()),
+            "--dmg07-host" => options.dmg07 = Some(LinkMode::Host(link::link_address(value()?))),
+            "--dmg07-connect" => options.dmg07 = Some(LinkMode::Connect(link::link_address(value()?))),
+            "--headless" => options.headless = true,
+            "--frames" => options.limits.frames = Some(parse_number(arg, value()?)?),
+            "--until-pc" => options.limits.until_pc = Some(parse_address(value()?)?),
+            "--timeout-cycles" => options.limits.timeout_cycles = Some(parse_number(arg, value()?)?),
+            "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+            "--load-state" => options.load_state = Some(PathBuf::from(value()?)),
+            "--save-state" => options.save_state = Some(PathBuf::from(value()?)),
+            "--rewind-budget" => {
+                let megabytes: usize = parse_number(arg, value()?)?;
+                options.rewind.budget = megabytes
+                    .checked_mul(1024 * 1024)
+                    .ok_or_else(|| format!("--rewind-budget is too large: {} MB", megabytes))?;
+            }
+            "--rewind-interval" => options.rewind.interval = parse_number::<u32>(arg, value()?)?.max(1),
+            // 0 fast-forwards as fast as possible
+            "--fast-forward" => {
+                let multiplier: f64 = parse_number(arg, value()?)?;
+                options.fast_forward = match Speed::from_multiplier(multiplier) {
+                    Some(Speed::Scaled(multiplier)) if multiplier <= 1.0 => None,
+                    speed => speed,
+                }
+                .ok_or_else(|| {
+                    format!("--fast-forward expects a speed above 1, or 0 for uncapped, got {}", multiplier)
+                })?;
+            }
+            "--slow-motion" => {
+                let multiplier: f64 = parse_number(arg, value()?)?;
+                if !(multiplier > 0.0 && multiplier <= 1.0) {
+                    return Err(format!("--slow-motion expects a speed between 0 and 1, got {}", multiplier));
+                }
+                options.slow_motion = Speed::Scaled(multiplier);
+            }
+            "--record-movie" => options.record_movie = Some(PathBuf::from(value()?)),
+            "--play-movie" => options.play_movie = Some(PathBuf::from(value()?)),
+            "--run-ahead" => options.run_ahead = parse_number(arg, value()?)?,
+            "--debug" => options.debug = true,
+            "--golden" => options.golden = Some(PathBuf::from(value()?)),
+            "--golden-output" => options.golden_options.output_dir = PathBuf::from(value()?),
+            "--bless" => options.golden_options.bless = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            path if rom_path.is_none() => rom_path = Some(path.to_string()),
+            extra => return Err(format!("Unexpected argument: {}", extra)),
+        }
+    }
+
+    let serial_devices = [options.link.is_some(), options.printer.is_some(), options.dmg07.is_some()];
+    if serial_devices.iter().filter(|&&used| used).count() > 1 {
+        return Err("Only one of the link cable, printer and DMG-07 can be connected".to_string());
+    }
+    // The extra frames would send serial data to the device
+    if options.run_ahead > 0 && serial_devices.contains(&true) {
+        return Err("--run-ahead can't be used with the link cable, printer or DMG-07".to_string());
+    }
+    // Any run limit or a screenshot asks for a headless run
+    if options.limits.is_bounded() || options.screenshot.is_some() {
+        options.headless = true;
+    }
+    if options.play_movie.is_some() {
+        if options.record_movie.is_some() {
+            return Err("--record-movie and --play-movie can't be used together".to_string());
+        }
+        if options.limits.is_bounded() {
+            return Err("--play-movie runs the whole movie and takes no run limits".to_string());
+        }
+        // Without a window the movie can only be checked
+        if cfg!(not(feature = "gui")) {
+            options.headless = true;
+        }
+    }
+    if options.record_movie.is_some() && (options.headless || cfg!(not(feature = "gui"))) {
+        return Err("--record-movie needs the window".to_string());
+    }
+    if options.debug && (options.headless || options.record_movie.is_some() || options.play_movie.is_some()) {
+        return Err("--debug runs on its own, without run limits or movies".to_string());
+    }
+    if options.save_state.is_some() && !options.headless {
+        return Err("--save-state needs a headless run".to_string());
+    }
+    if options.headless && !options.limits.is_bounded() && options.play_movie.is_none() {
+        return Err("Headless mode needs --frames, --until-pc or --timeout-cycles".to_string());
+    }
+    if let Some(path) = rom_path {
+        options.rom_path = path;
+    }
+    Ok(options)
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got {}", flag, value))
+}
+
+// Addresses are hexadecimal, with or without a 0x or $ prefix
+fn parse_address(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", value))
+}
+
+// `disasm` subcommand options
+pub struct DisasmOptions {
+    pub rom_path: String,
+    pub start: u16,
+    // Instructions to list
+    pub count: usize,
+    // ROM bank seen at 0x4000-0x7FFF
+    pub bank: usize,
+}
+
+pub fn parse_disasm_args(args: &[String]) -> Result<DisasmOptions, String> {
+    let mut options = DisasmOptions { rom_path: String::new(), start: 0x0100, count: 64, bank: 1 };
+    let mut rom_path = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--start" => options.start = parse_address(value()?)?,
+            "--count" => {
+                options.count = parse_number(arg, value()?)?;
+                if options.count > disasm::MAX_COUNT {
+                    return Err(format!("--count is at most {}", disasm::MAX_COUNT));
+                }
+            }
+            "--bank" => options.bank = parse_number(arg, value()?)?,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            path if rom_path.is_none() => rom_path = Some(path.to_string()),
+            extra => return Err(format!("Unexpected argument: {}", extra)),
+        }
+    }
+    options.rom_path = rom_path.ok_or("disasm needs a ROM file")?;
+    Ok(options)
+}
@@ -0,0 +1,160 @@
+// Frame pacing for the GUI
+//
+// The DMG draws a frame every 70224 clock cycles at 4.194304 MHz, which is
+// about 59.73 Hz rather than a round 60. Pacing against 60 FPS makes the
+// emulator slowly drift ahead of real hardware.
+//
+// Frames are paced by sleeping until absolute deadlines, so rounding errors
+// never accumulate. That's the only mode: there is no audio output yet to
+// drive pacing from its buffer fill level.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub const CPU_CLOCK_HZ: u64 = 4_194_304;
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+// Exact duration of one DMG frame (~16.743 ms)
+pub fn frame_duration() -> Duration {
+    Duration::from_nanos(CYCLES_PER_FRAME * 1_000_000_000 / CPU_CLOCK_HZ)
+}
+
+// Emulation speed relative to real hardware
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    // 2.0 runs twice as fast, 0.25 at a quarter speed
+    Scaled(f64),
+    // As fast as the host can go
+    Uncapped,
+}
+
+impl Speed {
+    pub const NORMAL: Speed = Speed::Scaled(1.0);
+
+    // Positive, finite multiplier, or zero for uncapped. None for anything
+    // else (negative, NaN, infinite).
+    pub fn from_multiplier(multiplier: f64) -> Option<Self> {
+        if multiplier == 0.0 {
+            Some(Speed::Uncapped)
+        } else if multiplier > 0.0 && multiplier.is_finite() {
+            Some(Speed::Scaled(multiplier))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Scaled(multiplier) => write!(f, "{}x", multiplier),
+            Speed::Uncapped => write!(f, "uncapped"),
+        }
+    }
+}
+
+pub struct FramePacer {
+    speed: Speed,
+    frame_duration: Duration,
+    next_deadline: Instant,
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        let frame_duration = frame_duration();
+        Self {
+            speed: Speed::NORMAL,
+            frame_duration,
+            next_deadline: Instant::now() + frame_duration,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        if speed == self.speed {
+            return;
+        }
+        self.speed = speed;
+        if let Speed::Scaled(multiplier) = speed {
+            self.frame_duration = frame_duration().div_f64(multiplier);
+        }
+        // Start the new schedule from now rather than catching up on the old one
+        self.next_deadline = Instant::now() + self.frame_duration;
+    }
+
+    // Block until the next frame should be emulated
+    pub fn wait(&mut self) {
+        if self.speed == Speed::Uncapped {
+            self.next_deadline = Instant::now() + self.frame_duration;
+            return;
+        }
+
+        let now = Instant::now();
+        if now < self.next_deadline {
+            std::thread::sleep(self.next_deadline - now);
+            self.next_deadline += self.frame_duration;
+        } else if now - self.next_deadline > self.frame_duration {
+            // Fell more than a frame behind (window dragged, debugger attached...):
+            // resynchronise instead of racing to catch up
+            self.next_deadline = now + self.frame_duration;
+        } else {
+            self.next_deadline += self.frame_duration;
+        }
+    }
+}
+
+// Measures the actual emulation speed over roughly one second windows, for
+// showing next to the requested speed while fast-forwarding
+pub struct SpeedMeter {
+    window_start: Instant,
+    frames: u32,
+    speed: Option<f64>,
+}
+
+impl Default for SpeedMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpeedMeter {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        Self { window_start: Instant::now(), frames: 0, speed: None }
+    }
+
+    // Count one emulated frame. Returns true when a new measurement is ready.
+    pub fn frame(&mut self) -> bool {
+        self.frames += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Self::WINDOW {
+            return false;
+        }
+        let speed = self.frames as f64 * frame_duration().as_secs_f64() / elapsed.as_secs_f64();
+        self.reset();
+        self.speed = Some(speed);
+        true
+    }
+
+    // Start over, for example after pausing or changing speed
+    pub fn reset(&mut self) {
+        self.window_start = Instant::now();
+        self.frames = 0;
+        self.speed = None;
+    }
+
+    // Multiple of real hardware speed over the last full window
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+}
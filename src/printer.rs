@@ -0,0 +1,356 @@
+// Game Boy Printer
+//
+// The printer is a serial device that never drives the clock. Games send it
+// packets, one byte per transfer:
+//   0x88 0x33                 magic bytes
+//   command                   0x01 INIT, 0x02 PRINT, 0x04 DATA, 0x0F STATUS
+//   compression               1 = data is RLE compressed
+//   length (2 bytes, LE)      number of data bytes that follow
+//   data
+//   checksum (2 bytes, LE)    sum of command through the last data byte
+//   0x00                      printer answers 0x81 (alive)
+//   0x00                      printer answers its status byte
+// Every other byte is answered with 0x00.
+//
+// DATA packets carry 2bpp tiles, 20 per row like the screen; an empty DATA
+// packet ends the image. PRINT data is 4 bytes: number of sheets, margins
+// (high nibble before, low nibble after), palette (2 bits per color, color 0
+// in bits 0-1) and exposure.
+//
+// Printed images are stacked onto a roll of paper like on the real printer,
+// so long images sent in several PRINT commands with no margins between them
+// (the Pokédex does this) come out as one picture. The roll is written as a
+// PNG when each print completes, the same file again as more is printed onto
+// it, and paper fed after a print cuts it off. Each margin unit is one tile row.
+// Saved files and failures are reported to the frontend's event handler.
+
+use crate::image::write_png;
+use crate::serial::SerialDevice;
+use std::io;
+use std::path::PathBuf;
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+
+const ALIVE: u8 = 0x81;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// The printer's RAM holds 9 rows of 20 tiles
+const BUFFER_SIZE: usize = 0x2000;
+const TILES_PER_ROW: usize = 20;
+const ROW_BYTES: usize = TILES_PER_ROW * 16;
+
+pub const PAPER_WIDTH: usize = TILES_PER_ROW * 8;
+
+// A print keeps the printer busy for about a quarter second per tile row
+const PRINT_CYCLES_PER_ROW: u32 = 1_048_576;
+
+// Paper shades from white to black
+const SHADES: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+// What happened to a print, for the frontend to report
+#[derive(Debug)]
+pub enum PrinterEvent {
+    // The roll was written to this file (again, if more was printed onto it)
+    Saved(PathBuf),
+    SaveFailed(io::Error),
+}
+
+pub type PrinterEventFn = Box<dyn FnMut(PrinterEvent) + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    // Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    busy_cycles: u32,
+    // Printed rows of pixels on the roll, and the file they're saved in
+    paper: Vec<u32>,
+    paper_path: Option<PathBuf>,
+    output_dir: PathBuf,
+    saved: Vec<PathBuf>,
+    on_event: Option<PrinterEventFn>,
+}
+
+impl Printer {
+    pub fn new(output_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            buffer: Vec::new(),
+            busy_cycles: 0,
+            paper: Vec::new(),
+            paper_path: None,
+            output_dir: output_dir.into(),
+            saved: Vec::new(),
+            on_event: None,
+        }
+    }
+
+    // Called with every saved print and every failure to save one
+    pub fn set_event_handler(&mut self, handler: impl FnMut(PrinterEvent) + Send + 'static) {
+        self.on_event = Some(Box::new(handler));
+    }
+
+    // Images written so far
+    pub fn saved(&self) -> &[PathBuf] {
+        &self.saved
+    }
+
+    // Feed one byte through the packet state machine and return the answer
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == MAGIC_1 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == MAGIC_2 => State::Command,
+            // Out of sync: resynchronise on the next magic sequence
+            State::Magic2 if byte == MAGIC_1 => State::Magic2,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                if self.received_checksum == self.checksum {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status();
+                State::Magic1
+            }
+        };
+        reply
+    }
+
+    fn status(&self) -> u8 {
+        let busy = if self.busy_cycles > 0 { STATUS_PRINTING } else { 0 };
+        self.status | busy
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    decompress_rle(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.buffer.truncate(BUFFER_SIZE);
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+            }
+            PRINT if self.data.len() >= 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                self.print(sheets, margins >> 4, margins & 0x0F, palette);
+            }
+            // STATUS only asks for the status byte
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margin_before: u8, margin_after: u8, palette: u8) {
+        // Feeding paper before the print ends whatever is on the roll
+        if margin_before > 0 {
+            self.cut_paper();
+        }
+        self.feed(margin_before);
+
+        // Zero sheets only feeds paper
+        let rows = self.buffer.len() / ROW_BYTES;
+        if sheets > 0 {
+            for _ in 0..sheets {
+                render_rows(&self.buffer[..rows * ROW_BYTES], palette, &mut self.paper);
+            }
+            self.busy_cycles = PRINT_CYCLES_PER_ROW * rows.max(1) as u32;
+        }
+
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+
+        self.feed(margin_after);
+        self.save_paper();
+        if margin_after > 0 {
+            self.cut_paper();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        let rows = margin as usize * 8;
+        self.paper.extend(std::iter::repeat_n(SHADES[0], rows * PAPER_WIDTH));
+    }
+
+    // Start a new roll: what was printed so far is already saved
+    fn cut_paper(&mut self) {
+        self.paper.clear();
+        self.paper_path = None;
+    }
+
+    fn save_paper(&mut self) {
+        // Blank paper from feeds alone isn't worth a file
+        if self.paper.iter().all(|&pixel| pixel == SHADES[0]) {
+            return;
+        }
+        let event = match self.try_save_paper() {
+            Ok(path) => PrinterEvent::Saved(path),
+            Err(e) => PrinterEvent::SaveFailed(e),
+        };
+        if let Some(handler) = &mut self.on_event {
+            handler(event);
+        }
+    }
+
+    fn try_save_paper(&mut self) -> io::Result<PathBuf> {
+        let path = match &self.paper_path {
+            Some(path) => path.clone(),
+            None => {
+                std::fs::create_dir_all(&self.output_dir)?;
+                // Never overwrite prints from earlier sessions
+                (1..)
+                    .map(|n| self.output_dir.join(format!("print_{:04}.png", n)))
+                    .find(|path| !path.exists())
+                    .expect("ran out of print file names")
+            }
+        };
+        let height = self.paper.len() / PAPER_WIDTH;
+        write_png(&path, PAPER_WIDTH, height, &self.paper)?;
+        if self.paper_path.is_none() {
+            self.paper_path = Some(path.clone());
+            self.saved.push(path.clone());
+        }
+        Ok(path)
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer_master(&mut self, data: u8) -> u8 {
+        self.receive(data)
+    }
+
+    // The printer never drives the clock
+    fn poll_slave(&mut self, _data: u8) -> Option<u8> {
+        None
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+    }
+}
+
+// RLE: a control byte with bit 7 clear is followed by (n + 1) literal bytes,
+// with bit 7 set by one byte repeated (n & 0x7F) + 2 times
+fn decompress_rle(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 == 0 {
+            let count = (control as usize + 1).min(data.len() - i);
+            out.extend_from_slice(&data[i..i + count]);
+            i += count;
+        } else if let Some(&byte) = data.get(i) {
+            out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            i += 1;
+        }
+    }
+}
+
+// Append 160-pixel rows for tile rows of 2bpp data
+fn render_rows(tiles: &[u8], palette: u8, paper: &mut Vec<u32>) {
+    // A palette of 0 behaves like the default 0xE4 (identity)
+    let palette = if palette == 0 { 0xE4 } else { palette };
+    for row in tiles.chunks(ROW_BYTES) {
+        for y in 0..8 {
+            for tile in row.chunks(16) {
+                let low = tile[y * 2];
+                let high = tile[y * 2 + 1];
+                for bit in (0..8).rev() {
+                    let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    paper.push(SHADES[shade as usize]);
+                }
+            }
+        }
+    }
+}
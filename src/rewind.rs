@@ -0,0 +1,234 @@
+// Rewind
+//
+// A ring buffer of save states taken every few frames. Keyframes are stored
+// whole, the states after them as the XOR against their keyframe, so bytes
+// that didn't change become zeros. Both are run-length compressed, which
+// squeezes the zeros out. Rewinding pops the newest state and loads it.
+//
+// Memory use stays within the budget by dropping the oldest snapshots; a
+// keyframe is always dropped together with the deltas that need it.
+
+use crate::gameboy::GameBoy;
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RewindConfig {
+    // Bytes of compressed snapshots to keep at most
+    pub budget: usize,
+    // Frames between snapshots. 1 rewinds smoothly, frame by frame.
+    pub interval: u32,
+    // Snapshots between keyframes. Longer runs of deltas take less memory,
+    // but the deltas drift from their keyframe and compress worse.
+    pub keyframe_interval: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        Self {
+            budget: 64 * 1024 * 1024,
+            interval: 1,
+            keyframe_interval: 60,
+        }
+    }
+}
+
+enum Snapshot {
+    Key(Vec<u8>),
+    // XOR against the closest keyframe before it
+    Delta(Vec<u8>),
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        match self {
+            Snapshot::Key(data) | Snapshot::Delta(data) => data.len(),
+        }
+    }
+}
+
+pub struct Rewind {
+    config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    // Uncompressed newest keyframe in the buffer, to make deltas against
+    // and decode them
+    keyframe: Option<Vec<u8>>,
+    // Deltas after that keyframe
+    since_keyframe: usize,
+    frames_until_snapshot: u32,
+    used: usize,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            snapshots: VecDeque::new(),
+            keyframe: None,
+            since_keyframe: 0,
+            frames_until_snapshot: 0,
+            used: 0,
+        }
+    }
+
+    // Call once per emulated frame; takes a snapshot every `interval` frames
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        if self.frames_until_snapshot > 0 {
+            self.frames_until_snapshot -= 1;
+            return;
+        }
+        self.frames_until_snapshot = self.config.interval.saturating_sub(1);
+        self.push(gameboy.save_state());
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        let snapshot = match &self.keyframe {
+            // States only change size when SGB packet data is in flight; start
+            // a new keyframe then too
+            Some(keyframe) if self.since_keyframe < self.config.keyframe_interval && keyframe.len() == state.len() => {
+                self.since_keyframe += 1;
+                let delta: Vec<u8> = state.iter().zip(keyframe).map(|(a, b)| a ^ b).collect();
+                Snapshot::Delta(compress(&delta))
+            }
+            _ => {
+                let snapshot = Snapshot::Key(compress(&state));
+                self.keyframe = Some(state);
+                self.since_keyframe = 0;
+                snapshot
+            }
+        };
+        self.used += snapshot.size();
+        self.snapshots.push_back(snapshot);
+        self.enforce_budget();
+    }
+
+    fn enforce_budget(&mut self) {
+        while self.used > self.config.budget {
+            // Drop the oldest keyframe and the deltas that depend on it
+            self.pop_front();
+            while matches!(self.snapshots.front(), Some(Snapshot::Delta(_))) {
+                self.pop_front();
+            }
+        }
+        if self.snapshots.is_empty() {
+            self.keyframe = None;
+        }
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(snapshot) = self.snapshots.pop_front() {
+            self.used -= snapshot.size();
+        }
+    }
+
+    // Go back one snapshot. Returns false when there's nothing left.
+    pub fn rewind(&mut self, gameboy: &mut GameBoy) -> bool {
+        while let Some(snapshot) = self.snapshots.pop_back() {
+            self.used -= snapshot.size();
+            let state = match snapshot {
+                // The newest keyframe is kept uncompressed already
+                Snapshot::Key(_) => {
+                    let state = self.keyframe.take().expect("keyframe not cached");
+                    self.reload_keyframe();
+                    state
+                }
+                Snapshot::Delta(data) => {
+                    self.since_keyframe -= 1;
+                    let keyframe = self.keyframe.as_ref().expect("delta without a keyframe");
+                    decompress(&data).iter().zip(keyframe).map(|(a, b)| a ^ b).collect()
+                }
+            };
+            if gameboy.load_state(&state).is_ok() {
+                // Pick up from here on the next record
+                self.frames_until_snapshot = self.config.interval.saturating_sub(1);
+                return true;
+            }
+        }
+        false
+    }
+
+    // After the newest keyframe was popped, make deltas against the one
+    // before it
+    fn reload_keyframe(&mut self) {
+        let newest_key = self.snapshots.iter().rposition(|s| matches!(s, Snapshot::Key(_)));
+        self.keyframe = newest_key.map(|index| match &self.snapshots[index] {
+            Snapshot::Key(data) => decompress(data),
+            Snapshot::Delta(_) => unreachable!(),
+        });
+        self.since_keyframe = newest_key.map_or(0, |index| self.snapshots.len() - 1 - index);
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    // Compressed bytes held
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe = None;
+        self.since_keyframe = 0;
+        self.used = 0;
+    }
+}
+
+// Run-length coding tuned for XOR deltas: pairs of (zero run, literal run)
+// lengths as LEB128 varints, each followed by the literal bytes
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&b| b == 0).count();
+        i += zeros;
+        // Literals end at the first run of zeros long enough to be worth a pair
+        let start = i;
+        while i < data.len() && !data[i..].starts_with(&[0, 0, 0, 0]) {
+            i += 1;
+        }
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, i - start);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeros = read_varint(data, &mut pos);
+        let literals = read_varint(data, &mut pos);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,96 @@
+// Run-ahead
+//
+// Games react to input a frame or more after reading it. Run-ahead hides
+// that lag: after each real frame the machine is saved, run a few frames
+// further with the same buttons, and restored. The picture from those extra
+// frames is shown instead of the real one, so a button press shows up as if
+// the game had reacted straight away.
+//
+// Every shown frame costs 1 + `frames` emulated frames plus a save and a
+// load. When that no longer fits in the frame budget on average, run-ahead
+// turns itself off rather than make the game stutter.
+//
+// Connected serial devices aren't part of save states, so they would see the
+// extra frames' transfers; don't run ahead with one connected.
+
+use crate::gameboy::GameBoy;
+use crate::pacing::frame_duration;
+use std::time::{Duration, Instant};
+
+// Weight of the newest frame in the average frame time
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+pub struct RunAhead {
+    frames: u32,
+    budget: Duration,
+    // Moving average of the time a shown frame takes, in seconds
+    average: f64,
+    enabled: bool,
+    // Picture `frames` frames ahead, None until the next frame after a jump
+    framebuffer: Option<Vec<u32>>,
+}
+
+impl RunAhead {
+    // Budget is one frame at normal speed
+    pub fn new(frames: u32) -> Self {
+        Self::with_budget(frames, frame_duration())
+    }
+
+    pub fn with_budget(frames: u32, budget: Duration) -> Self {
+        Self { frames, budget, average: 0.0, enabled: frames > 0, framebuffer: None }
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Average time a shown frame took, real and run-ahead frames together
+    pub fn average_frame_time(&self) -> Duration {
+        Duration::from_secs_f64(self.average)
+    }
+
+    // Run the real frame with `real_frame`, then look ahead with the buttons
+    // it left pressed. The machine ends up just after the real frame. Returns
+    // false when this frame pushed the average over budget and run-ahead
+    // turned itself off.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy, real_frame: impl FnOnce(&mut GameBoy)) -> bool {
+        let start = Instant::now();
+        real_frame(gameboy);
+        if !self.enabled {
+            return true;
+        }
+
+        let state = gameboy.save_state();
+        for _ in 0..self.frames {
+            gameboy.run_frame();
+        }
+        let framebuffer = self.framebuffer.get_or_insert_with(Vec::new);
+        framebuffer.clear();
+        framebuffer.extend_from_slice(gameboy.framebuffer());
+        gameboy.load_state(&state).expect("reloading the state saved before running ahead");
+
+        let elapsed = start.elapsed().as_secs_f64();
+        self.average += (elapsed - self.average) * AVERAGE_WEIGHT;
+        if self.average > self.budget.as_secs_f64() {
+            self.enabled = false;
+            self.framebuffer = None;
+            return false;
+        }
+        true
+    }
+
+    // The machine jumped to another state (save state, rewind); the picture
+    // ahead is stale until the next frame
+    pub fn invalidate(&mut self) {
+        self.framebuffer = None;
+    }
+
+    // The picture to show: the one from ahead if there is one
+    pub fn framebuffer<'a>(&'a self, gameboy: &'a GameBoy) -> &'a [u32] {
+        self.framebuffer.as_deref().unwrap_or(gameboy.framebuffer())
+    }
+}
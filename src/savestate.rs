@@ -0,0 +1,207 @@
+// Save states
+//
+// A save state is the complete machine state in a small binary format:
+//   header    "GBST", format version (u16), CRC-32 of the ROM (u32)
+//   sections  tag (4 bytes), length (u32), payload
+// All numbers are little-endian. Sections:
+//   "CPU "    registers, IME, HALT, stall cycles, cycle counter
+//   "MEM "    RAM, I/O registers and the CGB banking/palette/HDMA state
+//   "SER "    serial port registers and transfer progress
+//   "GPU "    LCD position and mode, last rendered picture
+//   "SGB "    Super Game Boy state, only on the SGB
+// Timers, sound and cartridge RAM/mappers aren't emulated yet; they will get
+// sections of their own.
+//
+// Compatibility rules, so old states keep loading:
+// - never reorder or remove fields, only append them to a section, and read
+//   appended fields only when the section still has data (at_end)
+// - unknown sections are skipped, so new things can get new sections
+// - bump VERSION for every change; loading refuses states from newer versions
+//
+// Why not serde: serde only reads the JSON files people edit here (the
+// opcode table, golden manifests). A binary serde format would be another
+// dependency whose encoding follows the struct layout, so appending a field
+// or skipping a section written by a newer build would break old states.
+// States are also taken many times a second for rewind and run-ahead, where
+// a plain byte buffer with no per-field overhead keeps snapshots small and
+// fast.
+
+use std::io;
+
+pub const MAGIC: [u8; 4] = *b"GBST";
+pub const VERSION: u16 = 1;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_crc: u32) -> Self {
+        let mut writer = Self { data: Vec::new() };
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.u32(rom_crc);
+        writer
+    }
+
+    // No save state header, for other files built from sections (movies)
+    pub fn headerless() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    // Write a section, with its length filled in once `write` is done
+    pub fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut Self)) {
+        self.bytes(tag);
+        let length_at = self.data.len();
+        self.u32(0);
+        write(self);
+        let length = (self.data.len() - length_at - 4) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, value: u128) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    // Fixed-size data; the reader must know the size
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn u16s(&mut self, values: &[u16]) {
+        for &value in values {
+            self.u16(value);
+        }
+    }
+
+    pub fn u32s(&mut self, values: &[u32]) {
+        for &value in values {
+            self.u32(value);
+        }
+    }
+
+    // Variable-size data, prefixed with its length
+    pub fn blob(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    // Check the header and return the ROM CRC it was saved with
+    pub fn header(&mut self) -> io::Result<u32> {
+        if self.take(4).ok() != Some(&MAGIC[..]) {
+            return Err(invalid("not a save state"));
+        }
+        let version = self.u16()?;
+        if version > VERSION {
+            return Err(invalid(&format!("save state version {} is newer than this emulator ({})", version, VERSION)));
+        }
+        self.u32()
+    }
+
+    // Next section's tag and a reader for its payload
+    pub fn section(&mut self) -> io::Result<Option<([u8; 4], StateReader<'a>)>> {
+        if self.at_end() {
+            return Ok(None);
+        }
+        let tag = self.take(4)?.try_into().unwrap();
+        let length = self.u32()? as usize;
+        Ok(Some((tag, StateReader::new(self.take(length)?))))
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let data = self.data.get(self.pos..self.pos + count).ok_or_else(|| invalid("save state is truncated"))?;
+        self.pos += count;
+        Ok(data)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn u16s(&mut self, out: &mut [u16]) -> io::Result<()> {
+        for value in out {
+            *value = self.u16()?;
+        }
+        Ok(())
+    }
+
+    pub fn u32s(&mut self, out: &mut [u32]) -> io::Result<()> {
+        for value in out {
+            *value = self.u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn blob(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+}
@@ -0,0 +1,163 @@
+// Serial port (link cable)
+//
+// 0xFF01: SB - byte to send, replaced by the received byte after a transfer
+// 0xFF02: SC - bit 7 starts a transfer / transfer in progress
+//              bit 1 fast clock (CGB only, 32x faster)
+//              bit 0 clock source (1 = internal, 0 = external)
+//
+// With the internal clock the Game Boy is the master: it shifts one bit every
+// 512 CPU cycles (8192 Hz), so a byte takes 8 x 512 cycles. With the external
+// clock the partner drives the transfer and the Game Boy waits for it. Either
+// way, the serial interrupt (IF bit 3) is requested when the byte is done.
+//
+// The port's input line is pulled up, so with no cable attached every
+// transfer receives 0xFF.
+
+use crate::savestate::{StateReader, StateWriter};
+use std::io;
+
+// CPU cycles per bit with the internal clock
+const CYCLES_PER_BIT: u32 = 512;
+const FAST_CYCLES_PER_BIT: u32 = 16;
+
+pub const SERIAL_INTERRUPT: u8 = 0x08;
+
+// Link partner plugged into the serial port. Send, so a Game Boy with a
+// device connected can still move to another thread.
+pub trait SerialDevice: Send {
+    // This side drives the clock: send `data` and return the partner's byte
+    fn transfer_master(&mut self, data: u8) -> u8;
+    // This side waits on the external clock with `data` ready to send. Returns the
+    // partner's byte once the partner has clocked a transfer, None while still waiting.
+    fn poll_slave(&mut self, data: u8) -> Option<u8>;
+    // This side just started driving the clock for `data`; transfer_master follows
+    // once the 8 bits are shifted out. `fast` is the CGB fast clock.
+    fn start_master(&mut self, _data: u8, _fast: bool) {}
+    // Called every CPU step with the elapsed CPU cycles, transfer or not
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    // CPU cycles until the current internal-clock transfer completes
+    cycles_left: u32,
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+// A device is plugged into one Game Boy only, so the clone's port is empty
+impl Clone for Serial {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data,
+            control: self.control,
+            cycles_left: self.cycles_left,
+            device: None,
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0,
+            control: 0,
+            cycles_left: 0,
+            device: None,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    pub fn read_control(&self, cgb_mode: bool) -> u8 {
+        if cgb_mode {
+            0x7C | self.control
+        } else {
+            0x7E | (self.control & 0x81)
+        }
+    }
+
+    pub fn write_control(&mut self, value: u8, cgb_mode: bool) {
+        self.control = value & if cgb_mode { 0x83 } else { 0x81 };
+        if self.transfer_pending() && self.internal_clock() {
+            let cycles_per_bit = if self.control & 0x02 != 0 { FAST_CYCLES_PER_BIT } else { CYCLES_PER_BIT };
+            self.cycles_left = 8 * cycles_per_bit;
+            if let Some(device) = &mut self.device {
+                device.start_master(self.data, self.control & 0x02 != 0);
+            }
+        }
+    }
+
+    // The connected device isn't part of the state: it stays plugged in
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u32(self.cycles_left);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.cycles_left = r.u32()?;
+        Ok(())
+    }
+
+    fn transfer_pending(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+
+    // Advance by `cycles` CPU cycles. Returns true when a transfer completed and
+    // the serial interrupt should be requested.
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        if let Some(device) = &mut self.device {
+            device.tick(cycles);
+        }
+        if !self.transfer_pending() {
+            return false;
+        }
+
+        let received = if self.internal_clock() {
+            self.cycles_left = self.cycles_left.saturating_sub(cycles);
+            if self.cycles_left > 0 {
+                return false;
+            }
+            match &mut self.device {
+                Some(device) => device.transfer_master(self.data),
+                None => 0xFF,
+            }
+        } else {
+            // Nobody drives the clock without a cable: wait forever
+            let Some(device) = &mut self.device else {
+                return false;
+            };
+            match device.poll_slave(self.data) {
+                Some(byte) => byte,
+                None => return false,
+            }
+        };
+
+        self.data = received;
+        self.control &= !0x80;
+        true
+    }
+}
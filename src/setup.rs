@@ -0,0 +1,189 @@
+// Setting up the machine from the command line options: model and colors,
+// the ROM, serial devices, the starting state and movies. Problems the
+// emulator can't start with exit the process.
+
+use crate::options::{usage_error, LinkMode, LinkProtocol, Options};
+use gb_emulator::bess;
+use gb_emulator::bgb::BgbLink;
+use gb_emulator::cartridge::CgbSupport;
+use gb_emulator::dmg07::{Dmg07, Dmg07Event, RemotePort};
+use gb_emulator::link::TcpLink;
+use gb_emulator::movie::{Movie, Player, Recorder};
+use gb_emulator::printer::{Printer, PrinterEvent};
+use gb_emulator::serial::SerialDevice;
+use gb_emulator::GameBoy;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Movie to play back. It's loaded first because it decides the model.
+pub fn load_movie(options: &Options) -> Option<Movie> {
+    options.play_movie.as_ref().map(|path| {
+        Movie::load(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load movie {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    })
+}
+
+// A machine with the model and colors asked for, and the ROM loaded
+pub fn build_gameboy(options: &Options, movie: Option<&Movie>) -> GameBoy {
+    let rom_path = &options.rom_path;
+    let mut gameboy = GameBoy::new();
+    // Movies replay on the hardware they were recorded on
+    if let Some(model) = movie.map(|movie| movie.model).or(options.model) {
+        gameboy.set_model(model);
+    }
+    if let Some(palette) = options.palette {
+        gameboy.set_palette_override(palette);
+    }
+    gameboy.set_color_correction(options.color_correction);
+    
+    // Load ROM file
+    if Path::new(rom_path).exists() {
+        match fs::read(rom_path) {
+            Ok(rom_data) => {
+                println!("Loading ROM from {}...", rom_path);
+                
+                // Check if this is a boot ROM (256 bytes) or a game ROM
+                if rom_data.len() == 256 {
+                    gameboy.load_boot_rom(&rom_data);
+                    println!("Boot ROM loaded successfully ({} bytes)", rom_data.len());
+                } else {
+                    let size = rom_data.len();
+                    let header = gameboy.load_cartridge(rom_data);
+                    println!("Game ROM loaded successfully ({} bytes)", size);
+                    
+                    if let Some(header) = header {
+                        println!("Title: {}", header.title_string());
+                        let memory = gameboy.memory();
+                        if memory.is_cgb_mode() {
+                            println!("Game Boy Color mode enabled");
+                        } else if memory.is_dmg_compatibility() {
+                            println!("DMG compatibility mode with CGB colorization");
+                        } else if header.cgb_support() == CgbSupport::Only {
+                            eprintln!("Warning: this game requires a Game Boy Color");
+                        } else if memory.sgb().is_some() && !header.supports_sgb() {
+                            eprintln!("Warning: this game doesn't use Super Game Boy functions");
+                        }
+                    }
+                }
+            }
+            Err(e) => usage_error(format!("Error reading ROM file {}: {}", rom_path, e)),
+        }
+    } else {
+        usage_error(format!("ROM file not found: {}", rom_path));
+    }
+    gameboy
+}
+
+// Plug in the printer, DMG-07 or link cable, whichever was asked for
+pub fn connect_serial(gameboy: &mut GameBoy, options: &Options) {
+    // Plug in the printer
+    if let Some(dir) = &options.printer {
+        let mut printer = Printer::new(dir);
+        printer.set_event_handler(|event| match event {
+            PrinterEvent::Saved(path) => println!("Printer: saved {}", path.display()),
+            PrinterEvent::SaveFailed(e) => eprintln!("Printer: failed to save image: {}", e),
+        });
+        gameboy.connect_serial(Box::new(printer));
+        println!("Game Boy Printer connected, saving prints to {}", dir);
+    }
+
+    // Plug in the Four Player Adapter
+    if let Some(mode) = &options.dmg07 {
+        let port: std::io::Result<Box<dyn SerialDevice>> = match mode {
+            LinkMode::Host(addr) => {
+                let adapter = Dmg07::new();
+                adapter.serve(addr.as_str(), |event| match event {
+                    Dmg07Event::Connected(player) => println!("DMG-07: player {} connected", player),
+                    Dmg07Event::Disconnected(player) => println!("DMG-07: player {} disconnected", player),
+                    Dmg07Event::ConnectFailed(player, e) => {
+                        eprintln!("DMG-07: failed to connect player {}: {}", player, e)
+                    }
+                    Dmg07Event::PortsFull => eprintln!("DMG-07: all ports in use"),
+                }).map(|_| {
+                    println!("DMG-07 adapter on {}, this instance is player 1", addr);
+                    Box::new(adapter.port(0)) as _
+                })
+            }
+            LinkMode::Connect(addr) => RemotePort::connect(addr.as_str()).map(|port| {
+                println!("Connected to DMG-07 adapter at {}", addr);
+                Box::new(port) as _
+            }),
+        };
+        match port {
+            Ok(port) => gameboy.connect_serial(port),
+            Err(e) => {
+                eprintln!("DMG-07 error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Plug in the link cable
+    if let Some(mode) = &options.link {
+        match mode {
+            LinkMode::Host(addr) => println!("Waiting for link partner on {}...", addr),
+            LinkMode::Connect(addr) => println!("Connecting to link partner at {}...", addr),
+        }
+        let link: std::io::Result<Box<dyn SerialDevice>> = match (mode, options.link_protocol) {
+            (LinkMode::Host(addr), LinkProtocol::Raw) => TcpLink::host(addr.as_str()).map(|l| Box::new(l) as _),
+            (LinkMode::Host(addr), LinkProtocol::Bgb) => BgbLink::host(addr.as_str()).map(|l| Box::new(l) as _),
+            (LinkMode::Connect(addr), LinkProtocol::Raw) => TcpLink::connect(addr.as_str()).map(|l| Box::new(l) as _),
+            (LinkMode::Connect(addr), LinkProtocol::Bgb) => BgbLink::connect(addr.as_str()).map(|l| Box::new(l) as _),
+        };
+        match link {
+            Ok(link) => {
+                gameboy.connect_serial(link);
+                println!("Link cable connected");
+            }
+            Err(e) => {
+                eprintln!("Link cable error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+pub fn load_start_state(gameboy: &mut GameBoy, options: &Options) {
+    // Start from a save state, ours or one from another emulator
+    if let Some(path) = &options.load_state {
+        let result = fs::read(path).and_then(|data| {
+            if bess::is_bess(&data) { gameboy.import_bess(&data) } else { gameboy.load_state(&data) }
+        });
+        match result {
+            Ok(()) => println!("State loaded from {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to load state from {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Start the movie to play back and the recording, after the machine is set up
+pub fn start_movies(
+    gameboy: &mut GameBoy,
+    options: &Options,
+    movie: Option<Movie>,
+) -> (Option<Player>, Option<(Recorder, PathBuf)>) {
+    let player = movie.map(|movie| {
+        Player::start(movie, gameboy).unwrap_or_else(|e| {
+            eprintln!("Can't play movie: {}", e);
+            std::process::exit(1);
+        })
+    });
+    let recorder = options.record_movie.as_ref().map(|path| {
+        let recorder = if options.load_state.is_some() {
+            Ok(Recorder::from_state(gameboy))
+        } else {
+            Recorder::power_on(gameboy)
+        };
+        let recorder = recorder.unwrap_or_else(|e| {
+            eprintln!("Can't record movie: {}", e);
+            std::process::exit(1);
+        });
+        (recorder, path.clone())
+    });
+    (player, recorder)
+}
@@ -0,0 +1,49 @@
+// Test ROMs shared by the unit tests and the libretro frontend tests
+
+// A 32 KB ROM that turns the LCD on, runs `setup` once, then runs `body` in
+// an endless loop
+pub fn looping_rom(setup: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut code = vec![
+        0x3E, 0x91, // LD A, 0x91
+        0xE0, 0x40, // LDH (LCDC), A
+    ];
+    code.extend_from_slice(setup);
+    code.extend_from_slice(body);
+    // JR back to the start of the body
+    code.extend_from_slice(&[0x18, (-(body.len() as i8 + 2)) as u8]);
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+    rom
+}
+
+// Keeps incrementing 0xC000 and copying it into the first row of tile 0, so
+// RAM, VRAM and the picture all change
+pub fn counter_rom() -> Vec<u8> {
+    looping_rom(
+        &[0x21, 0x00, 0xC0], // LD HL, 0xC000
+        &[
+            0x34, // loop: INC (HL)
+            0x7E, // LD A, (HL)
+            0xEA, 0x00, 0x80, // LD (0x8000), A
+        ],
+    )
+}
+
+// Keeps copying the direction keys into 0xC000 and the button keys into the
+// first row of tile 0, which the whole background is made of
+pub fn joypad_rom() -> Vec<u8> {
+    looping_rom(
+        &[],
+        &[
+            0x3E, 0x20, // loop: LD A, 0x20: select the direction keys
+            0xE0, 0x00, // LDH (P1), A
+            0xF0, 0x00, // LDH A, (P1)
+            0xEA, 0x00, 0xC0, // LD (0xC000), A
+            0x3E, 0x10, // LD A, 0x10: select the button keys
+            0xE0, 0x00, // LDH (P1), A
+            0xF0, 0x00, // LDH A, (P1)
+            0xEA, 0x00, 0x80, // LD (0x8000), A
+        ],
+    )
+}
@@ -1,4 +1,5 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::flags::FlagsRegister;
     #[test]
@@ -297,3 +298,2856 @@ mod cpu_tests {
         assert_eq!(cpu.get_pc(), 0x0106);
     }
 }
+
+#[cfg(test)]
+mod pacing_tests {
+    use crate::pacing::{frame_duration, FramePacer, Speed, SpeedMeter};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_frame_duration_matches_dmg_refresh_rate() {
+        // 70224 cycles at 4.194304 MHz = 16.742706 ms (~59.73 Hz)
+        assert_eq!(frame_duration(), Duration::from_nanos(16_742_706));
+    }
+
+    #[test]
+    fn test_wait_sleeps_until_the_frame_deadline() {
+        let mut pacer = FramePacer::new();
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= frame_duration() - Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_speed_from_multiplier_and_display() {
+        assert_eq!(Speed::from_multiplier(4.0), Some(Speed::Scaled(4.0)));
+        assert_eq!(Speed::from_multiplier(0.0), Some(Speed::Uncapped));
+        assert_eq!(Speed::from_multiplier(-2.0), None);
+        assert_eq!(Speed::from_multiplier(f64::NAN), None);
+        assert_eq!(Speed::from_multiplier(f64::INFINITY), None);
+        assert_eq!(Speed::Scaled(0.25).to_string(), "0.25x");
+        assert_eq!(Speed::NORMAL.to_string(), "1x");
+        assert_eq!(Speed::Uncapped.to_string(), "uncapped");
+    }
+
+    #[test]
+    fn test_speed_changes_frame_deadlines() {
+        let mut pacer = FramePacer::new();
+
+        // Uncapped never waits
+        pacer.set_speed(Speed::Uncapped);
+        let start = Instant::now();
+        for _ in 0..100 {
+            pacer.wait();
+        }
+        assert!(start.elapsed() < frame_duration());
+
+        pacer.set_speed(Speed::Scaled(0.5));
+        assert_eq!(pacer.speed(), Speed::Scaled(0.5));
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= frame_duration() * 2 - Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_speed_meter_measures_after_a_window() {
+        let mut meter = SpeedMeter::new();
+        assert!(!meter.frame());
+        assert_eq!(meter.speed(), None);
+
+        std::thread::sleep(Duration::from_millis(1000));
+        assert!(meter.frame());
+        // Two frames in a little over a second
+        let speed = meter.speed().unwrap();
+        assert!(speed > 0.0 && speed < 2.0 / 59.0);
+
+        meter.reset();
+        assert_eq!(meter.speed(), None);
+    }
+}
+
+#[cfg(test)]
+mod cgb_tests {
+    use crate::cartridge::{CartridgeHeader, CgbSupport};
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+
+    fn cgb_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(code);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0143] = 0x80;
+        rom
+    }
+
+    fn cgb_memory() -> Memory {
+        let mut memory = Memory::new();
+        memory.load_rom(cgb_rom(&[]));
+        memory
+    }
+
+    #[test]
+    fn test_header_cgb_flag() {
+        let mut rom = cgb_rom(&[]);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.cgb_support(), CgbSupport::Enhanced);
+        assert_eq!(header.title_string(), "TEST");
+
+        rom[0x0143] = 0xC0;
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().cgb_support(), CgbSupport::Only);
+
+        rom[0x0143] = 0x00;
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().cgb_support(), CgbSupport::None);
+
+        assert!(CartridgeHeader::parse(&[0; 0x100]).is_none());
+    }
+
+    #[test]
+    fn test_dmg_rom_ignores_cgb_registers() {
+        let mut memory = Memory::new();
+        memory.load_rom(vec![0; 0x8000]);
+        assert!(!memory.is_cgb_mode());
+
+        memory.write(0x8000, 0x11);
+        memory.write(0xFF4F, 0x01);
+        assert_eq!(memory.read(0xFF4F), 0xFF);
+        assert_eq!(memory.read(0x8000), 0x11);
+    }
+
+    #[test]
+    fn test_vram_banking() {
+        let mut memory = cgb_memory();
+        memory.write(0x8000, 0xAA);
+        memory.write(0xFF4F, 0x01);
+        assert_eq!(memory.read(0xFF4F), 0xFF);
+        assert_eq!(memory.read(0x8000), 0x00);
+        memory.write(0x8000, 0xBB);
+
+        memory.write(0xFF4F, 0x00);
+        assert_eq!(memory.read(0xFF4F), 0xFE);
+        assert_eq!(memory.read(0x8000), 0xAA);
+        assert_eq!(memory.read_vram(1, 0x8000), 0xBB);
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut memory = cgb_memory();
+        assert_eq!(memory.read(0xFF70) & 0x07, 1);
+
+        memory.write(0xD000, 0x01);
+        memory.write(0xFF70, 0x07);
+        assert_eq!(memory.read(0xD000), 0x00);
+        memory.write(0xD000, 0x07);
+
+        // Bank 0 selects bank 1
+        memory.write(0xFF70, 0x00);
+        assert_eq!(memory.read(0xFF70) & 0x07, 1);
+        assert_eq!(memory.read(0xD000), 0x01);
+
+        // Bank 0 area and echo RAM are unaffected by SVBK
+        memory.write(0xC000, 0x42);
+        memory.write(0xFF70, 0x07);
+        assert_eq!(memory.read(0xC000), 0x42);
+        assert_eq!(memory.read(0xE000), 0x42);
+        assert_eq!(memory.read(0xF000), 0x07);
+    }
+
+    #[test]
+    fn test_cgb_rom_sets_a_register() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(cgb_rom(&[
+            0xFE, 0x11, // CP $11
+            0x28, 0x02, // JR Z, +2
+        ]));
+        cpu.step();
+        cpu.step();
+        assert!(cpu.get_memory().is_cgb_mode());
+        assert_eq!(cpu.get_pc(), 0x0106);
+    }
+
+    #[test]
+    fn test_stop_switches_to_double_speed() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(cgb_rom(&[
+            0x3E, 0x01,       // LD A, $01
+            0xE0, 0x4D,       // LDH ($4D), A - arm speed switch
+            0x10, 0x00,       // STOP
+        ]));
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.get_memory().read(0xFF4D), 0x7F);
+
+        cpu.step();
+        assert!(cpu.get_memory().is_double_speed());
+        assert_eq!(cpu.get_memory().read(0xFF4D), 0xFE);
+
+        // CPU is stalled for 2050 M-cycles while the clock settles
+        let stalled_pc = cpu.get_pc();
+        let start = cpu.get_ticks();
+        while cpu.get_pc() == stalled_pc {
+            cpu.step();
+        }
+        assert_eq!(cpu.get_ticks() - start, 2050 * 2 + 2); // + the NOP after STOP
+    }
+
+    #[test]
+    fn test_double_speed_halves_cycle_cost() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(cgb_rom(&[
+            0x3E, 0x01,       // LD A, $01
+            0xE0, 0x4D,       // LDH ($4D), A
+            0x10, 0x00,       // STOP
+            0x00,             // NOP
+            0x3E, 0x42,       // LD A, $42
+        ]));
+        cpu.step();
+        cpu.step();
+        cpu.step();
+
+        // Run through the speed switch stall and the NOP
+        let mut cycles = 0;
+        while cpu.get_pc() == 0x0106 {
+            cycles = cpu.step();
+        }
+        assert_eq!(cycles, 2);     // NOP: 4 CPU cycles, 2 system cycles
+        assert_eq!(cpu.step(), 4); // LD A, d8: 8 CPU cycles, 4 system cycles
+    }
+}
+
+#[cfg(test)]
+mod cgb_palette_tests {
+    use crate::gpu::{rgb555_to_u32, GPU, SCREEN_WIDTH};
+    use crate::memory::Memory;
+
+    fn cgb_memory() -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut memory = Memory::new();
+        memory.load_rom(rom);
+        memory
+    }
+
+    fn render_frame(gpu: &mut GPU, memory: &mut Memory) {
+        for _ in 0..70224 / 4 {
+            gpu.step(4, memory);
+        }
+    }
+
+    // Write a tile whose every row uses the same two bit planes
+    fn write_tile(memory: &mut Memory, addr: u16, low: u8, high: u8) {
+        for row in 0..8 {
+            memory.write(addr + row * 2, low);
+            memory.write(addr + row * 2 + 1, high);
+        }
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF68, 0x80 | 0x08); // palette 1, color 0, auto-increment
+        memory.write(0xFF69, 0x1F);
+        memory.write(0xFF69, 0x00);
+        memory.write(0xFF69, 0xE0);
+        memory.write(0xFF69, 0x03);
+        assert_eq!(memory.read(0xFF68), 0xC0 | 0x0C);
+        assert_eq!(memory.bg_palette_color(1, 0), 0x001F);
+        assert_eq!(memory.bg_palette_color(1, 1), 0x03E0);
+
+        // Without auto-increment the index stays put
+        memory.write(0xFF6A, 0x3F);
+        memory.write(0xFF6B, 0x12);
+        memory.write(0xFF6B, 0x7C);
+        assert_eq!(memory.read(0xFF6A), 0x7F);
+        assert_eq!(memory.read(0xFF6B), 0x7C);
+        assert_eq!(memory.obj_palette_color(7, 3), 0x7CFF);
+    }
+
+    #[test]
+    fn test_index_wraps_after_last_palette() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF68, 0x80 | 0x3F);
+        memory.write(0xFF69, 0x00);
+        assert_eq!(memory.read(0xFF68) & 0x3F, 0x00);
+    }
+
+    #[test]
+    fn test_rgb555_conversion() {
+        assert_eq!(rgb555_to_u32(0x0000, false), 0x000000);
+        assert_eq!(rgb555_to_u32(0x7FFF, false), 0xFFFFFF);
+        assert_eq!(rgb555_to_u32(0x001F, false), 0xFF0000);
+        assert_eq!(rgb555_to_u32(0x03E0, false), 0x00FF00);
+        assert_eq!(rgb555_to_u32(0x7C00, false), 0x0000FF);
+
+        // Correction keeps white white but desaturates pure primaries
+        assert_eq!(rgb555_to_u32(0x7FFF, true), 0xF0F0F0);
+        let red = rgb555_to_u32(0x001F, true);
+        assert!(red & 0xFF > 0 && (red >> 16) < 0xFF);
+    }
+
+    #[test]
+    fn test_bg_attributes_select_palette_bank_and_flip() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF40, 0x91); // LCD on, BG on, tile data at 0x8000
+
+        // Tile 0 in bank 1: left pixel color 3, the rest color 0
+        memory.write(0xFF4F, 0x01);
+        write_tile(&mut memory, 0x8000, 0x80, 0x80);
+        // Attribute for map entry (0, 0): palette 2, bank 1, X flip
+        memory.write(0x9800, 0x02 | 0x08 | 0x20);
+        memory.write(0xFF4F, 0x00);
+
+        // Palette 2: color 0 = black, color 3 = blue
+        memory.write(0xFF68, 0x80 | 0x10);
+        for byte in [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7C] {
+            memory.write(0xFF69, byte);
+        }
+
+        let mut gpu = GPU::new();
+        render_frame(&mut gpu, &mut memory);
+
+        // X flip moves the colored pixel to the right edge of the tile
+        assert_eq!(gpu.framebuffer[0], 0x000000);
+        assert_eq!(gpu.framebuffer[7], 0x0000FF);
+        assert_eq!(gpu.framebuffer[SCREEN_WIDTH + 7], 0x0000FF);
+    }
+
+    #[test]
+    fn test_sprite_uses_obj_palette_and_bg_priority() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF40, 0x93); // LCD on, OBJ on, BG on, tile data at 0x8000
+
+        // BG tile 0 is color 0 except the left column (color 1); sprite tile 1 is solid color 3
+        write_tile(&mut memory, 0x8000, 0x80, 0x00);
+        write_tile(&mut memory, 0x8010, 0xFF, 0xFF);
+
+        // OBJ palette 1, color 3 = green
+        memory.write(0xFF6A, 0x80 | 0x0E);
+        memory.write(0xFF6B, 0xE0);
+        memory.write(0xFF6B, 0x03);
+
+        // Sprite 0 at screen (0, 0), tile 1, palette 1, behind non-zero BG colors
+        memory.write(0xFE00, 16);
+        memory.write(0xFE01, 8);
+        memory.write(0xFE02, 1);
+        memory.write(0xFE03, 0x80 | 0x01);
+
+        let mut gpu = GPU::new();
+        render_frame(&mut gpu, &mut memory);
+
+        let bg_color_1 = rgb555_to_u32(memory.bg_palette_color(0, 1), false);
+        assert_eq!(gpu.framebuffer[0], bg_color_1);
+        assert_eq!(gpu.framebuffer[1], 0x00FF00);
+    }
+}
+
+#[cfg(test)]
+mod hdma_tests {
+    use crate::cpu::CPU;
+    use crate::gpu::GPU;
+    use crate::memory::Memory;
+
+    fn cgb_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(code);
+        rom[0x0143] = 0x80;
+        // Source data for the transfers at 0x4000
+        for (i, byte) in rom[0x4000..0x4100].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        rom
+    }
+
+    fn setup_transfer(memory: &mut Memory, length_and_mode: u8) {
+        memory.write(0xFF51, 0x40);
+        memory.write(0xFF52, 0x00);
+        memory.write(0xFF53, 0x81);
+        memory.write(0xFF54, 0x00);
+        memory.write(0xFF55, length_and_mode);
+    }
+
+    #[test]
+    fn test_general_purpose_dma_copies_in_one_burst() {
+        let mut memory = Memory::new();
+        memory.load_rom(cgb_rom(&[]));
+        setup_transfer(&mut memory, 0x03); // 4 blocks
+
+        for i in 0..64u16 {
+            assert_eq!(memory.read(0x8100 + i), i as u8);
+        }
+        assert_eq!(memory.read(0x8140), 0x00);
+        assert_eq!(memory.read(0xFF55), 0xFF);
+        assert_eq!(memory.take_dma_stall(), 4 * 32);
+        assert_eq!(memory.take_dma_stall(), 0);
+    }
+
+    #[test]
+    fn test_general_purpose_dma_stalls_cpu() {
+        let mut cpu = CPU::new();
+        cpu.load_rom(cgb_rom(&[
+            0x3E, 0x40, 0xE0, 0x51, // LD A, $40; LDH ($51), A
+            0xAF, 0xE0, 0x52,       // XOR A; LDH ($52), A
+            0x3E, 0x01, 0xE0, 0x53, // LD A, $01; LDH ($53), A
+            0xAF, 0xE0, 0x54,       // XOR A; LDH ($54), A
+            0x3E, 0x01, 0xE0, 0x55, // LD A, $01; LDH ($55), A - 2 blocks, general purpose
+            0x00,                   // NOP
+        ]));
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.get_memory().read(0x811F), 0x1F);
+
+        // The CPU doesn't fetch the NOP until the 64-cycle transfer is over
+        let start = cpu.get_ticks();
+        cpu.step();
+        assert_eq!(cpu.get_pc(), 0x0112);
+        while cpu.get_pc() == 0x0112 {
+            cpu.step();
+        }
+        assert_eq!(cpu.get_ticks() - start, 64 + 4);
+    }
+
+    #[test]
+    fn test_hblank_dma_copies_one_block_per_hblank() {
+        let mut memory = Memory::new();
+        memory.load_rom(cgb_rom(&[]));
+        setup_transfer(&mut memory, 0x80 | 0x02); // 3 blocks, HBlank mode
+        assert_eq!(memory.read(0xFF55), 0x02);
+        assert_eq!(memory.read(0x8100), 0x00);
+
+        let mut gpu = GPU::new();
+        // Run into the first line's HBlank
+        for _ in 0..(80 + 172) / 4 {
+            gpu.step(4, &mut memory);
+        }
+        assert_eq!(memory.read(0x810F), 0x0F);
+        assert_eq!(memory.read(0x8110), 0x00);
+        assert_eq!(memory.read(0xFF55), 0x01);
+
+        // Two more lines finish the transfer
+        for _ in 0..(2 * 456) / 4 {
+            gpu.step(4, &mut memory);
+        }
+        assert_eq!(memory.read(0x812F), 0x2F);
+        assert_eq!(memory.read(0xFF55), 0xFF);
+        assert_eq!(memory.read(0xFF44), 2);
+    }
+
+    #[test]
+    fn test_hblank_dma_cancel() {
+        let mut memory = Memory::new();
+        memory.load_rom(cgb_rom(&[]));
+        setup_transfer(&mut memory, 0x80 | 0x07);
+        memory.hblank();
+        memory.hblank();
+
+        memory.write(0xFF55, 0x00);
+        assert_eq!(memory.read(0xFF55), 0x80 | 0x05);
+        memory.hblank();
+        assert_eq!(memory.read(0x8120), 0x00);
+        assert_eq!(memory.read(0x811F), 0x1F);
+    }
+
+    #[test]
+    fn test_gpu_updates_ly_and_stat_mode() {
+        let mut memory = Memory::new();
+        let mut gpu = GPU::new();
+        for _ in 0..84 / 4 {
+            gpu.step(4, &mut memory);
+        }
+        assert_eq!(memory.read(0xFF41) & 0x03, 3);
+        for _ in 0..(456 * 144 - 84) / 4 {
+            gpu.step(4, &mut memory);
+        }
+        assert_eq!(memory.read(0xFF44), 144);
+        assert_eq!(memory.read(0xFF41) & 0x03, 1);
+    }
+}
+
+#[cfg(test)]
+mod colorization_tests {
+    use crate::cartridge::CartridgeHeader;
+    use crate::colorization::{palette_for_header, rgb888_to_rgb555, ButtonPalette, DEFAULT_PALETTE};
+    use crate::gpu::{rgb555_to_u32, GPU};
+    use crate::memory::{Memory, Model};
+
+    fn dmg_rom(title: &[u8], old_licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x014B] = old_licensee;
+        rom
+    }
+
+    #[test]
+    fn test_title_checksum_lookup() {
+        let header = CartridgeHeader::parse(&dmg_rom(b"TETRIS", 0x01)).unwrap();
+        assert_eq!(header.title_checksum(), 0xDB);
+        let palette = palette_for_header(&header);
+        assert_ne!(palette, DEFAULT_PALETTE);
+        assert_eq!(palette.bg[1], 0xFFFF00);
+    }
+
+    #[test]
+    fn test_non_nintendo_titles_get_default_palette() {
+        let header = CartridgeHeader::parse(&dmg_rom(b"TETRIS", 0x08)).unwrap();
+        assert_eq!(palette_for_header(&header), DEFAULT_PALETTE);
+
+        // New licensee code "01" also counts as Nintendo
+        let mut rom = dmg_rom(b"TETRIS", 0x33);
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_ne!(palette_for_header(&header), DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_fourth_letter_disambiguates_shared_checksums() {
+        let blue = CartridgeHeader::parse(&dmg_rom(b"POKEMON BLUE", 0x01)).unwrap();
+        assert_eq!(blue.title_checksum(), 0x61);
+        assert_eq!(palette_for_header(&blue).bg[2], 0x0000FF);
+
+        // Same checksum, 4th letter from the second row of shared entries
+        let vegas = CartridgeHeader::parse(&dmg_rom(b"VEGAS STAKES", 0x01)).unwrap();
+        assert_eq!(vegas.title_checksum(), 0x61);
+        assert_eq!(palette_for_header(&vegas).bg, [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+
+        // Same checksum, but a 4th letter no entry has
+        let other = CartridgeHeader::parse(&dmg_rom(b"POKFMON BLUD", 0x01)).unwrap();
+        assert_eq!(other.title_checksum(), 0x61);
+        assert_eq!(palette_for_header(&other), DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_boot_rom_table_spot_checks() {
+        let palette = |title: &[u8]| palette_for_header(&CartridgeHeader::parse(&dmg_rom(title, 0x01)).unwrap());
+        const BROWN: [u32; 4] = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+        const RED: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+
+        let yakuman = palette(b"YAKUMAN");
+        assert_eq!((yakuman.bg, yakuman.obj0, yakuman.obj1), (BROWN, BROWN, BROWN));
+
+        // Shared checksum 0xBF: KID ICARUS has a space as its 4th letter
+        let icarus = palette(b"KID ICARUS");
+        assert_eq!((icarus.bg, icarus.obj0), ([0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000], RED));
+        let soccer = palette(b"SOCCER");
+        assert_eq!(soccer.bg, [0x6BFF00, 0xFFFFFF, 0xFF524A, 0x000000]);
+        // Starts on the last color of one palette and runs into the next
+        assert_eq!(soccer.obj0, [0xFFFFFF, 0xFFFFFF, 0x63A5FF, 0x0000FF]);
+        assert_eq!(soccer.obj1, BROWN);
+
+        let mario = palette(b"SUPER MARIOLAND");
+        assert_eq!(mario.bg, [0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000]);
+        assert_eq!(mario.obj0, [0x000000, 0xFFFFFF, 0xFF8484, 0x943A3A]);
+
+        let zelda = palette(b"ZELDA");
+        assert_eq!((zelda.bg, zelda.obj0), (RED, [0xFFFFFF, 0x00FF00, 0x318400, 0x004A00]));
+    }
+
+    #[test]
+    fn test_button_palette_names() {
+        assert_eq!(ButtonPalette::ALL.len(), 12);
+        for combo in ButtonPalette::ALL {
+            assert_eq!(ButtonPalette::from_name(combo.name()), Some(combo));
+        }
+        assert_eq!(ButtonPalette::from_name("Left+B"), Some(ButtonPalette::LeftB));
+        assert_eq!(ButtonPalette::from_name("a+b"), None);
+        assert_eq!(ButtonPalette::RightA.palette(), DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_rgb888_to_rgb555() {
+        assert_eq!(rgb888_to_rgb555(0xFFFFFF), 0x7FFF);
+        assert_eq!(rgb888_to_rgb555(0xFF0000), 0x001F);
+        assert_eq!(rgb888_to_rgb555(0x0000FF), 0x7C00);
+    }
+
+    #[test]
+    fn test_model_selection() {
+        let mut memory = Memory::new();
+        memory.load_rom(dmg_rom(b"TETRIS", 0x01));
+        assert_eq!(memory.model(), Model::Dmg);
+        assert!(!memory.is_dmg_compatibility());
+
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        memory.load_rom(dmg_rom(b"TETRIS", 0x01));
+        assert!(!memory.is_cgb_mode());
+        assert!(memory.is_dmg_compatibility());
+        // CGB registers stay locked in compatibility mode
+        assert_eq!(memory.read(0xFF4F), 0xFF);
+
+        let mut rom = dmg_rom(b"COLOR", 0x01);
+        rom[0x0143] = 0x80;
+        let mut memory = Memory::new();
+        memory.set_model(Model::Dmg);
+        memory.load_rom(rom);
+        assert!(!memory.is_cgb_mode());
+    }
+
+    #[test]
+    fn test_auto_model_follows_each_loaded_rom() {
+        let mut cgb_rom = dmg_rom(b"COLOR", 0x01);
+        cgb_rom[0x0143] = 0x80;
+
+        let mut memory = Memory::new();
+        memory.load_rom(cgb_rom.clone());
+        assert_eq!(memory.model(), Model::Cgb);
+        memory.load_rom(dmg_rom(b"TETRIS", 0x01));
+        assert_eq!(memory.model(), Model::Dmg);
+        memory.load_rom(cgb_rom.clone());
+        assert_eq!(memory.model(), Model::Cgb);
+        assert!(memory.is_cgb_mode());
+
+        // A forced model survives loading another ROM
+        memory.set_model(Model::Dmg);
+        memory.load_rom(cgb_rom);
+        assert_eq!(memory.model(), Model::Dmg);
+        assert!(!memory.is_cgb_mode());
+    }
+
+    #[test]
+    fn test_compatibility_mode_maps_bgp_through_palette_ram() {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Cgb);
+        memory.load_rom(dmg_rom(b"TETRIS", 0x01));
+        let palette = ButtonPalette::DownA.palette();
+        memory.load_compat_palette(&palette);
+
+        // Tile 0 is solid color 1; BGP maps color 1 to shade 2
+        for row in 0..8 {
+            memory.write(0x8000 + row * 2, 0xFF);
+        }
+        memory.write(0xFF40, 0x91);
+        memory.write(0xFF47, 0b0000_1000);
+
+        let mut gpu = GPU::new();
+        for _ in 0..70224 / 4 {
+            gpu.step(4, &mut memory);
+        }
+        let expected = rgb555_to_u32(rgb888_to_rgb555(palette.bg[2]), false);
+        assert_eq!(gpu.framebuffer[0], expected);
+    }
+}
+
+#[cfg(test)]
+mod sgb_tests {
+    use crate::gpu::{rgb555_to_u32, SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::memory::{Memory, Model};
+    use crate::sgb::{SGB_HEIGHT, SGB_WIDTH};
+
+    fn sgb_memory() -> Memory {
+        let mut memory = Memory::new();
+        memory.set_model(Model::Sgb);
+        memory.load_rom(vec![0; 0x8000]);
+        memory
+    }
+
+    // Bit-bang packets through the joypad register like a game does
+    fn send_packets(memory: &mut Memory, data: &[u8]) {
+        for packet in data.chunks(16) {
+            memory.write(0xFF00, 0x00);
+            memory.write(0xFF00, 0x30);
+            for i in 0..128 {
+                let bit = (packet[i / 8] >> (i % 8)) & 1;
+                memory.write(0xFF00, if bit == 1 { 0x10 } else { 0x20 });
+                memory.write(0xFF00, 0x30);
+            }
+            memory.write(0xFF00, 0x20);
+            memory.write(0xFF00, 0x30);
+        }
+    }
+
+    fn packet(command: u8, length: u8, payload: &[u8]) -> [u8; 16] {
+        let mut packet = [0; 16];
+        packet[0] = (command << 3) | length;
+        packet[1..1 + payload.len()].copy_from_slice(payload);
+        packet
+    }
+
+    fn render(memory: &mut Memory, shades: &[u8]) -> Vec<u32> {
+        let mut output = vec![0; SGB_WIDTH * SGB_HEIGHT];
+        memory.sgb_mut().unwrap().render(shades, &mut output);
+        output
+    }
+
+    // Output pixel of Game Boy screen coordinates
+    fn screen_pixel(output: &[u32], x: usize, y: usize) -> u32 {
+        output[(40 + y) * SGB_WIDTH + 48 + x]
+    }
+
+    const RED: u16 = 0x001F;
+    const GREEN: u16 = 0x03E0;
+    const BLUE: u16 = 0x7C00;
+    const WHITE: u16 = 0x7FFF;
+
+    fn set_palettes_01(memory: &mut Memory) {
+        // Color 0 white, palette 0 colors 1-3 red, palette 1 colors 1-3 blue
+        let mut payload = Vec::new();
+        for color in [WHITE, RED, RED, RED, BLUE, BLUE, BLUE] {
+            payload.extend_from_slice(&color.to_le_bytes());
+        }
+        send_packets(memory, &packet(0x00, 1, &payload));
+    }
+
+    #[test]
+    fn test_pal01_colorizes_screen() {
+        let mut memory = sgb_memory();
+        set_palettes_01(&mut memory);
+
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[1] = 3;
+        let output = render(&mut memory, &shades);
+        assert_eq!(screen_pixel(&output, 0, 0), rgb555_to_u32(WHITE, false));
+        assert_eq!(screen_pixel(&output, 1, 0), rgb555_to_u32(RED, false));
+        // No border loaded: backdrop is color 0
+        assert_eq!(output[0], rgb555_to_u32(WHITE, false));
+    }
+
+    #[test]
+    fn test_attr_blk_assigns_palettes_to_cells() {
+        let mut memory = sgb_memory();
+        set_palettes_01(&mut memory);
+        // One data set: inside only, palette 1, cells (2,2)-(4,4)
+        send_packets(&mut memory, &packet(0x04, 1, &[1, 0x01, 0x01, 2, 2, 4, 4]));
+
+        let shades = vec![1; SCREEN_WIDTH * SCREEN_HEIGHT];
+        let output = render(&mut memory, &shades);
+        assert_eq!(screen_pixel(&output, 3 * 8, 3 * 8), rgb555_to_u32(BLUE, false));
+        // Inside-only also colors the border of the block
+        assert_eq!(screen_pixel(&output, 2 * 8, 2 * 8), rgb555_to_u32(BLUE, false));
+        assert_eq!(screen_pixel(&output, 5 * 8, 3 * 8), rgb555_to_u32(RED, false));
+    }
+
+    #[test]
+    fn test_attr_lin_and_div() {
+        let mut memory = sgb_memory();
+        set_palettes_01(&mut memory);
+        let shades = vec![1; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+        // Horizontal division at row 9: palette 1 above, palette 0 on the line and below
+        send_packets(&mut memory, &packet(0x06, 1, &[0x40 | (1 << 2), 9]));
+        let output = render(&mut memory, &shades);
+        assert_eq!(screen_pixel(&output, 0, 8 * 8), rgb555_to_u32(BLUE, false));
+        assert_eq!(screen_pixel(&output, 0, 9 * 8), rgb555_to_u32(RED, false));
+
+        // Vertical line at column 3 with palette 1
+        send_packets(&mut memory, &packet(0x05, 1, &[1, (1 << 5) | 3]));
+        let output = render(&mut memory, &shades);
+        assert_eq!(screen_pixel(&output, 3 * 8, 17 * 8), rgb555_to_u32(BLUE, false));
+        assert_eq!(screen_pixel(&output, 4 * 8, 17 * 8), rgb555_to_u32(RED, false));
+    }
+
+    #[test]
+    fn test_attr_chr_spans_packets() {
+        let mut memory = sgb_memory();
+        set_palettes_01(&mut memory);
+
+        // 60 cells (3 rows) starting at (0, 5), alternating palettes 0 and 1.
+        // 15 data bytes don't fit in the first packet.
+        let mut data = vec![0u8; 32];
+        data[0] = (0x07 << 3) | 2;
+        data[1] = 0;
+        data[2] = 5;
+        data[3] = 60;
+        data[5] = 0;
+        for byte in data[6..21].iter_mut() {
+            *byte = 0b0001_0001;
+        }
+        send_packets(&mut memory, &data);
+
+        let shades = vec![1; SCREEN_WIDTH * SCREEN_HEIGHT];
+        let output = render(&mut memory, &shades);
+        assert_eq!(screen_pixel(&output, 0, 5 * 8), rgb555_to_u32(RED, false));
+        assert_eq!(screen_pixel(&output, 8, 5 * 8), rgb555_to_u32(BLUE, false));
+        assert_eq!(screen_pixel(&output, 19 * 8, 7 * 8), rgb555_to_u32(BLUE, false));
+        assert_eq!(screen_pixel(&output, 8, 8 * 8), rgb555_to_u32(RED, false));
+    }
+
+    #[test]
+    fn test_mask_en_black() {
+        let mut memory = sgb_memory();
+        set_palettes_01(&mut memory);
+        send_packets(&mut memory, &packet(0x17, 1, &[2]));
+        let output = render(&mut memory, &vec![1; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(screen_pixel(&output, 10, 10), 0x000000);
+
+        send_packets(&mut memory, &packet(0x17, 1, &[0]));
+        let output = render(&mut memory, &vec![1; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(screen_pixel(&output, 10, 10), rgb555_to_u32(RED, false));
+    }
+
+    #[test]
+    fn test_mlt_req_cycles_controllers() {
+        let mut memory = sgb_memory();
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x0F);
+
+        send_packets(&mut memory, &packet(0x11, 1, &[0x01]));
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x0F);
+
+        // Reading the buttons and releasing the lines switches to player 2
+        memory.write(0xFF00, 0x10);
+        memory.write(0xFF00, 0x30);
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x0E);
+        memory.write(0xFF00, 0x10);
+        memory.write(0xFF00, 0x30);
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn test_pal_trn_and_pal_set() {
+        let mut memory = sgb_memory();
+        // Screen shows tiles 0-255 in order with unsigned tile data
+        memory.write(0xFF40, 0x91);
+        for i in 0..256u16 {
+            memory.write(0x9800 + (i / 20) * 32 + i % 20, i as u8);
+        }
+        // System palette 2 starts at byte 16 of the transfer: colors green
+        for color in 0..4u16 {
+            let bytes = GREEN.to_le_bytes();
+            memory.write(0x8000 + 16 + color * 2, bytes[0]);
+            memory.write(0x8000 + 16 + color * 2 + 1, bytes[1]);
+        }
+        send_packets(&mut memory, &packet(0x0B, 1, &[]));
+        // PAL_SET: palette 0 = system palette 2
+        send_packets(&mut memory, &packet(0x0A, 1, &[2, 0, 0, 0, 0, 0, 0, 0]));
+
+        let output = render(&mut memory, &vec![3; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(screen_pixel(&output, 0, 0), rgb555_to_u32(GREEN, false));
+    }
+
+    #[test]
+    fn test_border_from_chr_and_pct_trn() {
+        let mut memory = sgb_memory();
+        memory.write(0xFF40, 0x91);
+        for i in 0..256u16 {
+            memory.write(0x9800 + (i / 20) * 32 + i % 20, i as u8);
+        }
+
+        // CHR_TRN: border tile 0 solid color 1 (bit plane 0 set)
+        for row in 0..8 {
+            memory.write(0x8000 + row * 2, 0xFF);
+        }
+        send_packets(&mut memory, &packet(0x13, 1, &[0]));
+
+        // PCT_TRN: map entries all tile 0 with palette 4, palette 4 color 1 = green
+        for addr in 0x8000..0x9000u16 {
+            memory.write(addr, 0);
+        }
+        for entry in 0..32 * 28u16 {
+            memory.write(0x8000 + entry * 2 + 1, 4 << 2);
+        }
+        let bytes = GREEN.to_le_bytes();
+        memory.write(0x8800 + 2, bytes[0]);
+        memory.write(0x8800 + 3, bytes[1]);
+        send_packets(&mut memory, &packet(0x14, 1, &[]));
+
+        let output = render(&mut memory, &vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(output[0], rgb555_to_u32(GREEN, false));
+        assert_eq!(output[SGB_WIDTH * SGB_HEIGHT - 1], rgb555_to_u32(GREEN, false));
+    }
+
+    #[test]
+    fn test_joypad_reads_buttons() {
+        use crate::input::Input;
+        let mut memory = sgb_memory();
+        let input = Input { a: true, down: true, ..Input::default() };
+        memory.set_joypad(input);
+        memory.write(0xFF00, 0x10); // select buttons
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x0E);
+        memory.write(0xFF00, 0x20); // select directions
+        assert_eq!(memory.read(0xFF00) & 0x0F, 0x07);
+    }
+}
+
+#[cfg(test)]
+mod serial_tests {
+    use crate::memory::Memory;
+    use crate::serial::SerialDevice;
+    use std::sync::{Arc, Mutex};
+
+    // Partner that answers with a fixed byte and records what it received
+    struct EchoPartner {
+        reply: u8,
+        ready: bool,
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl SerialDevice for EchoPartner {
+        fn transfer_master(&mut self, data: u8) -> u8 {
+            self.received.lock().unwrap().push(data);
+            self.reply
+        }
+
+        fn poll_slave(&mut self, data: u8) -> Option<u8> {
+            if !self.ready {
+                return None;
+            }
+            self.received.lock().unwrap().push(data);
+            Some(self.reply)
+        }
+    }
+
+    fn memory_with_partner(reply: u8, ready: bool) -> (Memory, Arc<Mutex<Vec<u8>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut memory = Memory::new();
+        memory.load_rom(vec![0; 0x8000]);
+        memory.connect_serial(Box::new(EchoPartner { reply, ready, received: received.clone() }));
+        (memory, received)
+    }
+
+    #[test]
+    fn test_internal_clock_transfer_timing() {
+        let (mut memory, received) = memory_with_partner(0x42, true);
+        memory.write(0xFF01, 0x99);
+        memory.write(0xFF02, 0x81);
+
+        memory.tick(8 * 512 - 4);
+        assert_eq!(memory.read(0xFF02), 0xFF);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0);
+
+        memory.tick(4);
+        assert_eq!(memory.read(0xFF01), 0x42);
+        assert_eq!(memory.read(0xFF02), 0x7F);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0x08);
+        assert_eq!(*received.lock().unwrap(), vec![0x99]);
+    }
+
+    #[test]
+    fn test_no_cable_receives_ff() {
+        let mut memory = Memory::new();
+        memory.load_rom(vec![0; 0x8000]);
+        memory.write(0xFF01, 0x12);
+        memory.write(0xFF02, 0x81);
+        memory.tick(8 * 512);
+        assert_eq!(memory.read(0xFF01), 0xFF);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0x08);
+
+        // External clock never completes without a partner
+        memory.write(0xFF0F, 0x00);
+        memory.write(0xFF02, 0x80);
+        memory.tick(100_000);
+        assert_eq!(memory.read(0xFF02), 0xFE);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0);
+    }
+
+    #[test]
+    fn test_external_clock_waits_for_partner() {
+        let (mut memory, _) = memory_with_partner(0x42, false);
+        memory.write(0xFF01, 0x55);
+        memory.write(0xFF02, 0x80);
+        memory.tick(100_000);
+        assert_eq!(memory.read(0xFF01), 0x55);
+
+        let (mut memory, received) = memory_with_partner(0x42, true);
+        memory.write(0xFF01, 0x55);
+        memory.write(0xFF02, 0x80);
+        memory.tick(4);
+        assert_eq!(memory.read(0xFF01), 0x42);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0x08);
+        assert_eq!(*received.lock().unwrap(), vec![0x55]);
+    }
+
+    #[test]
+    fn test_cgb_fast_clock() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut memory = Memory::new();
+        memory.load_rom(rom);
+        memory.write(0xFF02, 0x83);
+        memory.tick(8 * 16);
+        assert_eq!(memory.read(0xFF02) & 0x80, 0);
+        assert_eq!(memory.read(0xFF0F) & 0x08, 0x08);
+    }
+}
+
+#[cfg(test)]
+mod link_tests {
+    use crate::link::{link_address, TcpLink};
+    use crate::memory::Memory;
+    use crate::pacing::CYCLES_PER_FRAME;
+    use crate::serial::SerialDevice;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Connected pair of links on 127.0.0.1
+    fn link_pair() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host = thread::spawn(move || TcpLink::accept(&listener).unwrap());
+        let client = TcpLink::connect(addr).unwrap();
+        (host.join().unwrap(), client)
+    }
+
+    fn linked_memory(link: TcpLink) -> Memory {
+        let mut memory = Memory::new();
+        memory.load_rom(vec![0; 0x8000]);
+        memory.connect_serial(Box::new(link));
+        memory
+    }
+
+    // Start a transfer and tick until the serial interrupt. Returns the received byte.
+    fn run_transfer(link: TcpLink, data: u8, control: u8) -> u8 {
+        let mut memory = linked_memory(link);
+        memory.write(0xFF01, data);
+        memory.write(0xFF02, control);
+        while memory.read(0xFF0F) & 0x08 == 0 {
+            memory.tick(4);
+        }
+        memory.read(0xFF01)
+    }
+
+    #[test]
+    fn test_master_and_slave_exchange_bytes() {
+        let (master, slave) = link_pair();
+        let slave = thread::spawn(move || run_transfer(slave, 0x5A, 0x80));
+        assert_eq!(run_transfer(master, 0xA5, 0x81), 0x5A);
+        assert_eq!(slave.join().unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn test_both_masters_complete() {
+        let (a, b) = link_pair();
+        let b = thread::spawn(move || run_transfer(b, 0x22, 0x81));
+        assert_eq!(run_transfer(a, 0x11, 0x81), 0x22);
+        assert_eq!(b.join().unwrap(), 0x11);
+    }
+
+    #[test]
+    fn test_disconnected_partner_reads_ff() {
+        let (master, slave) = link_pair();
+        drop(slave);
+        assert_eq!(run_transfer(master, 0x12, 0x81), 0xFF);
+    }
+
+    #[test]
+    fn test_paused_partner_still_answers() {
+        let (master, slave) = link_pair();
+        let slave = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(600));
+            run_transfer(slave, 0x5A, 0x80)
+        });
+        assert_eq!(run_transfer(master, 0xA5, 0x81), 0x5A);
+        assert_eq!(slave.join().unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn test_quiet_partner_doesnt_block() {
+        let (mut ahead, mut quiet) = link_pair();
+
+        // The partner doesn't run at all: holding gives up after a while
+        // instead of blocking the frontend
+        let start = Instant::now();
+        for _ in 0..2 * CYCLES_PER_FRAME / 4 {
+            ahead.tick(4);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Transfers don't wait for it either
+        let start = Instant::now();
+        assert_eq!(ahead.transfer_master(0x12), 0xFF);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Once it speaks again both sides carry on in lockstep
+        quiet.tick(CYCLES_PER_FRAME as u32);
+        // Give its sync time to arrive
+        thread::sleep(Duration::from_millis(50));
+        let slave = thread::spawn(move || run_transfer(quiet, 0x5A, 0x80));
+        assert_eq!(run_transfer(ahead, 0xA5, 0x81), 0x5A);
+        assert_eq!(slave.join().unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn test_idle_slave_reads_ff() {
+        let (master, mut slave) = link_pair();
+        let stop = Arc::new(AtomicBool::new(false));
+        let idle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    slave.tick(4);
+                }
+            })
+        };
+        assert_eq!(run_transfer(master, 0x12, 0x81), 0xFF);
+        stop.store(true, Ordering::Relaxed);
+        idle.join().unwrap();
+    }
+
+    #[test]
+    fn test_side_ahead_waits_for_partner() {
+        let (mut ahead, mut behind) = link_pair();
+        let progress = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let partner = {
+            let (progress, stop) = (progress.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    behind.tick(4);
+                    progress.fetch_add(4, Ordering::Relaxed);
+                }
+            })
+        };
+
+        for _ in 0..3 * CYCLES_PER_FRAME / 4 {
+            ahead.tick(4);
+        }
+        // At most a frame ahead of the partner
+        assert!(progress.load(Ordering::Relaxed) >= 2 * CYCLES_PER_FRAME);
+
+        stop.store(true, Ordering::Relaxed);
+        drop(ahead);
+        partner.join().unwrap();
+    }
+
+    #[test]
+    fn test_held_side_answers_transfer() {
+        let (mut master, mut ahead) = link_pair();
+        let progress = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let partner = {
+            let (progress, stop) = (progress.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    ahead.tick(4);
+                    progress.fetch_add(4, Ordering::Relaxed);
+                }
+            })
+        };
+
+        // The partner runs a frame ahead and holds for the master, whose
+        // clock stands still while it waits for the answer
+        while progress.load(Ordering::Relaxed) < CYCLES_PER_FRAME {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(master.transfer_master(0x12), 0xFF);
+
+        stop.store(true, Ordering::Relaxed);
+        drop(master);
+        partner.join().unwrap();
+    }
+
+    #[test]
+    fn test_link_address() {
+        assert_eq!(link_address("5000"), "127.0.0.1:5000");
+        assert_eq!(link_address("192.168.1.2:5000"), "192.168.1.2:5000");
+    }
+}
+
+#[cfg(test)]
+mod bgb_tests {
+    use crate::bgb::{BgbLink, Packet};
+    use crate::memory::Memory;
+    use crate::pacing::CYCLES_PER_FRAME;
+    use crate::serial::SerialDevice;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Our implementation on both ends of a 127.0.0.1 connection
+    fn loopback_pair() -> (BgbLink, BgbLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let host = thread::spawn(move || BgbLink::host(addr).unwrap());
+        let client = loop {
+            if let Ok(link) = BgbLink::connect(addr) {
+                break link;
+            }
+            thread::yield_now();
+        };
+        (host.join().unwrap(), client)
+    }
+
+    fn run_transfer(link: BgbLink, data: u8, control: u8) -> u8 {
+        let mut memory = Memory::new();
+        memory.load_rom(vec![0; 0x8000]);
+        memory.connect_serial(Box::new(link));
+        memory.write(0xFF01, data);
+        memory.write(0xFF02, control);
+        while memory.read(0xFF0F) & 0x08 == 0 {
+            memory.tick(4);
+        }
+        memory.read(0xFF01)
+    }
+
+    #[test]
+    fn test_packet_encoding() {
+        let packet = Packet::new(104, 0x42, 0x81, 0, 0x0012_3456);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes, [104, 0x42, 0x81, 0, 0x56, 0x34, 0x12, 0x00]);
+        assert_eq!(Packet::from_bytes(bytes), packet);
+    }
+
+    #[test]
+    fn test_loopback_transfer() {
+        let (master, slave) = loopback_pair();
+        let slave = thread::spawn(move || run_transfer(slave, 0x3C, 0x80));
+        assert_eq!(run_transfer(master, 0xC3, 0x81), 0x3C);
+        assert_eq!(slave.join().unwrap(), 0xC3);
+    }
+
+    #[test]
+    fn test_loopback_slave_not_ready() {
+        let (mut master, mut slave) = loopback_pair();
+        // The idle slave acknowledges SYNC1 with SYNC3 on its next step
+        let slave = thread::spawn(move || {
+            for _ in 0..100_000 {
+                slave.tick(4);
+                thread::yield_now();
+            }
+        });
+        let start = Instant::now();
+        assert_eq!(master.transfer_master(0x12), 0xFF);
+        // Answered, not timed out
+        assert!(start.elapsed() < Duration::from_millis(400));
+        // The slave runs ahead of the idle master and holds until it's gone
+        drop(master);
+        slave.join().unwrap();
+    }
+
+    #[test]
+    fn test_loopback_timestamp_sync() {
+        let (mut a, mut b) = loopback_pair();
+        // A frame's worth of cycles triggers a SYNC3 timestamp update
+        a.tick(70224);
+        while b.peer_timestamp() == 0 {
+            b.tick(4);
+            thread::yield_now();
+        }
+        assert_eq!(b.peer_timestamp(), 70224 / 2);
+    }
+
+    #[test]
+    fn test_loopback_side_ahead_waits_for_peer() {
+        let (mut ahead, mut behind) = loopback_pair();
+        let progress = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let peer = {
+            let (progress, stop) = (progress.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    behind.tick(4);
+                    progress.fetch_add(4, Ordering::Relaxed);
+                }
+            })
+        };
+
+        for _ in 0..3 * CYCLES_PER_FRAME / 4 {
+            ahead.tick(4);
+        }
+        // At most a frame ahead of the peer, plus the quarter frame its first
+        // timestamp may have been late
+        assert!(progress.load(Ordering::Relaxed) >= 2 * CYCLES_PER_FRAME - CYCLES_PER_FRAME / 4);
+
+        stop.store(true, Ordering::Relaxed);
+        drop(ahead);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_loopback_quiet_peer_doesnt_block() {
+        let (mut ahead, mut quiet) = loopback_pair();
+
+        // The peer doesn't run at all: holding gives up after a while
+        let start = Instant::now();
+        for _ in 0..2 * CYCLES_PER_FRAME / 4 {
+            ahead.tick(4);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Transfers don't wait for it either
+        let start = Instant::now();
+        assert_eq!(ahead.transfer_master(0x12), 0xFF);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Once it speaks again both sides carry on in lockstep
+        quiet.tick(CYCLES_PER_FRAME as u32);
+        // Give its sync time to arrive
+        thread::sleep(Duration::from_millis(50));
+        let slave = thread::spawn(move || run_transfer(quiet, 0x5A, 0x80));
+        assert_eq!(run_transfer(ahead, 0xA5, 0x81), 0x5A);
+        assert_eq!(slave.join().unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn test_sync1_carries_fast_clock() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&Packet::new(1, 1, 4, 0, 0).to_bytes()).unwrap();
+            let mut buf = [0; 8];
+            let sync1 = loop {
+                stream.read_exact(&mut buf).unwrap();
+                let packet = Packet::from_bytes(buf);
+                if packet.command == 104 {
+                    break packet;
+                }
+            };
+            stream.write_all(&Packet::new(105, 0x99, 0x80, 0, 0).to_bytes()).unwrap();
+            sync1
+        });
+
+        let mut link = BgbLink::connect(addr).unwrap();
+        link.start_master(0x42, true);
+        assert_eq!(link.transfer_master(0x42), 0x99);
+        let sync1 = peer.join().unwrap();
+        assert_eq!((sync1.b2, sync1.b3), (0x42, 0x83));
+    }
+
+    #[test]
+    fn test_rejects_other_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&Packet::new(1, 1, 3, 0, 0).to_bytes()).unwrap();
+            let mut buf = [0; 8];
+            let _ = stream.read_exact(&mut buf);
+        });
+        assert!(BgbLink::connect(addr).is_err());
+        peer.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod printer_tests {
+    use crate::image::{crc32, encode_png};
+    use crate::printer::{Printer, PrinterEvent, PAPER_WIDTH};
+    use crate::serial::SerialDevice;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+
+    fn output_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gb-printer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Send a packet and return the (alive, status) answers
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut body = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+        for byte in [0x88, 0x33].into_iter().chain(body).chain(checksum.to_le_bytes()) {
+            assert_eq!(printer.transfer_master(byte), 0x00);
+        }
+        (printer.transfer_master(0x00), printer.transfer_master(0x00))
+    }
+
+    fn png_size(path: &Path) -> (u32, u32) {
+        let png = std::fs::read(path).unwrap();
+        let width = u32::from_be_bytes([png[16], png[17], png[18], png[19]]);
+        let height = u32::from_be_bytes([png[20], png[21], png[22], png[23]]);
+        (width, height)
+    }
+
+    // One row of 20 tiles with every pixel set to color 3
+    fn black_row() -> Vec<u8> {
+        vec![0xFF; 20 * 16]
+    }
+
+    #[test]
+    fn test_status_and_checksum() {
+        let mut printer = Printer::new(output_dir("status"));
+        assert_eq!(send_packet(&mut printer, 0x01, false, &[]), (0x81, 0x00));
+        assert_eq!(send_packet(&mut printer, 0x04, false, &black_row()), (0x81, 0x08));
+
+        // Corrupt checksum
+        for byte in [0x88, 0x33, 0x0F, 0x00, 0x00, 0x00, 0x12, 0x34] {
+            printer.transfer_master(byte);
+        }
+        assert_eq!(printer.transfer_master(0x00), 0x81);
+        assert_eq!(printer.transfer_master(0x00) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn test_print_saves_image_with_margins() {
+        let dir = output_dir("margins");
+        let mut printer = Printer::new(&dir);
+        send_packet(&mut printer, 0x01, false, &[]);
+        send_packet(&mut printer, 0x04, false, &black_row());
+        send_packet(&mut printer, 0x04, false, &[]);
+        // 1 sheet, margins 1 before / 2 after, identity palette
+        let (_, status) = send_packet(&mut printer, 0x02, false, &[1, 0x12, 0xE4, 0x40]);
+        assert_eq!(status & 0x02, 0x02);
+
+        assert_eq!(printer.saved().len(), 1);
+        assert_eq!(png_size(&printer.saved()[0]), (PAPER_WIDTH as u32, 8 + 8 + 16));
+
+        // Busy until the print time has passed
+        printer.tick(u32::MAX);
+        assert_eq!(send_packet(&mut printer, 0x0F, false, &[]), (0x81, 0x00));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_prints_without_margins_join() {
+        let dir = output_dir("join");
+        let mut printer = Printer::new(&dir);
+        for margins in [0x10, 0x00, 0x03] {
+            send_packet(&mut printer, 0x04, false, &black_row());
+            send_packet(&mut printer, 0x04, false, &black_row());
+            send_packet(&mut printer, 0x02, false, &[1, margins, 0xE4, 0x40]);
+        }
+        assert_eq!(printer.saved().len(), 1);
+        assert_eq!(png_size(&printer.saved()[0]).1, 8 + 3 * 16 + 24);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_print_without_feed_is_saved_at_once() {
+        let dir = output_dir("nofeed");
+        let mut printer = Printer::new(&dir);
+        send_packet(&mut printer, 0x04, false, &black_row());
+        send_packet(&mut printer, 0x02, false, &[1, 0x00, 0xE4, 0x40]);
+        assert_eq!(printer.saved().len(), 1);
+        assert_eq!(png_size(&printer.saved()[0]), (160, 8));
+
+        // More printed onto the same roll updates the same file
+        send_packet(&mut printer, 0x04, false, &black_row());
+        send_packet(&mut printer, 0x02, false, &[1, 0x00, 0xE4, 0x40]);
+        assert_eq!(printer.saved().len(), 1);
+        assert_eq!(png_size(&printer.saved()[0]), (160, 16));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_saves_are_reported_to_the_event_handler() {
+        let dir = output_dir("events");
+        let mut printer = Printer::new(&dir);
+        let (sender, events) = mpsc::channel();
+        printer.set_event_handler(move |event| sender.send(event).unwrap());
+
+        for _ in 0..2 {
+            send_packet(&mut printer, 0x04, false, &black_row());
+            send_packet(&mut printer, 0x02, false, &[1, 0x00, 0xE4, 0x40]);
+        }
+        let saved: Vec<PathBuf> = events
+            .try_iter()
+            .map(|event| match event {
+                PrinterEvent::Saved(path) => path,
+                PrinterEvent::SaveFailed(e) => panic!("save failed: {}", e),
+            })
+            .collect();
+        assert_eq!(saved, [printer.saved()[0].clone(), printer.saved()[0].clone()]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rle_data() {
+        let dir = output_dir("rle");
+        let mut printer = Printer::new(&dir);
+        // 320 bytes of 0xFF as runs of 129, 129 and 62 bytes, then literals
+        let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF, 0x01, 0x00, 0x00];
+        assert_eq!(send_packet(&mut printer, 0x04, true, &data).1, 0x08);
+        send_packet(&mut printer, 0x02, false, &[1, 0x01, 0xE4, 0x40]);
+        assert_eq!(png_size(&printer.saved()[0]), (160, 16));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_png_encoding() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        let png = encode_png(2, 1, &[0xFF0000, 0x00FF00]);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+}
+
+#[cfg(test)]
+mod dmg07_tests {
+    use crate::dmg07::{Dmg07, Dmg07Event, Phase, RemotePort};
+    use crate::serial::SerialDevice;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    const ACK: u8 = 0x88;
+
+    // Game Boy side of a port: what it received so far
+    struct Player {
+        device: Box<dyn SerialDevice>,
+        received: Vec<u8>,
+    }
+
+    impl Player {
+        fn new(device: Box<dyn SerialDevice>) -> Self {
+            Self { device, received: Vec::new() }
+        }
+
+        fn exchange(&mut self, data: u8) {
+            if let Some(byte) = self.device.poll_slave(data) {
+                self.received.push(byte);
+            }
+        }
+
+        // Answer pings like a game: ACK, ACK, RATE, SIZE
+        fn ping_reply(&self, size: u8) -> u8 {
+            match self.received.iter().rev().position(|&b| b == 0xFE) {
+                Some(1) => 0x00,
+                Some(2) => size,
+                _ => ACK,
+            }
+        }
+
+        fn seen_start(&self) -> bool {
+            self.received.contains(&0xCC)
+        }
+
+        // Bytes received since transmission started
+        fn transmission(&self) -> &[u8] {
+            match self.received.iter().rposition(|&b| b == 0xCC) {
+                Some(i) => &self.received[i + 1..],
+                None => &[],
+            }
+        }
+    }
+
+    fn step(adapter_clock: &mut Player, cycles: u32) {
+        adapter_clock.device.tick(cycles);
+    }
+
+    #[test]
+    fn test_ping_reports_ids_and_connected_players() {
+        let adapter = Dmg07::new();
+        let mut players = [Player::new(Box::new(adapter.port(0))), Player::new(Box::new(adapter.port(1)))];
+
+        while players[1].received.len() < 12 {
+            step(&mut players[0], 256);
+            for player in players.iter_mut() {
+                let reply = player.ping_reply(1);
+                player.exchange(reply);
+            }
+        }
+        // Both players acknowledged the header, so both show up as connected
+        assert_eq!(&players[0].received[..4], &[0xFE, 0x31, 0x31, 0x31]);
+        assert_eq!(&players[1].received[8..12], &[0xFE, 0x32, 0x32, 0x32]);
+        assert_eq!(adapter.phase(), Phase::Ping);
+    }
+
+    #[test]
+    fn test_transmission_relays_packets() {
+        let adapter = Dmg07::new();
+        let mut players: Vec<Player> = (0..4).map(|i| Player::new(Box::new(adapter.port(i)))).collect();
+        let values = [0x11, 0x22, 0x33, 0x44];
+
+        while players.iter().any(|p| p.transmission().len() < 12) {
+            step(&mut players[0], 256);
+            for (i, player) in players.iter_mut().enumerate() {
+                let reply = if player.seen_start() {
+                    values[i]
+                } else if i == 0 && player.received.len() >= 8 {
+                    // Player 1 starts the session after a couple of pings
+                    0xAA
+                } else {
+                    player.ping_reply(1)
+                };
+                player.exchange(reply);
+            }
+        }
+        assert_eq!(adapter.phase(), Phase::Transmission);
+        for player in &players {
+            // Round 1 relays the empty buffer, round 2 everybody's packets
+            assert_eq!(&player.transmission()[..4], &[0, 0, 0, 0]);
+            assert_eq!(&player.transmission()[4..8], &values);
+        }
+    }
+
+    #[test]
+    fn test_restart_returns_to_ping() {
+        let adapter = Dmg07::new();
+        let mut player = Player::new(Box::new(adapter.port(0)));
+        while player.transmission().len() < 8 {
+            step(&mut player, 256);
+            let reply = if player.seen_start() {
+                0x01
+            } else if player.received.len() >= 8 {
+                0xAA
+            } else {
+                player.ping_reply(1)
+            };
+            player.exchange(reply);
+        }
+        assert_eq!(adapter.phase(), Phase::Transmission);
+
+        while adapter.phase() == Phase::Transmission {
+            step(&mut player, 256);
+            player.exchange(0xFF);
+        }
+        assert_eq!(adapter.phase(), Phase::Ping);
+    }
+
+    #[test]
+    fn test_remote_player_over_tcp() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let adapter = Dmg07::new();
+        let mut host = Player::new(Box::new(adapter.port(0)));
+        let (sender, events) = std::sync::mpsc::channel();
+        adapter.serve(addr, move |event| {
+            let _ = sender.send(event);
+        })
+        .unwrap();
+        let mut remote = Player::new(Box::new(RemotePort::connect(addr).unwrap()));
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, Dmg07Event::Connected(2)));
+
+        while remote.received.len() < 12 {
+            step(&mut host, 256);
+            for player in [&mut host, &mut remote] {
+                let reply = player.ping_reply(1);
+                player.exchange(reply);
+            }
+            std::thread::sleep(Duration::from_micros(20));
+        }
+        assert_eq!(&remote.received[8..12], &[0xFE, 0x32, 0x32, 0x32]);
+    }
+}
+
+#[cfg(test)]
+mod gameboy_tests {
+    use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::sgb::{SGB_HEIGHT, SGB_WIDTH};
+    use crate::{GameBoy, Input, Model};
+
+    fn rom_with_title(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        // JR -2: spin forever
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xFE;
+        rom
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut gameboy = GameBoy::new();
+        let header = gameboy.load_cartridge(rom_with_title(b"TEST")).unwrap();
+        assert_eq!(header.title_string(), "TEST");
+
+        gameboy.run_frame();
+        assert!(gameboy.cycles() >= 70224);
+        assert!(gameboy.cycles() < 70224 + 24);
+        assert_eq!(gameboy.screen_size(), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(gameboy.framebuffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+    }
+
+    #[test]
+    fn test_set_buttons() {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(rom_with_title(b"TEST"));
+        gameboy.set_buttons(Input { start: true, ..Input::default() });
+        assert!(gameboy.buttons().start);
+
+        gameboy.memory_mut().write(0xFF00, 0x10);
+        assert_eq!(gameboy.memory().read(0xFF00) & 0x0F, 0x07);
+    }
+
+    #[test]
+    fn test_load_cartridge_colorizes_dmg_games_on_cgb() {
+        let mut gameboy = GameBoy::new();
+        gameboy.set_model(Model::Cgb);
+        gameboy.load_cartridge(rom_with_title(b"TEST"));
+        assert!(gameboy.memory().is_dmg_compatibility());
+        // Default palette (sea green BG): color 1 is 0x7BFF31
+        assert_eq!(gameboy.memory().bg_palette_color(0, 1), crate::colorization::rgb888_to_rgb555(0x7BFF31));
+    }
+
+    #[test]
+    fn test_sgb_framebuffer_includes_border() {
+        let mut gameboy = GameBoy::new();
+        gameboy.set_model(Model::Sgb);
+        gameboy.load_cartridge(rom_with_title(b"TEST"));
+        gameboy.run_frame();
+        assert_eq!(gameboy.screen_size(), (SGB_WIDTH, SGB_HEIGHT));
+        assert_eq!(gameboy.framebuffer().len(), SGB_WIDTH * SGB_HEIGHT);
+    }
+}
+
+#[cfg(test)]
+mod bus_tests {
+    use crate::bus::{Access, Bus, FlatBus, TracingBus};
+    use crate::cpu::CPU;
+
+    fn flat_cpu(program: &[u8]) -> CPU<FlatBus> {
+        let mut bus = FlatBus::new();
+        bus.load(0x0100, program);
+        CPU::with_bus(bus)
+    }
+
+    #[test]
+    fn test_cpu_runs_on_flat_bus() {
+        let mut cpu = flat_cpu(&[
+            0x3E, 0x12,       // LD A, $12
+            0xC6, 0x30,       // ADD A, $30
+            0xEA, 0x00, 0x80, // LD ($8000), A - plain RAM, no VRAM rules
+            0x31, 0x00, 0xE0, // LD SP, $E000
+            0xCD, 0x00, 0x02, // CALL $0200
+        ]);
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(cpu.get_memory_mut().read(0x8000), 0x42);
+        assert_eq!(cpu.get_pc(), 0x0200);
+        // Return address pushed on the stack
+        assert_eq!(cpu.get_memory_mut().read(0xDFFE), 0x0D);
+        assert_eq!(cpu.get_memory_mut().read(0xDFFF), 0x01);
+    }
+
+    #[test]
+    fn test_tracing_bus_records_accesses() {
+        let mut bus = FlatBus::new();
+        bus.load(0x0100, &[0x3E, 0x99, 0xE0, 0x80]); // LD A, $99; LDH ($80), A
+        let mut cpu = CPU::with_bus(TracingBus::new(bus));
+        cpu.step();
+        cpu.step();
+
+        let log = &cpu.get_memory().log;
+        assert_eq!(
+            log.as_slice(),
+            &[
+                Access::Read { addr: 0x0100, value: 0x3E },
+                Access::Read { addr: 0x0101, value: 0x99 },
+                Access::Read { addr: 0x0102, value: 0xE0 },
+                Access::Read { addr: 0x0103, value: 0x80 },
+                Access::Write { addr: 0xFF80, value: 0x99 },
+            ]
+        );
+        assert_eq!(cpu.get_memory_mut().inner.read(0xFF80), 0x99);
+    }
+
+    #[test]
+    fn test_flat_bus_has_no_speed_switch() {
+        let mut cpu = flat_cpu(&[0x10, 0x00, 0x00]); // STOP; NOP
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.get_pc(), 0x0103);
+    }
+}
+
+#[cfg(test)]
+mod headless_tests {
+    use crate::headless::{self, RunLimits, StopReason};
+    use crate::image::encode_ppm;
+    use crate::GameBoy;
+
+    // NOP, NOP, then JR -2 at 0x0102 forever
+    fn spin_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0102] = 0x18;
+        rom[0x0103] = 0xFE;
+        rom
+    }
+
+    #[test]
+    fn test_run_frames_matches_run_frame() {
+        let mut headless_gb = GameBoy::new();
+        headless_gb.load_cartridge(spin_rom());
+        let limits = RunLimits { frames: Some(3), ..RunLimits::default() };
+        let result = headless::run(&mut headless_gb, &limits);
+        assert_eq!(result.reason, StopReason::Frames);
+        assert_eq!(result.frames, 3);
+
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(spin_rom());
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        assert_eq!(headless_gb.cycles(), gameboy.cycles());
+        assert_eq!(headless_gb.framebuffer(), gameboy.framebuffer());
+    }
+
+    #[test]
+    fn test_run_until_pc() {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(spin_rom());
+        let limits = RunLimits { until_pc: Some(0x0102), timeout_cycles: Some(1000), ..RunLimits::default() };
+        let result = headless::run(&mut gameboy, &limits);
+        assert_eq!(result.reason, StopReason::ReachedPc);
+        assert_eq!(result.cycles, 8);
+        assert_eq!(gameboy.cpu().registers().pc, 0x0102);
+    }
+
+    #[test]
+    fn test_timeout_when_pc_never_reached() {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(spin_rom());
+        let limits = RunLimits { until_pc: Some(0x4000), timeout_cycles: Some(1000), ..RunLimits::default() };
+        let result = headless::run(&mut gameboy, &limits);
+        assert_eq!(result.reason, StopReason::Timeout);
+        assert!(result.cycles >= 1000);
+    }
+
+    #[test]
+    fn test_register_dump() {
+        let gameboy = GameBoy::new();
+        assert_eq!(
+            gameboy.cpu().registers().to_string(),
+            "AF=0100 BC=0013 DE=00D8 HL=014D SP=FFFE PC=0100 [----]"
+        );
+    }
+
+    #[test]
+    fn test_encode_ppm() {
+        let ppm = encode_ppm(2, 1, &[0xFF0000, 0x00FF80]);
+        assert_eq!(ppm, b"P6\n2 1\n255\n\xFF\x00\x00\x00\xFF\x80");
+    }
+}
+
+#[cfg(test)]
+mod golden_tests {
+    use crate::test_roms::joypad_rom;
+    use crate::golden::{self, GoldenOptions, Outcome};
+    use crate::image::{crc32, decode_png, decode_ppm, encode_png, encode_ppm};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gb-golden-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_manifest(dir: &Path, tests: &str) -> PathBuf {
+        fs::write(dir.join("joypad.gb"), joypad_rom()).unwrap();
+        let path = dir.join("manifest.json");
+        fs::write(&path, format!("{{ \"tests\": [{}] }}", tests)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_bless_then_pass() {
+        let dir = test_dir("pass");
+        let manifest = write_manifest(
+            &dir,
+            r#"{ "name": "idle", "rom": "joypad.gb", "frames": 2, "expected": "expected/idle.png" }"#,
+        );
+        let mut options = GoldenOptions { output_dir: dir.join("failures"), bless: true };
+
+        let results = golden::run_manifest(&manifest, &options).unwrap();
+        assert_eq!(results[0].outcome, Outcome::Blessed);
+        assert!(dir.join("expected/idle.png").exists());
+
+        options.bless = false;
+        let results = golden::run_manifest(&manifest, &options).unwrap();
+        assert!(results[0].passed(), "{}", results[0]);
+        assert!(!dir.join("failures").exists());
+    }
+
+    #[test]
+    fn test_input_script_mismatch_writes_diff() {
+        let dir = test_dir("mismatch");
+        let idle = r#"{ "name": "idle", "rom": "joypad.gb", "frames": 2, "expected": "idle.ppm" }"#;
+        let options = GoldenOptions { output_dir: dir.join("failures"), bless: false };
+        let manifest = write_manifest(&dir, idle);
+        golden::run_manifest(&manifest, &GoldenOptions { bless: true, ..options.clone() }).unwrap();
+
+        // Holding start changes the picture
+        let start = r#"{ "name": "start", "rom": "joypad.gb", "frames": 2, "expected": "idle.ppm",
+                         "input": [{ "frame": 0, "buttons": ["start"] }] }"#;
+        let manifest = write_manifest(&dir, &format!("{}, {}", idle, start));
+
+        let results = golden::run_manifest(&manifest, &options).unwrap();
+        assert!(results[0].passed());
+        match &results[1].outcome {
+            Outcome::Mismatch { pixels, diff } => {
+                assert!(*pixels > 0);
+                let diff = decode_png(&fs::read(diff).unwrap()).unwrap();
+                assert!(diff.pixels.contains(&0xFF0000));
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+        assert!(dir.join("failures/start.actual.png").exists());
+        assert_ne!(results[0].hash, results[1].hash);
+    }
+
+    #[test]
+    fn test_missing_rom_is_an_error() {
+        let dir = test_dir("missing");
+        let manifest = write_manifest(
+            &dir,
+            r#"{ "name": "missing", "rom": "nope.gb", "frames": 1, "expected": "nope.png" }"#,
+        );
+        let options = GoldenOptions { output_dir: dir.join("failures"), bless: false };
+        let results = golden::run_manifest(&manifest, &options).unwrap();
+        assert!(matches!(results[0].outcome, Outcome::Error(_)));
+        assert!(!results[0].passed());
+    }
+
+    #[test]
+    fn test_name_with_path_separator_is_an_error() {
+        let dir = test_dir("escape");
+        let manifest = write_manifest(
+            &dir,
+            r#"{ "name": "../escape", "rom": "joypad.gb", "frames": 1, "expected": "escape.png" }"#,
+        );
+        let options = GoldenOptions { output_dir: dir.join("failures"), bless: true };
+        let results = golden::run_manifest(&manifest, &options).unwrap();
+        assert!(matches!(results[0].outcome, Outcome::Error(_)));
+        assert!(!dir.join("escape.png").exists());
+    }
+
+    // Commercial and test ROMs can't live in the repository, so the real
+    // suite runs from a manifest named by GB_GOLDEN_MANIFEST when it's set
+    #[test]
+    fn test_golden_manifest_from_env() {
+        let Ok(path) = std::env::var("GB_GOLDEN_MANIFEST") else {
+            return;
+        };
+        let path = PathBuf::from(path);
+        let output_dir = path.parent().unwrap_or(&path).join("failures");
+        let results = golden::run_manifest(&path, &GoldenOptions { output_dir, bless: false }).unwrap();
+        let failures: Vec<String> = results.iter().filter(|r| !r.passed()).map(|r| r.to_string()).collect();
+        assert!(failures.is_empty(), "golden image failures:\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_decode_own_images() {
+        let pixels = [0x123456, 0xABCDEF, 0x000000, 0xFFFFFF, 0x808080, 0x0000FF];
+        assert_eq!(decode_png(&encode_png(3, 2, &pixels)).unwrap().pixels, pixels);
+        assert_eq!(decode_ppm(&encode_ppm(3, 2, &pixels)).unwrap().pixels, pixels);
+    }
+
+    #[test]
+    fn test_huge_images_are_rejected() {
+        // A 1x1 PNG whose header claims 100000x100000 pixels
+        let mut png = encode_png(1, 1, &[0]);
+        png[16..20].copy_from_slice(&100_000u32.to_be_bytes());
+        png[20..24].copy_from_slice(&100_000u32.to_be_bytes());
+        let crc = crc32(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.to_be_bytes());
+        assert!(decode_png(&png).is_err());
+
+        assert!(decode_ppm(b"P6 100000 100000 255\n").is_err());
+        assert!(decode_ppm(b"P6 18446744073709551615 2 255\n").is_err());
+    }
+
+    #[test]
+    fn test_decode_compressed_png() {
+        // 8x4 RGB made by zlib with a dynamic Huffman block, using all five
+        // filter types
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00,
+            0x00, 0x08, 0x00, 0x00, 0x00, 0x04, 0x08, 0x02, 0x00, 0x00, 0x00, 0x3C, 0xAF, 0xE9, 0xA7, 0x00, 0x00, 0x00,
+            0x61, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x1D, 0xC9, 0x41, 0x0D, 0x80, 0x30, 0x0C, 0x85, 0xE1, 0xC2, 0x34,
+            0xE0, 0x60, 0x09, 0x1A, 0x10, 0x40, 0x32, 0xB2, 0x4A, 0x79, 0x87, 0x4A, 0xE9, 0x92, 0x2A, 0x98, 0x86, 0xDD,
+            0x30, 0x32, 0x4C, 0xD4, 0x01, 0x81, 0xC1, 0x7F, 0xFA, 0xF2, 0x1E, 0x3D, 0x44, 0x29, 0x25, 0x55, 0x5D, 0x7B,
+            0x8F, 0x31, 0x02, 0xC8, 0xAD, 0x11, 0xD1, 0x34, 0xA6, 0x71, 0x04, 0xE6, 0x92, 0xBE, 0x32, 0xF3, 0xF1, 0x63,
+            0x36, 0xB3, 0xD3, 0xEC, 0x52, 0x1D, 0x08, 0x66, 0x45, 0xBF, 0xF2, 0xF0, 0x56, 0x6B, 0x11, 0x71, 0xF7, 0x0C,
+            0xEC, 0x80, 0x88, 0x2C, 0xEE, 0x37, 0xF0, 0x02, 0x8B, 0xAE, 0x2A, 0xD9, 0x0A, 0x5D, 0x01, 0xA3, 0x00, 0x00,
+            0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        let image = decode_png(&png).unwrap();
+        assert_eq!((image.width, image.height), (8, 4));
+        for y in 0..4u32 {
+            for x in 0..8u32 {
+                let red = if (x + y) % 3 == 0 { 0xFF0000 } else { 0 };
+                let expected = (((x * 37 + y * 11) % 7) * 0x242424) ^ red;
+                assert_eq!(image.pixels[(y * 8 + x) as usize], expected, "pixel {},{}", x, y);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod savestate_tests {
+    use crate::savestate::VERSION;
+    use crate::test_roms::counter_rom;
+    use crate::{GameBoy, Model};
+
+    fn running_gameboy(model: Model) -> GameBoy {
+        let mut gameboy = GameBoy::new();
+        gameboy.set_model(model);
+        gameboy.load_cartridge(counter_rom());
+        gameboy.run_frame();
+        gameboy
+    }
+
+    #[test]
+    fn test_load_restores_and_replays_identically() {
+        let mut gameboy = running_gameboy(Model::Dmg);
+        let state = gameboy.save_state();
+        let registers = gameboy.cpu().registers();
+        let counter = gameboy.memory().read(0xC000);
+
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        let later_frame = gameboy.framebuffer().to_vec();
+        let later_cycles = gameboy.cycles();
+        assert_ne!(gameboy.memory().read(0xC000), counter);
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.cpu().registers(), registers);
+        assert_eq!(gameboy.memory().read(0xC000), counter);
+
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.cycles(), later_cycles);
+        assert_eq!(gameboy.framebuffer(), &later_frame[..]);
+    }
+
+    #[test]
+    fn test_sgb_state_round_trip() {
+        let mut gameboy = running_gameboy(Model::Sgb);
+        let state = gameboy.save_state();
+        let frame = gameboy.framebuffer().to_vec();
+        gameboy.run_frame();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.framebuffer(), &frame[..]);
+
+        // An SGB state doesn't fit a DMG
+        let mut dmg = running_gameboy(Model::Dmg);
+        assert!(dmg.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_rejects_other_games_and_newer_versions() {
+        let mut gameboy = running_gameboy(Model::Dmg);
+        let mut state = gameboy.save_state();
+
+        let mut other = GameBoy::new();
+        let mut rom = counter_rom();
+        rom[0x0134] = b'X';
+        other.load_cartridge(rom);
+        assert!(other.load_state(&state).is_err());
+
+        state[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(gameboy.load_state(&state).is_err());
+        assert!(gameboy.load_state(b"not a state").is_err());
+    }
+
+    #[test]
+    fn test_skips_unknown_sections() {
+        let mut gameboy = running_gameboy(Model::Dmg);
+        let mut state = gameboy.save_state();
+        let pc = gameboy.cpu().get_pc();
+        state.extend_from_slice(b"NEW ");
+        state.extend_from_slice(&3u32.to_le_bytes());
+        state.extend_from_slice(&[1, 2, 3]);
+
+        gameboy.run_frame();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.cpu().get_pc(), pc);
+    }
+
+    #[test]
+    fn test_failed_load_keeps_the_machine_as_it_was() {
+        let mut gameboy = running_gameboy(Model::Dmg);
+        let state = gameboy.save_state();
+        gameboy.run_frame();
+        let registers = gameboy.cpu().registers();
+        let counter = gameboy.memory().read(0xC000);
+
+        // Cut off in the middle of the GPU section, after CPU and memory loaded
+        assert!(gameboy.load_state(&state[..state.len() - 100]).is_err());
+        assert_eq!(gameboy.cpu().registers(), registers);
+        assert_eq!(gameboy.memory().read(0xC000), counter);
+    }
+
+    // Where the data of section `tag` lies in a state
+    fn section(state: &[u8], tag: &[u8; 4]) -> std::ops::Range<usize> {
+        // Magic, version and ROM checksum come first
+        let mut at = 10;
+        loop {
+            let length = u32::from_le_bytes(state[at + 4..at + 8].try_into().unwrap()) as usize;
+            if &state[at..at + 4] == tag {
+                return at + 8..at + 8 + length;
+            }
+            at += 8 + length;
+        }
+    }
+
+    #[test]
+    fn test_rejects_stall_counts_off_the_step_size() {
+        let mut gameboy = running_gameboy(Model::Dmg);
+        let state = gameboy.save_state();
+
+        // CPU stall after the registers, SP, PC, IME and HALT
+        let mut bad_cpu = state.clone();
+        let at = section(&state, b"CPU ").start + 14;
+        bad_cpu[at..at + 4].copy_from_slice(&6u32.to_le_bytes());
+        assert!(gameboy.load_state(&bad_cpu).is_err());
+
+        // DMA stall ends the memory section
+        let mut bad_dma = state.clone();
+        let at = section(&state, b"MEM ").end - 4;
+        bad_dma[at..at + 4].copy_from_slice(&6u32.to_le_bytes());
+        assert!(gameboy.load_state(&bad_dma).is_err());
+    }
+
+    #[test]
+    fn test_rejects_an_hblank_dma_with_no_blocks_left() {
+        let mut gameboy = running_gameboy(Model::Cgb);
+        let state = gameboy.save_state();
+        // Blocks left and the HBlank flag come right before the DMA stall
+        let at = section(&state, b"MEM ").end - 6;
+
+        let mut active_but_done = state.clone();
+        active_but_done[at..at + 2].copy_from_slice(&[0, 1]);
+        assert!(gameboy.load_state(&active_but_done).is_err());
+
+        let mut too_many_blocks = state.clone();
+        too_many_blocks[at..at + 2].copy_from_slice(&[0x81, 1]);
+        assert!(gameboy.load_state(&too_many_blocks).is_err());
+
+        let mut in_progress = state;
+        in_progress[at..at + 2].copy_from_slice(&[3, 1]);
+        assert!(gameboy.load_state(&in_progress).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_a_frozen_sgb_screen_of_the_wrong_size() {
+        let mut gameboy = running_gameboy(Model::Sgb);
+        let mut state = gameboy.save_state();
+        // Frozen flag, then an empty screen blob and the player counts
+        let at = section(&state, b"SGB ").end - 7;
+        state[at] = 1;
+        assert!(gameboy.load_state(&state).is_err());
+    }
+}
+
+#[cfg(test)]
+mod bess_tests {
+    use crate::bess;
+    use crate::cpu::Registers;
+    use crate::{GameBoy, Model};
+
+    fn gameboy_with_title(model: Model, title: &[u8]) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        // JR -2
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xFE;
+        let mut gameboy = GameBoy::new();
+        gameboy.set_model(model);
+        gameboy.load_cartridge(rom);
+        gameboy.run_frame();
+        gameboy
+    }
+
+    // A BESS file the way another emulator might write it: no NAME or INFO,
+    // blocks for a mapper and RTC we don't have, buffers after the blocks
+    fn foreign_state() -> Vec<u8> {
+        let mut core = vec![0; 0xD0];
+        core[0..2].copy_from_slice(&1u16.to_le_bytes());
+        core[2..4].copy_from_slice(&1u16.to_le_bytes());
+        core[4..8].copy_from_slice(b"GD  ");
+        for (at, value) in [(0x08, 0x1234u16), (0x0A, 0x12B0), (0x0C, 0x3456), (0x0E, 0x789A), (0x10, 0xBCDE), (0x12, 0xDFF0)] {
+            core[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        core[0x14] = 1; // IME
+        core[0x15] = 0x1F; // IE
+        core[0x16] = 1; // halted
+        core[0x18 + 0x47] = 0xE4; // BGP
+        core[0x18 + 0x50] = 0x01; // boot ROM unmapped
+
+        let mut blocks = Vec::new();
+        for (id, data) in [(&b"MBC "[..], vec![0x00, 0x20, 0x05]), (b"RTC ", vec![0; 0x30]), (b"CORE", core)] {
+            blocks.extend_from_slice(id);
+            blocks.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            blocks.extend_from_slice(&data);
+        }
+        blocks.extend_from_slice(b"END \0\0\0\0");
+
+        // WRAM buffer right after the blocks, with a marker at 0xC000
+        let wram_offset = blocks.len() as u32;
+        let core_at = blocks.len() - 8 - 0xD0;
+        blocks[core_at + 0x98..core_at + 0x9C].copy_from_slice(&0x2000u32.to_le_bytes());
+        blocks[core_at + 0x9C..core_at + 0xA0].copy_from_slice(&wram_offset.to_le_bytes());
+        let mut wram = vec![0; 0x2000];
+        wram[0] = 0x42;
+        blocks.extend_from_slice(&wram);
+
+        blocks.extend_from_slice(&0u32.to_le_bytes());
+        blocks.extend_from_slice(b"BESS");
+        blocks
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let mut gameboy = gameboy_with_title(Model::Dmg, b"TEST");
+        gameboy.memory_mut().write(0xC123, 0x5A);
+        gameboy.memory_mut().write(0xFF80, 0xA5);
+        let state = gameboy.export_bess();
+        assert!(bess::is_bess(&state));
+        let registers = gameboy.cpu().registers();
+
+        gameboy.memory_mut().write(0xC123, 0);
+        gameboy.memory_mut().write(0xFF80, 0);
+        gameboy.run_frame();
+        gameboy.import_bess(&state).unwrap();
+        assert_eq!(gameboy.cpu().registers(), registers);
+        assert_eq!(gameboy.memory().read(0xC123), 0x5A);
+        assert_eq!(gameboy.memory().read(0xFF80), 0xA5);
+        assert_eq!(gameboy.export_bess(), state);
+    }
+
+    #[test]
+    fn test_cgb_banks_and_palettes() {
+        // The last title byte is the CGB flag
+        const CGB_TITLE: &[u8] = b"TEST\0\0\0\0\0\0\0\0\0\0\0\x80";
+        let mut gameboy = gameboy_with_title(Model::Cgb, CGB_TITLE);
+        let memory = gameboy.memory_mut();
+        memory.write(0xFF70, 3);
+        memory.write(0xD000, 0x33);
+        memory.write(0xFF4F, 1);
+        memory.write(0x8000, 0x11);
+        memory.write(0xFF68, 0x80);
+        memory.write(0xFF69, 0x1F);
+        let state = gameboy.export_bess();
+
+        let mut other = gameboy_with_title(Model::Cgb, CGB_TITLE);
+        other.import_bess(&state).unwrap();
+        let memory = other.memory();
+        assert_eq!(memory.read(0xFF70) & 0x07, 3);
+        assert_eq!(memory.read(0xD000), 0x33);
+        assert_eq!(memory.read(0xFF4F) & 0x01, 1);
+        assert_eq!(memory.read(0x8000), 0x11);
+        assert_eq!(memory.bg_palette_color(0, 0) & 0x1F, 0x1F);
+        // BCPS kept auto-increment and moved on to index 1
+        assert_eq!(memory.read(0xFF68), 0xC1);
+    }
+
+    #[test]
+    fn test_import_foreign_state() {
+        let mut gameboy = gameboy_with_title(Model::Dmg, b"TEST");
+        gameboy.import_bess(&foreign_state()).unwrap();
+        assert_eq!(
+            gameboy.cpu().registers(),
+            Registers { a: 0x12, f: 0xB0, b: 0x34, c: 0x56, d: 0x78, e: 0x9A, h: 0xBC, l: 0xDE, sp: 0xDFF0, pc: 0x1234 }
+        );
+        assert_eq!(gameboy.cpu().interrupt_state(), (true, true));
+        assert_eq!(gameboy.memory().read(0xFFFF), 0x1F);
+        assert_eq!(gameboy.memory().read(0xFF47), 0xE4);
+        assert_eq!(gameboy.memory().read(0xC000), 0x42);
+    }
+
+    #[test]
+    fn test_sgb_round_trip() {
+        let gameboy = gameboy_with_title(Model::Sgb, b"TEST");
+        let state = gameboy.export_bess();
+        let mut other = gameboy_with_title(Model::Sgb, b"TEST");
+        other.import_bess(&state).unwrap();
+        assert_eq!(other.export_bess(), state);
+    }
+
+    #[test]
+    fn test_rejects_mismatches() {
+        let dmg = gameboy_with_title(Model::Dmg, b"TEST");
+        let state = dmg.export_bess();
+
+        // Different game (INFO), different model (CORE), not BESS at all
+        let mut other_game = gameboy_with_title(Model::Dmg, b"OTHER");
+        assert!(other_game.import_bess(&state).is_err());
+        let mut cgb = gameboy_with_title(Model::Cgb, b"TEST");
+        assert!(cgb.import_bess(&state).is_err());
+        let mut gameboy = gameboy_with_title(Model::Dmg, b"TEST");
+        let registers = gameboy.cpu().registers();
+        assert!(gameboy.import_bess(&state[..state.len() - 1]).is_err());
+        assert!(gameboy.import_bess(&dmg.save_state()).is_err());
+        assert_eq!(gameboy.cpu().registers(), registers);
+    }
+}
+
+#[cfg(test)]
+mod rewind_tests {
+    use crate::rewind::{compress, decompress, Rewind, RewindConfig};
+    use crate::test_roms::counter_rom;
+    use crate::GameBoy;
+
+    // Every frame's state is different
+    fn counting_gameboy() -> GameBoy {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(counter_rom());
+        gameboy
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut data = vec![0u8; 5000];
+        data[10] = 1;
+        data[11..14].copy_from_slice(&[0, 0, 7]);
+        data[4000..4300].fill(0xAB);
+        data[4999] = 9;
+        let packed = compress(&data);
+        assert!(packed.len() < 400);
+        assert_eq!(decompress(&packed), data);
+        assert_eq!(decompress(&compress(&[])), Vec::<u8>::new());
+        assert_eq!(decompress(&compress(&[1, 2, 3])), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_rewind_steps_back_frame_by_frame() {
+        let config = RewindConfig { keyframe_interval: 4, ..RewindConfig::default() };
+        let mut rewind = Rewind::new(config);
+        let mut gameboy = counting_gameboy();
+        let mut history = Vec::new();
+        for _ in 0..10 {
+            gameboy.run_frame();
+            rewind.record(&gameboy);
+            history.push((gameboy.cpu().registers(), gameboy.cycles(), gameboy.memory().read(0xC000)));
+        }
+        assert_eq!(rewind.len(), 10);
+
+        // Crosses two keyframe boundaries on the way back
+        for expected in history.iter().rev() {
+            assert!(rewind.rewind(&mut gameboy));
+            let actual = (gameboy.cpu().registers(), gameboy.cycles(), gameboy.memory().read(0xC000));
+            assert_eq!(&actual, expected);
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+    }
+
+    #[test]
+    fn test_recording_after_rewind_continues_the_history() {
+        let mut rewind = Rewind::new(RewindConfig { keyframe_interval: 3, ..RewindConfig::default() });
+        let mut gameboy = counting_gameboy();
+        for _ in 0..5 {
+            gameboy.run_frame();
+            rewind.record(&gameboy);
+        }
+        rewind.rewind(&mut gameboy);
+        rewind.rewind(&mut gameboy);
+        rewind.rewind(&mut gameboy);
+
+        let mut cycles = Vec::new();
+        for _ in 0..4 {
+            gameboy.run_frame();
+            rewind.record(&gameboy);
+            cycles.push(gameboy.cycles());
+        }
+        for _ in 0..4 {
+            assert!(rewind.rewind(&mut gameboy));
+        }
+        assert_eq!(gameboy.cycles(), cycles[0]);
+        // The two oldest snapshots from before rewinding are still there
+        assert_eq!(rewind.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_stays_within_budget() {
+        let mut gameboy = counting_gameboy();
+        gameboy.run_frame();
+        let state_size = compress(&gameboy.save_state()).len();
+        let budget = state_size * 3;
+        let mut rewind = Rewind::new(RewindConfig { budget, keyframe_interval: 5, ..RewindConfig::default() });
+        for _ in 0..100 {
+            gameboy.run_frame();
+            rewind.record(&gameboy);
+            assert!(rewind.memory_used() <= budget);
+        }
+        // Deltas are small, so far more than three snapshots fit
+        assert!(rewind.len() > 3);
+        assert!(rewind.len() < 100);
+    }
+
+    #[test]
+    fn test_interval_and_empty_buffer() {
+        let mut rewind = Rewind::new(RewindConfig { interval: 4, ..RewindConfig::default() });
+        let mut gameboy = counting_gameboy();
+        assert!(!rewind.rewind(&mut gameboy));
+        for _ in 0..12 {
+            gameboy.run_frame();
+            rewind.record(&gameboy);
+        }
+        assert_eq!(rewind.len(), 3);
+        rewind.clear();
+        assert!(!rewind.rewind(&mut gameboy));
+    }
+}
+
+#[cfg(test)]
+mod movie_tests {
+    use crate::movie::{Movie, Player, Recorder, Start};
+    use crate::test_roms::joypad_rom;
+    use crate::{GameBoy, Input, Model};
+
+    fn powered_on() -> GameBoy {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(joypad_rom());
+        gameboy
+    }
+
+    fn input_for(frame: usize) -> Input {
+        Input { right: frame.is_multiple_of(3), up: frame % 5 == 1, ..Input::new() }
+    }
+
+    fn record(frames: usize) -> (Movie, GameBoy) {
+        let mut gameboy = powered_on();
+        let mut recorder = Recorder::power_on(&gameboy).unwrap();
+        for frame in 0..frames {
+            recorder.run_frame(&mut gameboy, input_for(frame));
+        }
+        (recorder.into_movie(), gameboy)
+    }
+
+    #[test]
+    fn test_input_bits_round_trip() {
+        let input = Input { left: true, a: true, start: true, ..Input::new() };
+        assert_eq!(input.to_bits(), 0b1001_0010);
+        assert_eq!(Input::from_bits(input.to_bits()), input);
+        assert_eq!(Input::from_bits(0).to_bits(), 0);
+        assert_eq!(Input::from_bits(0xFF).to_bits(), 0xFF);
+    }
+
+    #[test]
+    fn test_playback_reproduces_recording() {
+        let (movie, recorded) = record(20);
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(movie.start, Start::PowerOn);
+        assert_eq!(movie.model, Model::Dmg);
+        assert_eq!(movie.frames.len(), 20);
+
+        let mut gameboy = powered_on();
+        let mut player = Player::start(movie, &mut gameboy).unwrap();
+        assert_eq!(player.run_to_end(&mut gameboy), Ok(()));
+        assert!(player.is_finished());
+        assert_eq!(gameboy.save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn test_playback_reports_first_diverging_frame() {
+        let (mut movie, _) = record(10);
+        // Frame 4 had no keys down; pressing one changes what the ROM reads
+        movie.frames[4].input.down = true;
+
+        let mut gameboy = powered_on();
+        let mut player = Player::start(movie.clone(), &mut gameboy).unwrap();
+        let desync = player.run_to_end(&mut gameboy).unwrap_err();
+        assert_eq!(desync.frame, 4);
+        assert_eq!(desync.expected, movie.frames[4].hash);
+        assert_eq!(player.position(), 5);
+    }
+
+    #[test]
+    fn test_rerecording_truncates_and_counts() {
+        let mut gameboy = powered_on();
+        let mut recorder = Recorder::power_on(&gameboy).unwrap();
+        let mut saved = None;
+        for frame in 0..10 {
+            recorder.run_frame(&mut gameboy, input_for(frame));
+            if frame == 5 {
+                saved = Some(gameboy.save_state());
+            }
+        }
+
+        gameboy.load_state(&saved.unwrap()).unwrap();
+        assert!(recorder.resync(&gameboy));
+        assert_eq!(recorder.movie().frames.len(), 6);
+        assert_eq!(recorder.movie().rerecords, 0);
+        recorder.run_frame(&mut gameboy, Input { a: true, ..Input::new() });
+        assert_eq!(recorder.movie().rerecords, 1);
+        assert_eq!(recorder.movie().frames.len(), 7);
+
+        // A state the recording never passed through
+        let mut other = powered_on();
+        other.set_buttons(Input { left: true, ..Input::new() });
+        other.run_frame();
+        gameboy.load_state(&other.save_state()).unwrap();
+        assert!(!recorder.resync(&gameboy));
+
+        // The re-recorded movie still plays back
+        let replay = recorder.movie().clone();
+        let mut fresh = powered_on();
+        assert_eq!(Player::start(replay, &mut fresh).unwrap().run_to_end(&mut fresh), Ok(()));
+    }
+
+    #[test]
+    fn test_movie_from_save_state() {
+        let mut gameboy = powered_on();
+        for _ in 0..3 {
+            gameboy.run_frame();
+        }
+        assert!(Recorder::power_on(&gameboy).is_err());
+        let mut recorder = Recorder::from_state(&gameboy);
+        for frame in 0..5 {
+            recorder.run_frame(&mut gameboy, input_for(frame));
+        }
+        let movie = Movie::from_bytes(&recorder.into_movie().to_bytes()).unwrap();
+        assert!(matches!(movie.start, Start::State(_)));
+
+        // Plays on a machine in any state, since the movie brings its own
+        let mut other = powered_on();
+        other.run_frame();
+        let mut player = Player::start(movie.clone(), &mut other).unwrap();
+        assert_eq!(player.run_to_end(&mut other), Ok(()));
+
+        let mut rom = joypad_rom();
+        rom[0x0200] = 1;
+        let mut different_game = GameBoy::new();
+        different_game.load_cartridge(rom);
+        assert!(Player::start(movie, &mut different_game).is_err());
+        assert!(Movie::from_bytes(b"GBST\x01\x00").is_err());
+    }
+}
+
+#[cfg(test)]
+mod runahead_tests {
+    use crate::runahead::RunAhead;
+    use crate::test_roms::counter_rom;
+    use crate::{GameBoy, Input};
+    use std::time::Duration;
+
+    // Every frame looks different
+    fn gameboy() -> GameBoy {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(counter_rom());
+        gameboy.run_frame();
+        gameboy
+    }
+
+    fn press(gameboy: &mut GameBoy) {
+        gameboy.set_buttons(Input { right: true, ..Input::new() });
+        gameboy.run_frame();
+    }
+
+    #[test]
+    fn test_machine_ends_up_after_the_real_frame() {
+        let mut plain = gameboy();
+        press(&mut plain);
+
+        let mut gameboy = gameboy();
+        let mut ahead = RunAhead::with_budget(2, Duration::from_secs(10));
+        assert!(ahead.run_frame(&mut gameboy, press));
+        assert_eq!(gameboy.save_state(), plain.save_state());
+        assert_eq!(gameboy.framebuffer(), plain.framebuffer());
+    }
+
+    #[test]
+    fn test_shows_the_picture_from_ahead() {
+        let mut future = gameboy();
+        for _ in 0..3 {
+            press(&mut future);
+        }
+
+        let mut gameboy = gameboy();
+        let mut ahead = RunAhead::with_budget(2, Duration::from_secs(10));
+        ahead.run_frame(&mut gameboy, press);
+        assert_eq!(ahead.framebuffer(&gameboy), future.framebuffer());
+        // Without run-ahead the picture is two frames behind
+        assert_ne!(gameboy.framebuffer(), future.framebuffer());
+
+        ahead.invalidate();
+        assert_eq!(ahead.framebuffer(&gameboy), gameboy.framebuffer());
+    }
+
+    #[test]
+    fn test_turns_off_when_over_budget() {
+        let mut gameboy = gameboy();
+        let mut ahead = RunAhead::with_budget(1, Duration::ZERO);
+        assert!(ahead.is_enabled());
+        assert!(!ahead.run_frame(&mut gameboy, press));
+        assert!(!ahead.is_enabled());
+        assert!(ahead.average_frame_time() > Duration::ZERO);
+
+        // The real frames keep running
+        let cycles = gameboy.cycles();
+        assert!(ahead.run_frame(&mut gameboy, press));
+        assert!(gameboy.cycles() > cycles);
+        assert_eq!(ahead.framebuffer(&gameboy), gameboy.framebuffer());
+        assert!(!RunAhead::new(0).is_enabled());
+    }
+}
+
+#[cfg(test)]
+mod env_tests {
+    use crate::env::{Env, Observation, ObservationKind};
+    use crate::test_roms::joypad_rom;
+    use crate::{GameBoy, Input};
+
+    fn env() -> Env {
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(joypad_rom());
+        gameboy.run_frame();
+
+        let mut env = Env::new(gameboy);
+        // One point per step with right held, the episode ends on down
+        env.set_reward(|_, after| if after.read(0xC000) & 0x01 == 0 { 1.0 } else { 0.0 });
+        env.set_done(|memory| memory.read(0xC000) & 0x08 == 0);
+        env
+    }
+
+    fn right() -> Input {
+        Input { right: true, ..Input::new() }
+    }
+
+    #[test]
+    fn test_reward_and_done_read_ram() {
+        let mut env = env();
+        assert_eq!(env.step(Input::new(), 1).reward, 0.0);
+        let step = env.step(right(), 2);
+        assert_eq!(step.reward, 1.0);
+        assert!(!step.done);
+        assert_eq!(env.frames(), 3);
+
+        // The episode ends after the first frame with down held
+        let step = env.step(Input { down: true, ..Input::new() }, 4);
+        assert!(step.done);
+        assert_eq!(env.frames(), 4);
+    }
+
+    #[test]
+    fn test_reset_goes_back_to_the_start() {
+        let mut env = env();
+        let start = env.gameboy().save_state();
+        let first = env.step(right(), 1);
+        env.step(right(), 5);
+
+        assert_eq!(env.reset(), env.observe());
+        assert_eq!(env.frames(), 0);
+        assert_eq!(env.gameboy().save_state(), start);
+        assert_eq!(env.step(right(), 1), first);
+    }
+
+    #[test]
+    fn test_grayscale_observation_averages_blocks() {
+        let mut env = env();
+        let Observation::Rgb { width, height, pixels: rgb } = env.step(right(), 1).observation else {
+            panic!("expected an RGB observation");
+        };
+        assert_eq!((width, height), (160, 144));
+
+        env.set_observation(ObservationKind::Grayscale { factor: 3 });
+        let observation = env.observe();
+        assert_eq!(observation.size(), (53, 48));
+        let Observation::Grayscale { pixels, .. } = observation else {
+            panic!("expected a grayscale observation");
+        };
+        let luma = |pixel: u32| ((pixel >> 16 & 0xFF) * 299 + (pixel >> 8 & 0xFF) * 587 + (pixel & 0xFF) * 114) / 1000;
+        let block: u32 = (0..3).flat_map(|y| (0..3).map(move |x| y * 160 + x)).map(|i| luma(rgb[i])).sum();
+        assert_eq!(pixels[0] as u32, block / 9);
+    }
+
+    #[test]
+    fn test_clones_run_independently_on_threads() {
+        let mut env = env();
+        env.set_observation(ObservationKind::Grayscale { factor: 2 });
+        let actions = [right(), Input::new(), right(), right()];
+        let expected: Vec<_> = actions.iter().map(|&action| env.clone().step(action, 3)).collect();
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = actions
+                .iter()
+                .map(|&action| {
+                    let mut env = env.clone();
+                    scope.spawn(move || env.step(action, 3))
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        assert_eq!(results, expected);
+        // The original never ran
+        assert_eq!(env.frames(), 0);
+    }
+}
+
+#[cfg(test)]
+mod disasm_tests {
+    use crate::disasm::{decode, disassemble};
+
+    // Decode `bytes` placed at `address`
+    fn text(bytes: &[u8], address: u16) -> String {
+        decode(|addr| bytes.get(addr.wrapping_sub(address) as usize).copied().unwrap_or(0), address).to_string()
+    }
+
+    #[test]
+    fn test_operands_are_resolved() {
+        assert_eq!(text(&[0x2A], 0x0100), "LD A,(HL+)");
+        assert_eq!(text(&[0xE0, 0x44], 0x0100), "LDH ($44),A");
+        assert_eq!(text(&[0xF0, 0x44], 0x0100), "LDH A,($44)");
+        assert_eq!(text(&[0x21, 0x00, 0xC0], 0x0100), "LD HL,$C000");
+        assert_eq!(text(&[0x08, 0x80, 0xFF], 0x0100), "LD ($FF80),SP");
+        assert_eq!(text(&[0xCD, 0x50, 0x01], 0x0100), "CALL $0150");
+        assert_eq!(text(&[0xFF], 0x0100), "RST $38");
+        assert_eq!(text(&[0x7C], 0x0100), "LD A,H");
+    }
+
+    #[test]
+    fn test_relative_operands() {
+        assert_eq!(text(&[0x20, 0x4E], 0x0100), "JR NZ,$0150");
+        assert_eq!(text(&[0x18, 0xFE], 0x0150), "JR $0150");
+        assert_eq!(text(&[0xE8, 0xFE], 0x0100), "ADD SP,-$02");
+        assert_eq!(text(&[0xF8, 0x05], 0x0100), "LD HL,SP+$05");
+    }
+
+    #[test]
+    fn test_prefixed_stop_and_unused_opcodes() {
+        let bit = decode(|addr| [0xCB, 0x7C][addr as usize], 0);
+        assert_eq!((bit.to_string(), bit.bytes.len()), ("BIT 7,H".to_string(), 2));
+        let stop = decode(|_| 0x10, 0);
+        assert_eq!((stop.to_string(), stop.bytes.len()), ("STOP".to_string(), 2));
+        assert_eq!(text(&[0xD3], 0x0100), "DB $D3");
+    }
+
+    #[test]
+    fn test_listing_follows_instruction_lengths() {
+        let code = [0x3E, 0x91, 0xE0, 0x40, 0xC3, 0x50, 0x01];
+        let read = |addr: u16| code.get(addr as usize - 0x100).copied().unwrap_or(0);
+        let listing: Vec<String> = disassemble(read, 0x0100, 4).iter().map(|i| i.listing()).collect();
+        assert_eq!(
+            listing,
+            [
+                "0100  3E 91     LD A,$91",
+                "0102  E0 40     LDH ($40),A",
+                "0104  C3 50 01  JP $0150",
+                "0107  00        NOP",
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use crate::debugger::{Debugger, Response};
+    use crate::test_roms::looping_rom;
+    use crate::GameBoy;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    // Turns the LCD on, then loops calling a routine that adds 2 to A and
+    // incrementing 0xC000
+    fn gameboy() -> GameBoy {
+        let mut rom = looping_rom(
+            &[0x21, 0x00, 0xC0], // LD HL, 0xC000
+            &[
+                0xCD, 0x00, 0x02, // loop: CALL 0x0200
+                0x34, // INC (HL)
+            ],
+        );
+        rom[0x0200..0x0203].copy_from_slice(&[
+            0x3C, // INC A
+            0x3C, // INC A
+            0xC9, // RET
+        ]);
+        let mut gameboy = GameBoy::new();
+        gameboy.load_cartridge(rom);
+        gameboy
+    }
+
+    fn run(debugger: &mut Debugger, gameboy: &mut GameBoy, line: &str) -> String {
+        match debugger.execute(gameboy, line) {
+            Ok(Response::Text(text)) => text,
+            other => panic!("{:?} for {}", other, line),
+        }
+    }
+
+    #[test]
+    fn test_breakpoints_stop_continue() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        run(&mut debugger, &mut gameboy, "b 10A");
+        let text = run(&mut debugger, &mut gameboy, "c");
+        assert!(text.starts_with("Breakpoint at 010A"), "{}", text);
+        assert_eq!(gameboy.cpu().get_pc(), 0x010A);
+
+        // An empty line continues again, round the loop
+        run(&mut debugger, &mut gameboy, "");
+        assert_eq!(gameboy.cpu().get_pc(), 0x010A);
+        assert_eq!(gameboy.memory().read(0xC000), 1);
+
+        run(&mut debugger, &mut gameboy, "d 10A");
+        assert_eq!(debugger.breakpoints().count(), 0);
+        let start = gameboy.cycles();
+        run(&mut debugger, &mut gameboy, "c 2");
+        assert!(gameboy.cycles() - start >= 2 * 70224);
+    }
+
+    #[test]
+    fn test_step_enters_calls_and_next_steps_over() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        run(&mut debugger, &mut gameboy, "s 3");
+        assert_eq!(gameboy.cpu().get_pc(), 0x0107);
+        let a = gameboy.cpu().registers().a;
+
+        let text = run(&mut debugger, &mut gameboy, "n");
+        assert!(text.ends_with("010A  34        INC (HL)"), "{}", text);
+        assert_eq!(gameboy.cpu().registers().a, a.wrapping_add(2));
+
+        run(&mut debugger, &mut gameboy, "s 2");
+        assert_eq!(gameboy.cpu().get_pc(), 0x0107);
+        run(&mut debugger, &mut gameboy, "step");
+        assert_eq!(gameboy.cpu().get_pc(), 0x0200);
+    }
+
+    #[test]
+    fn test_interrupt_stops_continue() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        let interrupt = debugger.interrupt_flag();
+        // Raised before the command runs: cleared, so it doesn't stop at once
+        interrupt.store(true, Ordering::Relaxed);
+        let ctrl_c = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+
+        // No breakpoints: only the interrupt stops it
+        let start = gameboy.cycles();
+        let text = run(&mut debugger, &mut gameboy, "c");
+        assert!(text.starts_with("Interrupted"), "{}", text);
+        assert!(gameboy.cycles() > start + 70224);
+        ctrl_c.join().unwrap();
+    }
+
+    #[test]
+    fn test_interrupt_stops_a_long_step() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        let interrupt = debugger.interrupt_flag();
+        let ctrl_c = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+
+        let text = run(&mut debugger, &mut gameboy, &format!("s {}", usize::MAX));
+        assert!(text.starts_with("Interrupted"), "{}", text);
+        ctrl_c.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_registers_flags_and_memory() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        run(&mut debugger, &mut gameboy, "set hl $C123");
+        run(&mut debugger, &mut gameboy, "set a 42");
+        let text = run(&mut debugger, &mut gameboy, "flag z 1");
+        assert!(text.contains("HL=C123"), "{}", text);
+        let registers = gameboy.cpu().registers();
+        assert_eq!((registers.a, registers.f & 0x80), (0x42, 0x80));
+        assert!(debugger.execute(&mut gameboy, "set a 100").is_err());
+        assert!(debugger.execute(&mut gameboy, "set q 1").is_err());
+
+        let text = run(&mut debugger, &mut gameboy, "w C000 48 69 00");
+        assert_eq!(text, format!("C000  48 69 00{}  Hi.", " ".repeat(39)));
+        assert_eq!(gameboy.memory().read(0xC001), 0x69);
+    }
+
+    #[test]
+    fn test_listing_marks_pc_and_breakpoints() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        run(&mut debugger, &mut gameboy, "b 107");
+        run(&mut debugger, &mut gameboy, "s 2");
+        let text = run(&mut debugger, &mut gameboy, "l");
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"   0100  3E 91     LD A,$91"), "{}", text);
+        assert!(lines.contains(&">  0104  21 00 C0  LD HL,$C000"), "{}", text);
+        assert!(lines.contains(&" * 0107  CD 00 02  CALL $0200"), "{}", text);
+
+        assert!(debugger.execute(&mut gameboy, "frobnicate").is_err());
+        assert_eq!(debugger.execute(&mut gameboy, "q"), Ok(Response::Quit));
+    }
+    #[test]
+    fn test_huge_counts_are_rejected() {
+        let (mut gameboy, mut debugger) = (gameboy(), Debugger::new());
+        assert_eq!(run(&mut debugger, &mut gameboy, "l 0 65536").lines().count(), 65536);
+        assert!(debugger.execute(&mut gameboy, "l 0 65537").is_err());
+        assert!(debugger.execute(&mut gameboy, &format!("l 0 {}", usize::MAX)).is_err());
+        assert!(debugger.execute(&mut gameboy, &format!("x 0 {}", usize::MAX)).is_err());
+    }
+}